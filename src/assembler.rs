@@ -1,11 +1,281 @@
-use std::{collections::HashMap, fmt::format, fs::File, io::Read, str::Chars};
-
-#[derive(PartialEq, Eq)]
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::format,
+    fs::File,
+    io::Read,
+    str::Chars,
+};
+
+use crate::{
+    expr::{parse_number, Expr, ExprContext},
+    object::{Object, Relocation},
+    rusty_boi::{RAM_START, STACK_START},
+};
+
+// Variables always leave this much RAM for the stack, --stack-budget checks larger stacks
+pub const STACK_RESERVE: u16 = 0x100;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Width {
     Byte,
     Word,
 }
 
+// Symbol information kept from assembly so tools can map addresses back to the source
+#[derive(Clone, Default)]
+pub struct DebugInfo {
+    pub labels: HashMap<String, u16>,
+    pub constants: HashMap<String, u16>,
+    pub label_lines: HashMap<String, usize>,
+    // Address of every assembled instruction with the source line it came from
+    pub lines: Vec<(u16, usize)>,
+    pub source_path: String,
+    pub source: String,
+    // Lines left out by conditional assembly, including the directives themselves
+    pub inactive_lines: HashSet<usize>,
+    // Instructions that came from a pseudo-instruction, with the pseudo-instruction
+    pub expansions: HashMap<u16, String>,
+}
+
+impl DebugInfo {
+    // Finds the closest label at or before the address
+    pub fn label_before(&self, address: u16) -> Option<(&str, u16)> {
+        self.labels
+            .iter()
+            .filter(|(_, label_address)| **label_address <= address)
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(name, label_address)| (name.as_str(), *label_address))
+    }
+
+    pub fn line_of(&self, address: u16) -> Option<usize> {
+        self.lines
+            .iter()
+            .find(|(line_address, _)| *line_address == address)
+            .map(|(_, line)| *line)
+    }
+
+    // Names an address as label+offset, falling back to plain hex
+    pub fn describe(&self, address: u16) -> String {
+        match self.label_before(address) {
+            Some((name, label_address)) if label_address == address => name.to_string(),
+            Some((name, label_address)) => format!("{}+{}", name, address - label_address),
+            None => format!("0x{:04X}", address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub column: usize,
+    pub text: &'a str,
+}
+
+// A source line split into whitespace separated tokens and the comment after "//"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine<'a> {
+    pub tokens: Vec<Token<'a>>,
+    pub comment: Option<&'a str>,
+}
+
+impl<'a> SourceLine<'a> {
+    pub fn parse(line: &'a str) -> Self {
+        let (code, comment) = match line.find("//") {
+            Some(index) => (&line[..index], Some(&line[index + 2..])),
+            None => (line, None),
+        };
+
+        let mut tokens = Vec::new();
+        let mut start = None;
+        let mut chars = code.char_indices();
+        while let Some((index, c)) = chars.next() {
+            // A character literal is one token even when it's a space, like ' '
+            if c == '\'' && start.is_none() {
+                let mut ahead = chars.clone();
+                if let (Some(_), Some((end, '\''))) = (ahead.next(), ahead.next()) {
+                    tokens.push(Token {
+                        column: index,
+                        text: &code[index..end + 1],
+                    });
+                    chars = ahead;
+                    continue;
+                }
+            }
+            match (c.is_whitespace(), start) {
+                (true, Some(column)) => {
+                    tokens.push(Token {
+                        column,
+                        text: &code[column..index],
+                    });
+                    start = None;
+                }
+                (false, None) => start = Some(index),
+                _ => (),
+            }
+        }
+        if let Some(column) = start {
+            tokens.push(Token {
+                column,
+                text: &code[column..],
+            });
+        }
+
+        Self { tokens, comment }
+    }
+
+    pub fn words(&self) -> Vec<&'a str> {
+        self.tokens.iter().map(|token| token.text).collect()
+    }
+}
+
+// Every mnemonic with the number of operands it takes, the pseudo-instructions from CLR on
+// are expanded into the real ones
+pub const MNEMONICS: [(&str, usize); 49] = [
+    ("LOAD", 2),
+    ("MOV", 2),
+    ("STORE", 1),
+    ("READ", 1),
+    ("PUSH", 1),
+    ("POP", 1),
+    ("EQ", 2),
+    ("NEQ", 2),
+    ("LES", 2),
+    ("LESi", 2),
+    ("ADD", 3),
+    ("ADDi", 3),
+    ("SUB", 3),
+    ("SUBi", 3),
+    ("MUL", 3),
+    ("MULi", 3),
+    ("DIV", 3),
+    ("DIVi", 3),
+    ("MOD", 3),
+    ("MODi", 3),
+    ("SHR", 3),
+    ("SHL", 3),
+    ("JMP", 1),
+    ("CJMP", 1),
+    ("NJMP", 1),
+    ("CALL", 1),
+    ("CCALL", 1),
+    ("NCALL", 1),
+    ("RET", 0),
+    ("DRAW", 0),
+    ("RDRAW", 0),
+    ("JDRAW", 1),
+    ("INC", 1),
+    ("DEC", 1),
+    ("NEG", 1),
+    ("NOT", 1),
+    ("NOP", 0),
+    ("HALT", 1),
+    ("CLR", 1),
+    ("BEQ", 3),
+    ("BNE", 3),
+    ("BLT", 3),
+    ("BLTi", 3),
+    ("BGE", 3),
+    ("BGEi", 3),
+    ("BGT", 3),
+    ("BGTi", 3),
+    ("BLE", 3),
+    ("BLEi", 3),
+];
+
+// A run of program memory filled without an org in between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub line: usize,
+}
+
+impl Region {
+    pub fn len(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+// An if, ifdef or ifndef that is still open and whether its current branch is assembled
+struct Conditional {
+    taken: bool,
+    seen_else: bool,
+    line: usize,
+}
+
+// Names in conditional expressions are the constants defined so far
+struct ConstantContext<'a> {
+    assembler: &'a Assembler,
+}
+
+impl ExprContext for ConstantContext<'_> {
+    fn name(&mut self, name: &str) -> Result<i64, String> {
+        match self.assembler.constant(name) {
+            Some(value) => Ok(value as i64),
+            None => Err(format!("Uknown constant {}", name)),
+        }
+    }
+}
+
+struct AnonymousLabel {
+    forward: bool,
+    index: usize,
+    address: u16,
+}
+
+// The counter of one rept block a line was repeated by, the name is optional
+#[derive(Clone)]
+struct Counter {
+    name: Option<String>,
+    iteration: u16,
+    count: u16,
+    line: usize,
+}
+
+// A line of the program after rept blocks are expanded
+#[derive(Clone)]
+struct ExpandedLine {
+    text: String,
+    line: usize,
+    counters: Vec<Counter>,
+    // The pseudo-instruction this line is part of and its position in the expansion
+    pseudo: Option<(String, usize)>,
+}
+
+// Stops a rept typo from expanding until memory runs out
+const MAX_EXPANDED_LINES: usize = 1 << 20;
+
+// Field offsets of a struct, fields of nested structs are listed as outer.inner
+#[derive(Clone, Default)]
+struct Struct {
+    fields: Vec<(String, u16)>,
+    size: u16,
+}
+
+// A var placed in workable RAM
+struct Variable {
+    name: String,
+    address: u16,
+    size: u16,
+    line: usize,
+}
+
+// Local labels start with a dot and are known as global.local
+pub fn qualify_label(scope: &str, label: &str) -> String {
+    match label.starts_with('.') && !scope.is_empty() {
+        true => format!("{}{}", scope, label),
+        false => label.to_string(),
+    }
+}
+
+// References to anonymous labels are runs of + or -
+pub fn is_anonymous_reference(label: &str) -> bool {
+    !label.is_empty() && (label.chars().all(|c| c == '+') || label.chars().all(|c| c == '-'))
+}
+
 pub struct Assembler {
     pc: u16,
     line_num: usize,
@@ -13,6 +283,38 @@ pub struct Assembler {
     constants: HashMap<String, u16>,
     program: Vec<u8>,
     file_string: String,
+    file_path: String,
+    line_info: Vec<(u16, usize)>,
+    label_lines: HashMap<String, usize>,
+    fill_byte: u8,
+    regions: Vec<Region>,
+    region_start: u16,
+    region_line: usize,
+    alignment: u16,
+    scope: String,
+    anonymous_labels: Vec<AnonymousLabel>,
+    exports: Vec<(String, usize)>,
+    imports: HashMap<String, usize>,
+    relocations: Vec<Relocation>,
+    conditionals: Vec<Conditional>,
+    // Indexes into the expanded lines that aren't assembled
+    inactive_lines: HashSet<usize>,
+    expanded: Vec<ExpandedLine>,
+    line_index: usize,
+    counters: Vec<Counter>,
+    ram_pc: u16,
+    variables: Vec<Variable>,
+    structs: HashMap<String, Struct>,
+    // Name and line of the struct being defined
+    open_struct: Option<(String, usize)>,
+    struct_lines: HashSet<usize>,
+    expansions: HashMap<u16, String>,
+    // Start and end address of every assembled line with its index in the expanded lines
+    listed: Vec<(u16, u16, usize)>,
+    // VM features the assembled code needs, written to the image header
+    features: Vec<String>,
+    // Constants given on the command line, a const in the source doesn't replace them
+    defines: HashSet<String>,
 }
 
 impl Assembler {
@@ -24,6 +326,33 @@ impl Assembler {
             constants: HashMap::new(),
             program: Vec::new(),
             file_string: "".to_string(),
+            file_path: "".to_string(),
+            line_info: Vec::new(),
+            label_lines: HashMap::new(),
+            fill_byte: 0,
+            regions: Vec::new(),
+            region_start: 0,
+            region_line: 1,
+            alignment: 1,
+            scope: String::new(),
+            anonymous_labels: Vec::new(),
+            exports: Vec::new(),
+            imports: HashMap::new(),
+            relocations: Vec::new(),
+            conditionals: Vec::new(),
+            inactive_lines: HashSet::new(),
+            expanded: Vec::new(),
+            line_index: 0,
+            counters: Vec::new(),
+            ram_pc: RAM_START,
+            variables: Vec::new(),
+            structs: HashMap::new(),
+            open_struct: None,
+            struct_lines: HashSet::new(),
+            expansions: HashMap::new(),
+            listed: Vec::new(),
+            features: Vec::new(),
+            defines: HashSet::new(),
         }
     }
     pub fn load_file(&mut self, file: &str) -> Result<(), String> {
@@ -32,58 +361,152 @@ impl Assembler {
             Err(error) => return Err(format!("Error opening file {} \n{}", file, error)),
         };
 
+        self.file_path = file.to_string();
         match file_handle.read_to_string(&mut self.file_string) {
             Ok(_) => Ok(()),
             Err(error) => Err(format!("Error reading file {} \n{}", file, error)),
         }
     }
 
+    // Assembles source that is already in memory, like an unsaved editor buffer
+    pub fn load_source(&mut self, path: &str, source: &str) {
+        self.file_path = path.to_string();
+        self.file_string = source.to_string();
+    }
+
+    // Defines a constant before assembly starts, like -D on the command line
+    pub fn define(&mut self, name: &str, value: u16) {
+        self.constants.insert(name.to_string(), value);
+        self.defines.insert(name.to_string());
+    }
+
     pub fn pre_assembly(&mut self) -> Result<(), String> {
-        let lines: Vec<String> = self
+        self.expanded = self
             .file_string
             .lines()
-            .filter(|x| !x.contains("//"))
-            .map(|x| x.to_string())
+            .enumerate()
+            .map(|(index, text)| ExpandedLine {
+                text: text.to_string(),
+                line: index + 1,
+                counters: Vec::new(),
+                pseudo: None,
+            })
             .collect();
+        let result = self.lay_out();
+        result.map_err(|error| self.describe_iteration(error))
+    }
 
-        for line in lines.iter() {
-            if line.len() == 0 {
+    fn lay_out(&mut self) -> Result<(), String> {
+        let mut index = 0;
+        while index < self.expanded.len() {
+            let line = self.expanded[index].clone();
+            self.enter_line(index, &line);
+            index += 1;
+            let args = SourceLine::parse(&line.text).words();
+            if args.is_empty() {
+                continue;
+            }
+            if self.conditional(&args)? {
+                self.inactive_lines.insert(self.line_index);
+                continue;
+            }
+            if self.open_struct.is_some() {
+                self.struct_lines.insert(self.line_index);
+                self.struct_field(&args)?;
+                continue;
+            }
+            self.check_operands(&args)?;
+            if let Some(expansion) = self.expand_pseudo(&args) {
+                index -= 1;
+                let pseudo = args.join(" ");
+                let lines: Vec<ExpandedLine> = expansion
+                    .into_iter()
+                    .enumerate()
+                    .map(|(position, text)| ExpandedLine {
+                        text,
+                        pseudo: Some((pseudo.clone(), position)),
+                        ..line.clone()
+                    })
+                    .collect();
+                self.expanded.splice(index..=index, lines);
                 continue;
             }
-            let args: Vec<&str> = line.split(" ").collect();
 
             match args[0] {
-                "JMP" => self.pc += 3,
-                "CJMP" => self.pc += 3,
-                "NJMP" => self.pc += 3,
-                "RET" => self.pc += 1,
-                "DRAW" => self.pc += 1,
-                "RDRAW" => self.pc += 1,
-                "JDRAW" => self.pc += 3,
-                "MOV" => self.pc += 3,
-                "STORE" => self.pc += 2,
-                "READ" => self.pc += 2,
-                "POP" => self.pc += 2,
-                "PUSH" => self.pc += 2,
-                "EQ" => self.pc += 3,
-                "NEQ" => self.pc += 3,
-                "LES" => self.pc += 3,
-                "ADD" => self.pc += 4,
-                "SUB" => self.pc += 4,
-                "MUL" => self.pc += 4,
-                "DIV" => self.pc += 4,
-                "MOD" => self.pc += 4,
-                "LESi" => self.pc += 3,
-                "ADDi" => self.pc += 4,
-                "SUBi" => self.pc += 4,
-                "MULi" => self.pc += 4,
-                "DIVi" => self.pc += 4,
-                "MODi" => self.pc += 4,
-                "SHR" => self.pc += 4,
-                "SHL" => self.pc += 4,
-                "CALL" => self.pc += 3,
-                "CCALL" => self.pc += 3,
-                "NCALL" => self.pc += 3,
+                "var" => self.allocate(args[1], args[2])?,
+                "struct" => {
+                    self.struct_lines.insert(self.line_index);
+                    if self.structs.contains_key(args[1]) {
+                        return Err(format!(
+                            "Struct {} is already defined on line {}",
+                            args[1], self.line_num
+                        ));
+                    }
+                    self.structs.insert(args[1].to_string(), Struct::default());
+                    self.open_struct = Some((args[1].to_string(), self.line_num));
+                }
+                "endstruct" => {
+                    return Err(format!(
+                        "endstruct without struct on line {}",
+                        self.line_num
+                    ))
+                }
+                "rept" => {
+                    index -= 1;
+                    self.expand_repeat(index, args.get(2).copied())?;
+                }
+                "endr" => return Err(format!("endr without rept on line {}", self.line_num)),
+                "JMP" => self.advance(3)?,
+                "CJMP" => self.advance(3)?,
+                "NJMP" => self.advance(3)?,
+                "RET" => self.advance(1)?,
+                "DRAW" => self.advance(1)?,
+                "RDRAW" => self.advance(1)?,
+                "JDRAW" => self.advance(3)?,
+                "MOV" => self.advance(3)?,
+                "STORE" => self.advance(2)?,
+                "READ" => self.advance(2)?,
+                "POP" => self.advance(2)?,
+                "PUSH" => self.advance(2)?,
+                "EQ" => self.advance(3)?,
+                "NEQ" => self.advance(3)?,
+                "LES" => self.advance(3)?,
+                "ADD" => self.advance(4)?,
+                "SUB" => self.advance(4)?,
+                "MUL" => self.advance(4)?,
+                "DIV" => self.advance(4)?,
+                "MOD" => self.advance(4)?,
+                "LESi" => self.advance(3)?,
+                "ADDi" => self.advance(4)?,
+                "SUBi" => self.advance(4)?,
+                "MULi" => self.advance(4)?,
+                "DIVi" => self.advance(4)?,
+                "MODi" => self.advance(4)?,
+                "SHR" => self.advance(4)?,
+                "SHL" => self.advance(4)?,
+                "CALL" => self.advance(3)?,
+                "CCALL" => self.advance(3)?,
+                "NCALL" => self.advance(3)?,
+                "INC" | "DEC" | "NEG" | "NOT" | "HALT" => self.advance(2)?,
+                "NOP" => self.advance(1)?,
+                "org" => {
+                    let address = self.parse_directive_value(args[1])?;
+                    self.start_region(address);
+                }
+                "export" => self.exports.push((args[1].to_string(), self.line_num)),
+                "import" => {
+                    self.imports.insert(args[1].to_string(), self.line_num);
+                }
+                "align" => {
+                    let padding = self.align_padding(args[1])?;
+                    self.advance(padding)?;
+                }
+                "fill" => {
+                    if args.len() > 2 {
+                        let count = self.parse_directive_value(args[2])?;
+                        self.advance(count)?;
+                    }
+                }
                 "const" => match self.parse_const(args[1], args[2]) {
                     Ok(_) => (),
                     Err(error) => {
@@ -97,8 +520,8 @@ impl Assembler {
                     if args[0] == "LOAD" {
                         match self.parse_register(args[1]) {
                             Ok((_, width)) => match width {
-                                Width::Byte => self.pc += 3,
-                                Width::Word => self.pc += 4,
+                                Width::Byte => self.advance(3)?,
+                                Width::Word => self.advance(4)?,
                             },
                             Err(error) => return Err(error),
                         }
@@ -118,200 +541,637 @@ impl Assembler {
                     ));
                 }
             }
-            self.line_num += 1;
+        }
+        self.counters.clear();
+        if let Some((name, line)) = &self.open_struct {
+            return Err(format!(
+                "Missing endstruct for the struct {} on line {}",
+                name, line
+            ));
+        }
+        if let Some(conditional) = self.conditionals.last() {
+            return Err(format!(
+                "Missing endif for the if on line {}",
+                conditional.line
+            ));
+        }
+        self.start_region(self.pc);
+
+        for (name, line) in self.exports.iter() {
+            if !self.labels.contains_key(name) {
+                return Err(format!(
+                    "Exported label {} is never defined on line {}",
+                    name, line
+                ));
+            }
+        }
+        for (name, line) in self.imports.iter() {
+            if let Some(label_line) = self.label_lines.get(name) {
+                return Err(format!(
+                    "Label {} is imported on line {} and defined on line {}",
+                    name, line, label_line
+                ));
+            }
+        }
+
+        // Regions only clash when org moves back over code that is already placed
+        let mut regions = self.regions.clone();
+        regions.sort_by_key(|region| region.start);
+        for pair in regions.windows(2) {
+            if pair[1].start < pair[0].end {
+                return Err(format!(
+                    "Code from line {} overlaps code from line {} at 0x{:04X}",
+                    pair[1].line, pair[0].line, pair[1].start
+                ));
+            }
         }
 
         Ok(())
     }
 
-    fn write_byte(&mut self, byte: u8) {
-        self.program.push(byte);
+    // Size of a var or field type and the fields it has when it is a struct
+    fn type_size(&mut self, type_name: &str) -> Result<(u16, Vec<(String, u16)>), String> {
+        match type_name {
+            "byte" => return Ok((1, Vec::new())),
+            "word" => return Ok((2, Vec::new())),
+            _ => (),
+        }
+        if let Some(count) = type_name
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            return Ok((self.parse_directive_value(count)?, Vec::new()));
+        }
+        match self.structs.get(type_name) {
+            Some(definition) => Ok((definition.size, definition.fields.clone())),
+            None => Err(format!(
+                "Uknown type {}, expected byte, word, [count] or a struct on line {}",
+                type_name, self.line_num
+            )),
+        }
     }
 
-    fn write_word(&mut self, word: u16) {
-        let bytes = word.to_le_bytes();
-        self.write_byte(bytes[0]);
-        self.write_byte(bytes[1]);
+    fn define_symbol(&mut self, name: String, value: u16) -> Result<(), String> {
+        if self.constants.contains_key(&name) {
+            return Err(format!(
+                "Constant {} is already defined on line {}",
+                name, self.line_num
+            ));
+        }
+        self.constants.insert(name, value);
+        Ok(())
     }
 
-    fn parse_byte_hex(&mut self, number_str: &str) -> Result<u8, String> {
-        let striped_string = match number_str.strip_prefix("0x") {
-            Some(str) => str,
+    // Places a var after the last one, the name and every field of a struct become constants
+    fn allocate(&mut self, name: &str, type_name: &str) -> Result<(), String> {
+        let (size, fields) = self.type_size(type_name)?;
+        let address = self.ram_pc;
+        if address as u32 + size as u32 > (STACK_START - STACK_RESERVE) as u32 {
+            return Err(format!(
+                "Variable {} needs {} bytes and runs into the {} bytes kept for the stack below 0x{:04X} on line {}",
+                name, size, STACK_RESERVE, STACK_START, self.line_num
+            ));
+        }
+        self.define_symbol(name.to_string(), address)?;
+        for (field, offset) in fields {
+            self.define_symbol(format!("{}.{}", name, field), address + offset)?;
+        }
+        self.variables.push(Variable {
+            name: name.to_string(),
+            address,
+            size,
+            line: self.line_num,
+        });
+        self.ram_pc += size;
+        Ok(())
+    }
+
+    // A line between struct and endstruct, each field is placed after the one before it
+    fn struct_field(&mut self, args: &[&str]) -> Result<(), String> {
+        let (struct_name, _) = self.open_struct.clone().unwrap_or_default();
+        if args[0] == "endstruct" {
+            let size = self.structs[&struct_name].size;
+            self.open_struct = None;
+            return self.define_symbol(format!("{}.size", struct_name), size);
+        }
+        if args.len() != 2 {
+            return Err(format!(
+                "Expected a field name and type in struct {} on line {}",
+                struct_name, self.line_num
+            ));
+        }
+        let (size, fields) = self.type_size(args[1])?;
+        let offset = self.structs[&struct_name].size;
+        let end = match offset.checked_add(size) {
+            Some(end) => end,
             None => {
                 return Err(format!(
-                    "Malformated number {} on line {}",
-                    number_str, self.line_num
+                    "Struct {} is bigger than memory on line {}",
+                    struct_name, self.line_num
                 ))
             }
         };
-
-        match u8::from_str_radix(striped_string, 16) {
-            Ok(num) => return Ok(num),
-            Err(_) => (),
+        let mut new_fields = vec![(args[0].to_string(), offset)];
+        new_fields.extend(fields.into_iter().map(|(field, field_offset)| {
+            (format!("{}.{}", args[0], field), offset + field_offset)
+        }));
+        for (field, field_offset) in new_fields {
+            self.define_symbol(format!("{}.{}", struct_name, field), field_offset)?;
+            if let Some(definition) = self.structs.get_mut(&struct_name) {
+                definition.fields.push((field, field_offset));
+            }
         }
+        if let Some(definition) = self.structs.get_mut(&struct_name) {
+            definition.size = end;
+        }
+        Ok(())
+    }
 
-        match i8::from_str_radix(striped_string, 16) {
-            Ok(num) => return Ok(num as u8),
-            Err(error) => {
+    // First RAM address after the vars
+    pub fn ram_end(&self) -> u16 {
+        self.ram_pc
+    }
+
+    fn enter_line(&mut self, index: usize, line: &ExpandedLine) {
+        self.line_index = index;
+        self.line_num = line.line;
+        self.counters = line.counters.clone();
+    }
+
+    // Replaces the rept block starting at the index with its body repeated, each copy of
+    // a line knows the iteration it belongs to. Nested blocks are expanded when reached so
+    // their count can use the outer counter
+    fn expand_repeat(&mut self, start: usize, counter: Option<&str>) -> Result<(), String> {
+        let text = self.expanded[start].text.clone();
+        let count = self.parse_directive_value(SourceLine::parse(&text).words()[1])?;
+        let mut depth = 0;
+        let mut end = None;
+        for (index, line) in self.expanded.iter().enumerate().skip(start + 1) {
+            match SourceLine::parse(&line.text).words().first() {
+                Some(&"rept") => depth += 1,
+                Some(&"endr") if depth == 0 => {
+                    end = Some(index);
+                    break;
+                }
+                Some(&"endr") => depth -= 1,
+                _ => (),
+            }
+        }
+        let end = match end {
+            Some(end) => end,
+            None => {
                 return Err(format!(
-                    "Error parsing hexadecimal number {} on line {} /n{}",
-                    number_str,
-                    self.line_num,
-                    error.to_string()
+                    "Missing endr for the rept on line {}",
+                    self.line_num
                 ))
             }
+        };
+
+        let body = &self.expanded[start + 1..end];
+        if self.expanded.len() + body.len() * count as usize > MAX_EXPANDED_LINES {
+            return Err(format!(
+                "rept expands to more than {} lines on line {}",
+                MAX_EXPANDED_LINES, self.line_num
+            ));
         }
+        let mut lines = Vec::with_capacity(body.len() * count as usize);
+        for iteration in 0..count {
+            for line in body.iter() {
+                let mut line = line.clone();
+                line.counters.push(Counter {
+                    name: counter.map(|name| name.to_string()),
+                    iteration,
+                    count,
+                    line: self.line_num,
+                });
+                lines.push(line);
+            }
+        }
+        self.expanded.splice(start..=end, lines);
+        Ok(())
     }
 
-    fn find_label(&mut self, label_str: &str) -> Result<u16, String> {
-        match self.labels.get(label_str) {
-            Some(num) => Ok(*num),
-            None => Err(format!(
-                "Uknown label {} on line {}",
-                label_str, self.line_num
+    // The real instructions a pseudo-instruction stands for, the operands are checked when
+    // the expansion is assembled
+    fn expand_pseudo(&self, args: &[&str]) -> Option<Vec<String>> {
+        let branch = |compare: &str, first: &str, second: &str, jump: &str| {
+            vec![
+                format!("{} {} {}", compare, first, second),
+                format!("{} {}", jump, args[3]),
+            ]
+        };
+        let (first, second) = (args.get(1).copied()?, args.get(2).copied().unwrap_or(""));
+        let expansion = match args[0] {
+            "CLR" => vec![format!("LOAD {} #0", first)],
+            "BEQ" => branch("NEQ", first, second, "NJMP"),
+            "BNE" => branch("NEQ", first, second, "CJMP"),
+            "BLT" => branch("LES", first, second, "CJMP"),
+            "BLTi" => branch("LESi", first, second, "CJMP"),
+            "BGE" => branch("LES", first, second, "NJMP"),
+            "BGEi" => branch("LESi", first, second, "NJMP"),
+            "BGT" => branch("LES", second, first, "CJMP"),
+            "BGTi" => branch("LESi", second, first, "CJMP"),
+            "BLE" => branch("LES", second, first, "NJMP"),
+            "BLEi" => branch("LESi", second, first, "NJMP"),
+            _ => return None,
+        };
+        Some(expansion)
+    }
+
+    // Errors in repeated code say which iteration of each rept around it failed
+    fn describe_iteration(&self, error: String) -> String {
+        if self.counters.is_empty() {
+            return error;
+        }
+        let iterations: Vec<String> = self
+            .counters
+            .iter()
+            .rev()
+            .map(|counter| {
+                format!(
+                    "iteration {} of {} of the rept at line {}",
+                    counter.iteration + 1,
+                    counter.count,
+                    counter.line
+                )
+            })
+            .collect();
+        format!("{} ({})", error, iterations.join(", "))
+    }
+
+    // Counters of the rept blocks around the line hide constants with the same name
+    fn constant(&self, name: &str) -> Option<u16> {
+        self.counters
+            .iter()
+            .rev()
+            .find(|counter| counter.name.as_deref() == Some(name))
+            .map(|counter| counter.iteration)
+            .or_else(|| self.constants.get(name).copied())
+    }
+
+    // Tracks if, ifdef, ifndef, else and endif, true when the line is one of them or sits in a
+    // branch that isn't assembled
+    fn conditional(&mut self, args: &[&str]) -> Result<bool, String> {
+        let assembling = self
+            .conditionals
+            .iter()
+            .all(|conditional| conditional.taken);
+        match args[0] {
+            "if" | "ifdef" | "ifndef" => {
+                self.check_operands(args)?;
+                // Conditions inside a skipped branch may use constants that don't exist
+                let taken = assembling
+                    && match args[0] {
+                        "ifdef" => self.constant(args[1]).is_some(),
+                        "ifndef" => self.constant(args[1]).is_none(),
+                        _ => self.evaluate_condition(&args[1..].join(" "))?,
+                    };
+                self.conditionals.push(Conditional {
+                    taken,
+                    seen_else: false,
+                    line: self.line_num,
+                });
+            }
+            "else" => match self.conditionals.last_mut() {
+                Some(conditional) if conditional.seen_else => {
+                    return Err(format!(
+                        "Second else for the if on line {} on line {}",
+                        conditional.line, self.line_num
+                    ))
+                }
+                Some(conditional) => {
+                    conditional.taken = !conditional.taken;
+                    conditional.seen_else = true;
+                }
+                None => return Err(format!("else without if on line {}", self.line_num)),
+            },
+            "endif" => {
+                if self.conditionals.pop().is_none() {
+                    return Err(format!("endif without if on line {}", self.line_num));
+                }
+            }
+            _ => return Ok(!assembling),
+        }
+        Ok(true)
+    }
+
+    fn evaluate_condition(&mut self, condition: &str) -> Result<bool, String> {
+        match self.evaluate(condition) {
+            Ok(value) => Ok(value != 0),
+            Err(error) => Err(format!(
+                "Invalid condition {}, {} on line {}",
+                condition, error, self.line_num
             )),
         }
     }
 
-    fn find_constant(&mut self, const_str: &str) -> Result<u16, String> {
-        match self.constants.get(const_str) {
-            Some(num) => Ok(*num),
+    fn evaluate(&self, text: &str) -> Result<i64, String> {
+        let mut context = ConstantContext { assembler: self };
+        Expr::parse(text).and_then(|expr| expr.eval(&mut context))
+    }
+
+    // Moves past the bytes of an instruction or directive while laying out the program
+    fn advance(&mut self, size: u16) -> Result<(), String> {
+        match self.pc.checked_add(size) {
+            Some(pc) => {
+                self.pc = pc;
+                Ok(())
+            }
             None => Err(format!(
-                "Uknown constant {} on line {}",
-                const_str, self.line_num
+                "Program runs past the end of program memory on line {}",
+                self.line_num
             )),
         }
     }
 
-    fn parse_label(&mut self, label_str: &str) -> Result<(), String> {
-        let label = match label_str.strip_suffix(":") {
-            Some(label) => label,
-            None => {
-                return Err(format!(
-                    "Malformated label {} on line {}",
-                    label_str, self.line_num
-                ))
-            }
+    // Closes the region being laid out and starts a new one at the address
+    fn start_region(&mut self, address: u16) {
+        if self.pc != self.region_start {
+            self.regions.push(Region {
+                start: self.region_start,
+                end: self.pc,
+                line: self.region_line,
+            });
+        }
+        self.pc = address;
+        self.region_start = address;
+        self.region_line = self.line_num;
+    }
+
+    // Directive operands are plain decimal, #decimal, hex, a constant or an expression of them
+    // like COUNT*4 written without spaces
+    fn parse_directive_value(&mut self, value: &str) -> Result<u16, String> {
+        let result = match Self::is_literal(value) {
+            true => self.parse_literal(value, Width::Word),
+            false => match value.parse::<u16>() {
+                Ok(num) => Ok(num),
+                Err(_) => self.find_constant(value),
+            },
         };
-        self.labels.insert(label.to_string(), self.pc);
-        Ok(())
+        match result {
+            Ok(num) => Ok(num),
+            Err(error) => match self.evaluate(value) {
+                Ok(num) if (0..=0xFFFF).contains(&num) => Ok(num as u16),
+                Ok(num) => Err(format!(
+                    "Value {} of {} doesn't fit in a word on line {}",
+                    num, value, self.line_num
+                )),
+                Err(_) => Err(error),
+            },
+        }
     }
 
-    fn parse_const(&mut self, const_str: &str, val: &str) -> Result<(), String> {
-        if let Some(num_type) = Self::get_imm_type(val) {
-            match num_type {
-                ImmType::Decimal => match self.parse_word_dec(val) {
-                    Ok(val) => {
-                        self.constants.insert(const_str.to_string(), val);
-                    }
-                    Err(error) => return Err(error),
-                },
-                ImmType::Hex => match self.parse_word_hex(val) {
-                    Ok(val) => {
-                        self.constants.insert(const_str.to_string(), val);
-                    }
-                    Err(error) => return Err(error),
-                },
-            }
+    fn align_padding(&mut self, alignment: &str) -> Result<u16, String> {
+        let alignment = self.parse_directive_value(alignment)?;
+        if alignment == 0 {
+            return Err(format!("Can't align to 0 on line {}", self.line_num));
+        }
+        self.alignment = self.alignment.max(alignment);
+        let remainder = self.pc % alignment;
+        match remainder {
+            0 => Ok(0),
+            _ => Ok(alignment - remainder),
+        }
+    }
+
+    // An if takes an expression with spaces in it, rept an optional counter name and fill an
+    // optional count, everything else takes an exact number of operands so nothing gets dropped
+    fn check_operands(&self, args: &[&str]) -> Result<(), String> {
+        let (expected, most) = match args[0] {
+            "const" | "var" => (2, 2),
+            "if" => (1, usize::MAX),
+            "rept" | "fill" => (1, 2),
+            "org" | "align" | "export" | "import" | "ifdef" | "ifndef" | "struct" => (1, 1),
+            mnemonic => match MNEMONICS.iter().find(|(name, _)| *name == mnemonic) {
+                Some((_, count)) => (*count, *count),
+                None => return Ok(()),
+            },
+        };
+        if args.len() - 1 < expected {
+            return Err(format!(
+                "Expected {} operands for {} on line {}",
+                expected, args[0], self.line_num
+            ));
+        }
+        if args.len() - 1 > most {
+            return Err(format!(
+                "Unexpected operand {} for {} on line {}",
+                args[most + 1],
+                args[0],
+                self.line_num
+            ));
         }
         Ok(())
     }
 
-    fn parse_byte_dec(&mut self, number_str: &str) -> Result<u8, String> {
-        let striped_string = match number_str.strip_prefix("#") {
-            Some(str) => str,
-            None => {
-                return Err(format!(
-                    "Malformated number {} on line {}",
-                    number_str, self.line_num
-                ))
-            }
+    // Writes at the current address, a gap left by org is padded with the fill byte
+    fn write_byte(&mut self, byte: u8) {
+        let index = self.pc as usize;
+        if self.program.len() <= index {
+            self.program.resize(index + 1, self.fill_byte);
+        }
+        self.program[index] = byte;
+        self.pc = self.pc.wrapping_add(1);
+    }
+
+    fn write_word(&mut self, word: u16) {
+        let bytes = word.to_le_bytes();
+        self.write_byte(bytes[0]);
+        self.write_byte(bytes[1]);
+    }
+
+    // Literals are #decimal, 0x hex, 0b binary or a 'c' character. A minus makes them
+    // negative and _ can separate digits, a lone - or -- is an anonymous label instead
+    fn is_literal(text: &str) -> bool {
+        let unsigned = text.strip_prefix('-').unwrap_or(text);
+        unsigned.starts_with('#')
+            || unsigned.starts_with("0x")
+            || unsigned.starts_with("0b")
+            || (text.len() == 3 && text.starts_with('\'') && text.ends_with('\''))
+    }
+
+    // Negative numbers are stored as two's complement of the width
+    fn parse_literal(&self, text: &str, width: Width) -> Result<u16, String> {
+        match parse_number(text) {
+            Ok(num) => self.fit(num, text, width),
+            Err(_) => Err(format!(
+                "Malformated number {} on line {}",
+                text, self.line_num
+            )),
+        }
+    }
+
+    fn fit(&self, num: i64, text: &str, width: Width) -> Result<u16, String> {
+        let (range, name) = match width {
+            Width::Byte => (-0x80..=0xFF, "byte"),
+            Width::Word => (-0x8000..=0xFFFF, "word"),
         };
+        if !range.contains(&num) {
+            return Err(format!(
+                "{} doesn't fit in a {} on line {}",
+                text, name, self.line_num
+            ));
+        }
+        Ok(match width {
+            Width::Byte => num as u8 as u16,
+            Width::Word => num as u16,
+        })
+    }
 
-        match u8::from_str_radix(striped_string, 10) {
-            Ok(num) => {
-                println!("{}", num);
-                return Ok(num);
-            }
-            Err(_) => (),
+    // An immediate operand is a literal, a constant or for words also a label. Constants
+    // hold words so one from 0xFF80 up is a negative byte
+    fn parse_value(&mut self, text: &str, width: Width) -> Result<u16, String> {
+        if Self::is_literal(text) {
+            return self.parse_literal(text, width);
         }
+        if let Some(value) = self.constant(text) {
+            return match width {
+                Width::Byte => self.fit(value as i16 as i64, text, width),
+                Width::Word => Ok(value),
+            };
+        }
+        match width {
+            Width::Byte => Err(format!(
+                "Uknown constant {} on line {}",
+                text, self.line_num
+            )),
+            Width::Word => self.find_label(text).map_err(|_| {
+                format!(
+                    "Uknown constant or label {} on line {}",
+                    text, self.line_num
+                )
+            }),
+        }
+    }
 
-        match i8::from_str_radix(striped_string, 10) {
-            Ok(num) => Ok(num as u8),
-            Err(error) => {
-                return Err(format!(
-                    "Error parsing decimal number {} on line {} /n{}",
-                    number_str,
-                    self.line_num,
-                    error.to_string()
-                ))
-            }
+    // Jump and call targets are a literal address or a label
+    fn parse_target(&mut self, text: &str) -> Result<u16, String> {
+        match Self::is_literal(text) {
+            true => self.parse_literal(text, Width::Word),
+            false => self.find_label(text),
         }
     }
 
-    fn parse_word_hex(&mut self, number_str: &str) -> Result<u16, String> {
-        let striped_string = match number_str.strip_prefix("0x") {
-            Some(str) => str,
-            None => {
-                return Err(format!(
-                    "Malformated number {} on line {}",
-                    number_str, self.line_num
-                ))
-            }
+    // Label operands are always written at the current address, which is remembered so the
+    // linker can move the address along with the code
+    fn find_label(&mut self, label_str: &str) -> Result<u16, String> {
+        let name = qualify_label(&self.scope, label_str);
+        let address = match is_anonymous_reference(label_str) {
+            true => Some(self.find_anonymous_label(label_str)?),
+            false => self.labels.get(&name).copied(),
+        };
+        let symbol = match address {
+            Some(_) => None,
+            None if self.imports.contains_key(&name) => Some(name.clone()),
+            None => None,
         };
+        if address.is_some() || symbol.is_some() {
+            self.relocations.push(Relocation {
+                offset: self.pc,
+                symbol,
+                line: self.line_num,
+            });
+        }
+        match address {
+            Some(num) => Ok(num),
+            None if self.imports.contains_key(&name) => Ok(0),
+            None => Err(format!("Uknown label {} on line {}", name, self.line_num)),
+        }
+    }
 
-        match u16::from_str_radix(striped_string, 16) {
-            Ok(num) => return Ok(num),
-            Err(_) => (),
+    // "-" is the closest anonymous label above the line and "--" the one before that,
+    // "+" and "++" count forward the same way
+    fn find_anonymous_label(&self, reference: &str) -> Result<u16, String> {
+        let count = reference.len();
+        let found = match reference.starts_with('+') {
+            true => self
+                .anonymous_labels
+                .iter()
+                .filter(|label| label.forward && label.index > self.line_index)
+                .nth(count - 1),
+            false => self
+                .anonymous_labels
+                .iter()
+                .rev()
+                .filter(|label| !label.forward && label.index < self.line_index)
+                .nth(count - 1),
+        };
+        match found {
+            Some(label) => Ok(label.address),
+            None => Err(format!(
+                "No anonymous label for {} on line {}",
+                reference, self.line_num
+            )),
         }
+    }
 
-        match i16::from_str_radix(striped_string, 16) {
-            Ok(num) => Ok(num as u16),
-            Err(error) => {
-                return Err(format!(
-                    "Error parsing hexadecimal number {} on line {} /n{}",
-                    number_str,
-                    self.line_num,
-                    error.to_string()
-                ))
-            }
+    fn find_constant(&mut self, const_str: &str) -> Result<u16, String> {
+        match self.constant(const_str) {
+            Some(num) => Ok(num),
+            None => Err(format!(
+                "Uknown constant {} on line {}",
+                const_str, self.line_num
+            )),
         }
     }
 
-    fn parse_word_dec(&mut self, number_str: &str) -> Result<u16, String> {
-        let striped_string = match number_str.strip_prefix("#") {
-            Some(str) => str,
+    fn parse_label(&mut self, label_str: &str) -> Result<(), String> {
+        let label = match label_str.strip_suffix(":") {
+            Some(label) => label,
             None => {
                 return Err(format!(
-                    "Malformated number {} on line {}",
-                    number_str, self.line_num
+                    "Malformated label {} on line {}",
+                    label_str, self.line_num
                 ))
             }
         };
+        if label == "+" || label == "-" {
+            self.anonymous_labels.push(AnonymousLabel {
+                forward: label == "+",
+                index: self.line_index,
+                address: self.pc,
+            });
+            return Ok(());
+        }
+        if label.starts_with('.') && self.scope.is_empty() {
+            return Err(format!(
+                "Local label {} needs a label before it on line {}",
+                label, self.line_num
+            ));
+        }
 
-        match u16::from_str_radix(striped_string, 10) {
-            Ok(num) => {
-                println!("{}", num);
-                return Ok(num);
-            }
-            Err(_) => (),
+        let name = qualify_label(&self.scope, label);
+        if let Some(line) = self.label_lines.get(&name) {
+            return Err(format!(
+                "Label {} was already defined on line {}, defined again on line {}",
+                name, line, self.line_num
+            ));
+        }
+        if !label.starts_with('.') {
+            self.scope = name.clone();
         }
+        self.labels.insert(name.clone(), self.pc);
+        self.label_lines.insert(name, self.line_num);
+        Ok(())
+    }
 
-        match i16::from_str_radix(striped_string, 10) {
-            Ok(num) => Ok(num as u16),
-            Err(error) => {
-                return Err(format!(
-                    "Error parsing decimal number {} on line {} /n{}",
-                    number_str,
-                    self.line_num,
-                    error.to_string()
-                ))
-            }
+    fn parse_const(&mut self, const_str: &str, val: &str) -> Result<(), String> {
+        let value = self.parse_directive_value(val)?;
+        // The source value is only the default for a constant given with -D
+        if !self.defines.contains(const_str) {
+            self.constants.insert(const_str.to_string(), value);
         }
+        Ok(())
     }
 
     fn parse_register(&mut self, register_str: &str) -> Result<(u8, Width), String> {
         let register_chars: Vec<char> = register_str.chars().collect();
+        if register_chars.len() < 2 || register_chars[0] != '$' {
+            return Err(format!(
+                "Invalid register {} on line {}",
+                register_str, self.line_num
+            ));
+        }
 
         let reg_num = match u8::from_str_radix(register_chars[1].to_string().as_str(), 10) {
             Ok(num) => num,
@@ -367,36 +1227,121 @@ impl Assembler {
             }
         }
     }
-
-    fn get_imm_type(num_str: &str) -> Option<ImmType> {
-        if num_str.contains("#") {
-            return Some(ImmType::Decimal);
-        } else if num_str.contains("0x") {
-            return Some(ImmType::Hex);
-        }
-
-        None
-    }
 }
 
 impl Assembler {
     pub fn assemble(&mut self) -> Result<Vec<u8>, String> {
-        self.line_num = 0;
+        let program = self.assemble_code()?;
+        match self.relocations.iter().find(|relocation| relocation.symbol.is_some()) {
+            Some(relocation) => Err(format!(
+                "Label {} is imported, the program has to be built as an object and linked on line {}",
+                relocation.symbol.clone().unwrap_or_default(),
+                relocation.line
+            )),
+            None => Ok(program),
+        }
+    }
 
-        let lines: Vec<String> = self
-            .file_string
-            .lines()
-            .filter(|x| !x.contains("//"))
-            .map(|x| x.to_string())
+    // Assembles the source as an object the linker can place anywhere
+    pub fn assemble_object(&mut self) -> Result<Object, String> {
+        if let Some(region) = self.regions.iter().find(|region| region.start != 0) {
+            return Err(format!(
+                "Objects are placed by the linker, org can't be used on line {}",
+                region.line
+            ));
+        }
+        let code = self.assemble_code()?;
+        // The code length is stored as a word
+        if code.len() > u16::MAX as usize {
+            return Err(format!(
+                "Object code is {} bytes, an object holds at most {}",
+                code.len(),
+                u16::MAX
+            ));
+        }
+        let mut exports: Vec<(String, u16)> = self
+            .exports
+            .iter()
+            .map(|(name, _)| (name.clone(), self.labels[name]))
             .collect();
+        exports.dedup();
+        let mut imports: Vec<String> = self.imports.keys().cloned().collect();
+        imports.sort();
+
+        Ok(Object {
+            source: self.file_path.clone(),
+            alignment: self.alignment,
+            code,
+            exports,
+            imports,
+            relocations: self.relocations.clone(),
+            features: self.features.clone(),
+        })
+    }
 
-        for line in lines.iter() {
-            if line.len() == 0 {
+    fn assemble_code(&mut self) -> Result<Vec<u8>, String> {
+        self.line_num = 0;
+        self.pc = 0;
+        self.fill_byte = 0;
+        self.relocations.clear();
+        self.scope.clear();
+        self.expansions.clear();
+        self.listed.clear();
+        self.features.clear();
+
+        let result = self.emit().map_err(|error| self.describe_iteration(error));
+        self.counters.clear();
+        result
+    }
+
+    fn emit(&mut self) -> Result<Vec<u8>, String> {
+        let lines = self.expanded.clone();
+        for (index, line) in lines.iter().enumerate() {
+            self.enter_line(index, line);
+            let args = SourceLine::parse(&line.text).words();
+            if args.is_empty()
+                || self.inactive_lines.contains(&index)
+                || self.struct_lines.contains(&index)
+            {
                 continue;
             }
-            let args: Vec<&str> = line.split(" ").collect();
+            let address = self.pc;
+            // Local labels below belong to the last global label
+            if let Some(label) = args[0].strip_suffix(':') {
+                if !label.starts_with('.') && label != "+" && label != "-" {
+                    self.scope = label.to_string();
+                }
+            }
 
             match args[0] {
+                "org" => {
+                    self.pc = self.parse_directive_value(args[1])?;
+                    continue;
+                }
+                "var" => continue,
+                "align" => {
+                    let padding = self.align_padding(args[1])?;
+                    for _ in 0..padding {
+                        self.write_byte(self.fill_byte);
+                    }
+                    continue;
+                }
+                "fill" => {
+                    let value = self.parse_directive_value(args[1])?;
+                    if value > 0xFF {
+                        return Err(format!(
+                            "Fill byte {} is bigger than a byte on line {}",
+                            args[1], self.line_num
+                        ));
+                    }
+                    self.fill_byte = value as u8;
+                    if args.len() > 2 {
+                        for _ in 0..self.parse_directive_value(args[2])? {
+                            self.write_byte(self.fill_byte);
+                        }
+                    }
+                    continue;
+                }
                 "LOAD" => {
                     let reg = match self.parse_register(args[1]) {
                         Ok(reg) => reg,
@@ -407,44 +1352,14 @@ impl Assembler {
                         Width::Byte => {
                             self.write_byte(0);
                             self.write_byte(reg.0);
-                            if let Some(num_type) = Self::get_imm_type(args[2]) {
-                                match num_type {
-                                    ImmType::Decimal => match self.parse_byte_dec(args[2]) {
-                                        Ok(val) => self.write_byte(val),
-                                        Err(error) => return Err(error),
-                                    },
-                                    ImmType::Hex => match self.parse_byte_hex(args[2]) {
-                                        Ok(val) => self.write_byte(val),
-                                        Err(error) => return Err(error),
-                                    },
-                                }
-                            }
+                            let value = self.parse_value(args[2], Width::Byte)?;
+                            self.write_byte(value as u8);
                         }
                         Width::Word => {
                             self.write_byte(1);
                             self.write_byte(reg.0);
-                            if let Some(num_type) = Self::get_imm_type(args[2]) {
-                                match num_type {
-                                    ImmType::Decimal => match self.parse_word_dec(args[2]) {
-                                        Ok(val) => self.write_word(val),
-                                        Err(error) => return Err(error),
-                                    },
-                                    ImmType::Hex => match self.parse_word_hex(args[2]) {
-                                        Ok(val) => self.write_word(val),
-                                        Err(error) => return Err(error),
-                                    },
-                                }
-                            } else {
-                                match self.find_constant(args[2]) {
-                                    Ok(val) => self.write_word(val),
-                                    Err(_) => {
-                                        return Err(format!(
-                                            "Uknown constant {} on line {}",
-                                            args[2], self.line_num
-                                        ))
-                                    }
-                                }
-                            }
+                            let value = self.parse_value(args[2], Width::Word)?;
+                            self.write_word(value);
                         }
                     }
                 }
@@ -581,123 +1496,33 @@ impl Assembler {
                 }
                 "JMP" => {
                     self.write_byte(10);
-                    if let Some(imm_type) = Self::get_imm_type(args[1]) {
-                        match imm_type {
-                            ImmType::Decimal => match self.parse_word_dec(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                            ImmType::Hex => match self.parse_word_hex(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                        }
-                    } else {
-                        match self.find_label(args[1]) {
-                            Ok(val) => self.write_word(val),
-                            Err(error) => return Err(error),
-                        }
-                    }
+                    let target = self.parse_target(args[1])?;
+                    self.write_word(target);
                 }
                 "CJMP" => {
                     self.write_byte(11);
-                    if let Some(imm_type) = Self::get_imm_type(args[1]) {
-                        match imm_type {
-                            ImmType::Decimal => match self.parse_word_dec(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                            ImmType::Hex => match self.parse_word_hex(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                        }
-                    } else {
-                        match self.find_label(args[1]) {
-                            Ok(val) => self.write_word(val),
-                            Err(error) => return Err(error),
-                        }
-                    }
+                    let target = self.parse_target(args[1])?;
+                    self.write_word(target);
                 }
                 "NJMP" => {
                     self.write_byte(12);
-                    if let Some(imm_type) = Self::get_imm_type(args[1]) {
-                        match imm_type {
-                            ImmType::Decimal => match self.parse_word_dec(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                            ImmType::Hex => match self.parse_word_hex(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                        }
-                    } else {
-                        match self.find_label(args[1]) {
-                            Ok(val) => self.write_word(val),
-                            Err(error) => return Err(error),
-                        }
-                    }
+                    let target = self.parse_target(args[1])?;
+                    self.write_word(target);
                 }
                 "CALL" => {
                     self.write_byte(53);
-                    if let Some(imm_type) = Self::get_imm_type(args[1]) {
-                        match imm_type {
-                            ImmType::Decimal => match self.parse_word_dec(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                            ImmType::Hex => match self.parse_word_hex(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                        }
-                    } else {
-                        match self.find_label(args[1]) {
-                            Ok(val) => self.write_word(val),
-                            Err(error) => return Err(error),
-                        }
-                    }
+                    let target = self.parse_target(args[1])?;
+                    self.write_word(target);
                 }
                 "CCALL" => {
                     self.write_byte(54);
-                    if let Some(imm_type) = Self::get_imm_type(args[1]) {
-                        match imm_type {
-                            ImmType::Decimal => match self.parse_word_dec(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                            ImmType::Hex => match self.parse_word_hex(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                        }
-                    } else {
-                        match self.find_label(args[1]) {
-                            Ok(val) => self.write_word(val),
-                            Err(error) => return Err(error),
-                        }
-                    }
+                    let target = self.parse_target(args[1])?;
+                    self.write_word(target);
                 }
                 "NCALL" => {
                     self.write_byte(55);
-                    if let Some(imm_type) = Self::get_imm_type(args[1]) {
-                        match imm_type {
-                            ImmType::Decimal => match self.parse_word_dec(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                            ImmType::Hex => match self.parse_word_hex(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                        }
-                    } else {
-                        match self.find_label(args[1]) {
-                            Ok(val) => self.write_word(val),
-                            Err(error) => return Err(error),
-                        }
-                    }
+                    let target = self.parse_target(args[1])?;
+                    self.write_word(target);
                 }
                 "ADD" => {
                     let (reg1, width1) = match self.parse_register(args[1]) {
@@ -1186,33 +2011,199 @@ impl Assembler {
                 "RET" => self.write_byte(48),
                 "JDRAW" => {
                     self.write_byte(52);
-                    if let Some(imm_type) = Self::get_imm_type(args[1]) {
-                        match imm_type {
-                            ImmType::Decimal => match self.parse_word_dec(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                            ImmType::Hex => match self.parse_word_hex(args[1]) {
-                                Ok(val) => self.write_word(val),
-                                Err(error) => return Err(error),
-                            },
-                        }
-                    } else {
-                        match self.find_label(args[1]) {
-                            Ok(val) => self.write_word(val),
-                            Err(error) => return Err(error),
-                        }
+                    let target = self.parse_target(args[1])?;
+                    self.write_word(target);
+                }
+                "INC" | "DEC" | "NEG" | "NOT" => {
+                    let (reg, width) = self.parse_register(args[1])?;
+                    let opcode = match args[0] {
+                        "INC" => 56,
+                        "DEC" => 58,
+                        "NEG" => 60,
+                        _ => 62,
+                    };
+                    match width {
+                        Width::Byte => self.write_byte(opcode),
+                        Width::Word => self.write_byte(opcode + 1),
+                    }
+                    self.write_byte(reg);
+                }
+                "NOP" => self.write_byte(64),
+                "HALT" => {
+                    let (reg, width) = self.parse_register(args[1])?;
+                    match width {
+                        Width::Byte => self.write_byte(65),
+                        Width::Word => self.write_byte(66),
                     }
+                    self.write_byte(reg);
+                    self.add_feature("halt");
                 }
                 _ => (),
             }
+
+            if self.pc != address {
+                self.line_info.push((address, self.line_num));
+                if let Some((pseudo, _)) = &line.pseudo {
+                    self.expansions.insert(address, pseudo.clone());
+                }
+            }
+            if self.pc != address || args[0].ends_with(':') {
+                self.listed.push((address, self.pc, index));
+            }
         }
 
         Ok(self.program.clone())
     }
-}
 
-enum ImmType {
-    Decimal,
-    Hex,
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    // The placed regions in address order with the free space between them
+    pub fn layout(&self) -> String {
+        let mut regions = self.regions.clone();
+        regions.sort_by_key(|region| region.start);
+        let mut layout = String::new();
+        let mut free_start = 0u32;
+        for region in regions.iter() {
+            if (region.start as u32) > free_start {
+                layout.push_str(&format!(
+                    "0x{:04X}-0x{:04X} {:>6} bytes free\n",
+                    free_start,
+                    region.start - 1,
+                    region.start as u32 - free_start
+                ));
+            }
+            let label = self
+                .labels
+                .iter()
+                .filter(|(_, address)| **address == region.start)
+                .map(|(name, _)| format!(" {}", name))
+                .min()
+                .unwrap_or_default();
+            layout.push_str(&format!(
+                "0x{:04X}-0x{:04X} {:>6} bytes from line {}{}\n",
+                region.start,
+                region.end - 1,
+                region.len(),
+                region.line,
+                label
+            ));
+            free_start = region.end as u32;
+        }
+        if free_start < 0x10000 {
+            layout.push_str(&format!(
+                "0x{:04X}-0xFFFF {:>6} bytes free\n",
+                free_start,
+                0x10000 - free_start
+            ));
+        }
+
+        if !self.variables.is_empty() {
+            layout.push_str("RAM\n");
+        }
+        for variable in self.variables.iter().filter(|variable| variable.size > 0) {
+            layout.push_str(&format!(
+                "0x{:04X}-0x{:04X} {:>6} bytes from line {} {}\n",
+                variable.address,
+                variable.address + variable.size - 1,
+                variable.size,
+                variable.line,
+                variable.name
+            ));
+        }
+        if !self.variables.is_empty() {
+            layout.push_str(&format!(
+                "0x{:04X}-0x{:04X} {:>6} bytes left for the stack\n",
+                self.ram_pc,
+                STACK_START - 1,
+                STACK_START - self.ram_pc
+            ));
+        }
+        layout
+    }
+
+    // Marks a VM feature as needed by the program, each one is only listed once
+    fn add_feature(&mut self, feature: &str) {
+        if !self.features.iter().any(|found| found == feature) {
+            self.features.push(feature.to_string());
+        }
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    // Every assembled line with its address and bytes, a pseudo-instruction is listed with
+    // the instructions it expands to below it
+    pub fn listing(&self) -> String {
+        let mut listing = String::new();
+        for (start, end, index) in self.listed.iter() {
+            let line = &self.expanded[*index];
+            let text = SourceLine::parse(&line.text).words().join(" ");
+            let bytes = &self.program[*start as usize..*end as usize];
+            let mut encoding: Vec<String> = bytes
+                .iter()
+                .take(4)
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            if bytes.len() > 4 {
+                encoding[3] = "..".to_string();
+            }
+            let address = format!("0x{:04X}", start);
+            match &line.pseudo {
+                Some((pseudo, position)) => {
+                    if *position == 0 {
+                        listing.push_str(&format!(
+                            "{:>5} {:6} {:11}  {}\n",
+                            line.line, "", "", pseudo
+                        ));
+                    }
+                    listing.push_str(&format!(
+                        "{:>5} {} {:11}    {}\n",
+                        "",
+                        address,
+                        encoding.join(" "),
+                        text
+                    ));
+                }
+                None => listing.push_str(&format!(
+                    "{:>5} {} {:11}  {}\n",
+                    line.line,
+                    address,
+                    encoding.join(" "),
+                    text
+                )),
+            }
+        }
+        listing
+    }
+
+    // Source lines that no copy of is assembled
+    fn inactive_source_lines(&self) -> HashSet<usize> {
+        let (inactive, active): (Vec<(usize, &ExpandedLine)>, _) = self
+            .expanded
+            .iter()
+            .enumerate()
+            .partition(|(index, _)| self.inactive_lines.contains(index));
+        let active: HashSet<usize> = active.iter().map(|(_, line)| line.line).collect();
+        inactive
+            .iter()
+            .map(|(_, line)| line.line)
+            .filter(|line| !active.contains(line))
+            .collect()
+    }
+
+    pub fn debug_info(&self) -> DebugInfo {
+        DebugInfo {
+            labels: self.labels.clone(),
+            constants: self.constants.clone(),
+            label_lines: self.label_lines.clone(),
+            lines: self.line_info.clone(),
+            source_path: self.file_path.clone(),
+            source: self.file_string.clone(),
+            inactive_lines: self.inactive_source_lines(),
+            expansions: self.expansions.clone(),
+        }
+    }
 }
@@ -0,0 +1,257 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{assembler::DebugInfo, disassembler::Instruction, opcodes::Opcode};
+
+// A straight run of instructions that is only entered at the top and only left at the bottom
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<Instruction>,
+    // Blocks execution can continue in, calls are expected to return to the next block
+    pub successors: Vec<u16>,
+    pub calls: Vec<u16>,
+}
+
+impl BasicBlock {
+    pub fn last(&self) -> &Instruction {
+        &self.instructions[self.instructions.len() - 1]
+    }
+
+    pub fn end(&self) -> u16 {
+        self.last().next_address()
+    }
+}
+
+// The blocks reached from an entry point without following calls, address 0 is the main loop
+pub struct Routine {
+    pub entry: u16,
+    pub blocks: Vec<u16>,
+    pub calls: Vec<u16>,
+}
+
+pub struct ControlFlowGraph {
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    pub routines: Vec<Routine>,
+}
+
+impl ControlFlowGraph {
+    // Splits the program at every instruction the assembler emitted, so data after the code
+    // is never decoded as instructions
+    pub fn build(program: &[u8], debug_info: &DebugInfo) -> Self {
+        let instructions: BTreeMap<u16, Instruction> = debug_info
+            .lines
+            .iter()
+            .map(|(address, _)| (*address, Instruction::decode(program, *address)))
+            .collect();
+
+        // Blocks start at address 0, at labels, at jump and call targets and after anything
+        // that doesn't just carry on to the next instruction
+        let mut leaders = BTreeSet::from([0u16]);
+        leaders.extend(debug_info.labels.values());
+        for instruction in instructions.values() {
+            leaders.extend(instruction.target());
+            if instruction.successors() != vec![instruction.next_address()] || instruction.is_call()
+            {
+                leaders.insert(instruction.next_address());
+            }
+        }
+
+        let mut blocks: BTreeMap<u16, BasicBlock> = BTreeMap::new();
+        let mut current: Option<BasicBlock> = None;
+        for instruction in instructions.values() {
+            let starts_block = match &current {
+                Some(block) => {
+                    leaders.contains(&instruction.address) || block.end() != instruction.address
+                }
+                None => true,
+            };
+            if starts_block {
+                if let Some(block) = current.take() {
+                    blocks.insert(block.start, block);
+                }
+                current = Some(BasicBlock {
+                    start: instruction.address,
+                    instructions: Vec::new(),
+                    successors: Vec::new(),
+                    calls: Vec::new(),
+                });
+            }
+            if let Some(block) = current.as_mut() {
+                block.instructions.push(instruction.clone());
+            }
+        }
+        if let Some(block) = current {
+            blocks.insert(block.start, block);
+        }
+
+        for block in blocks.values_mut() {
+            let last = block.last();
+            let successors = last.successors();
+            let calls: Vec<u16> = match last.is_call() {
+                true => last.target().into_iter().collect(),
+                false => Vec::new(),
+            };
+            block.successors = successors;
+            block.calls = calls;
+        }
+
+        let mut cfg = Self {
+            blocks,
+            routines: Vec::new(),
+        };
+        cfg.find_routines();
+        cfg
+    }
+
+    fn find_routines(&mut self) {
+        let mut entries = VecDeque::new();
+        if self.blocks.contains_key(&0) {
+            entries.push_back(0u16);
+        }
+        let mut seen = BTreeSet::new();
+        while let Some(entry) = entries.pop_front() {
+            if !seen.insert(entry) || !self.blocks.contains_key(&entry) {
+                continue;
+            }
+            let mut blocks = BTreeSet::new();
+            let mut calls = BTreeSet::new();
+            let mut queue = VecDeque::from([entry]);
+            while let Some(start) = queue.pop_front() {
+                let block = match self.blocks.get(&start) {
+                    Some(block) => block,
+                    None => continue,
+                };
+                if !blocks.insert(start) {
+                    continue;
+                }
+                queue.extend(block.successors.iter());
+                calls.extend(block.calls.iter());
+            }
+            entries.extend(calls.iter());
+            self.routines.push(Routine {
+                entry,
+                blocks: blocks.into_iter().collect(),
+                calls: calls.into_iter().collect(),
+            });
+        }
+        self.routines.sort_by_key(|routine| routine.entry);
+    }
+
+    pub fn routine(&self, entry: u16) -> Option<&Routine> {
+        self.routines.iter().find(|routine| routine.entry == entry)
+    }
+
+    // Blocks no routine ever reaches
+    pub fn unreachable_blocks(&self) -> Vec<u16> {
+        self.blocks
+            .keys()
+            .filter(|start| {
+                !self
+                    .routines
+                    .iter()
+                    .any(|routine| routine.blocks.contains(start))
+            })
+            .copied()
+            .collect()
+    }
+
+    // Graphviz graph with one cluster per routine, each block lists its instructions
+    pub fn to_dot(&self, debug_info: &DebugInfo) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box fontname=monospace];\n");
+        let mut clusters: Vec<(String, &[u16])> = self
+            .routines
+            .iter()
+            .map(|routine| {
+                (
+                    routine_name(routine.entry, debug_info),
+                    routine.blocks.as_slice(),
+                )
+            })
+            .collect();
+        let unreachable = self.unreachable_blocks();
+        if !unreachable.is_empty() {
+            clusters.push(("unreachable".to_string(), unreachable.as_slice()));
+        }
+
+        // A block shared by several routines is drawn in the first one
+        let mut drawn = BTreeSet::new();
+        for (index, (name, blocks)) in clusters.iter().enumerate() {
+            dot.push_str(&format!(
+                "    subgraph cluster_{} {{\n        label=\"{}\";\n",
+                index,
+                escape(name)
+            ));
+            for start in blocks.iter().filter(|start| drawn.insert(**start)) {
+                let block = &self.blocks[start];
+                let mut label = match debug_info.label_before(block.start) {
+                    Some((name, address)) if address == block.start => format!("{}:\\l", name),
+                    _ => format!("0x{:04X}:\\l", block.start),
+                };
+                for instruction in block.instructions.iter() {
+                    label.push_str(&format!(
+                        "0x{:04X}  {}\\l",
+                        instruction.address,
+                        escape(&instruction.to_string())
+                    ));
+                }
+                dot.push_str(&format!(
+                    "        b{} [label=\"{}\"];\n",
+                    block.start, label
+                ));
+            }
+            dot.push_str("    }\n");
+        }
+
+        for block in self.blocks.values() {
+            let conditional = block.successors.len() > 1;
+            for (index, successor) in block.successors.iter().enumerate() {
+                if !self.blocks.contains_key(successor) {
+                    continue;
+                }
+                let style = match (block.last().opcode, conditional, index) {
+                    (Opcode::RDRAW | Opcode::JDRAW, _, _) => " [style=dashed label=\"frame\"]",
+                    (_, true, 0) => " [label=\"taken\"]",
+                    _ => "",
+                };
+                dot.push_str(&format!(
+                    "    b{} -> b{}{};\n",
+                    block.start, successor, style
+                ));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // Graphviz graph of which routines call which
+    pub fn call_graph_dot(&self, debug_info: &DebugInfo) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=box];\n");
+        for routine in self.routines.iter() {
+            dot.push_str(&format!(
+                "    r{} [label=\"{}\"];\n",
+                routine.entry,
+                escape(&routine_name(routine.entry, debug_info))
+            ));
+        }
+        for routine in self.routines.iter() {
+            for call in routine.calls.iter() {
+                dot.push_str(&format!("    r{} -> r{};\n", routine.entry, call));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn routine_name(entry: u16, debug_info: &DebugInfo) -> String {
+    match entry {
+        0 => match debug_info.label_before(0) {
+            Some((name, 0)) => name.to_string(),
+            _ => "main".to_string(),
+        },
+        _ => debug_info.describe(entry),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
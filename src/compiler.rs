@@ -0,0 +1,1379 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    expr::parse_number,
+    vm_main::{DOWN_ADDRESS, LEFT_ADDRESS, RIGHT_ADDRESS, UP_ADDRESS},
+};
+
+// Tiles are stored column by column from the start of VRAM, three bytes each
+const VRAM_START: u16 = 0x87FF;
+const SCREEN_HEIGHT: u16 = 80;
+// $7 holds the address for STORE and READ so expressions get the other seven
+const REGISTERS: usize = 7;
+
+const INPUTS: [(&str, u16); 4] = [
+    ("input_up", UP_ADDRESS),
+    ("input_down", DOWN_ADDRESS),
+    ("input_right", RIGHT_ADDRESS),
+    ("input_left", LEFT_ADDRESS),
+];
+const BUILTINS: [&str; 8] = [
+    "draw",
+    "pixel",
+    "peek",
+    "poke",
+    "input_up",
+    "input_down",
+    "input_right",
+    "input_left",
+];
+const KEYWORDS: [&str; 12] = [
+    "u8", "u16", "i8", "i16", "void", "const", "if", "else", "while", "return", "break", "continue",
+];
+
+// Longest symbols first so "<=" isn't read as "<" and "="
+const SYMBOLS: [&str; 32] = [
+    "<<=", ">>=", "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "+=", "-=", "*=", "/=", "%=",
+    "<", ">", "+", "-", "*", "/", "%", "!", "=", "(", ")", "{", "}", "[", "]", ",", ";",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    U8,
+    U16,
+    I8,
+    I16,
+}
+
+impl Type {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "u8" => Some(Self::U8),
+            "u16" => Some(Self::U16),
+            "i8" => Some(Self::I8),
+            "i16" => Some(Self::I16),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> u16 {
+        match self {
+            Self::U8 | Self::I8 => 1,
+            Self::U16 | Self::I16 => 2,
+        }
+    }
+
+    fn signed(self) -> bool {
+        matches!(self, Self::I8 | Self::I16)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Var(String, usize),
+    Index(String, Box<Expr>, usize),
+    Call(String, Vec<Expr>, usize),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+struct Declaration {
+    name: String,
+    ty: Type,
+    count: Option<u16>,
+    init: Option<Expr>,
+    line: usize,
+}
+
+#[derive(Debug, Clone)]
+enum StatementKind {
+    Declare(Declaration),
+    Assign(Expr, Expr),
+    If(Expr, Vec<Statement>, Vec<Statement>),
+    While(Expr, Vec<Statement>),
+    Return(Option<Expr>),
+    Break,
+    Continue,
+    Call(Expr),
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    line: usize,
+    kind: StatementKind,
+}
+
+#[derive(Debug, Clone)]
+struct Function {
+    name: String,
+    ret: Option<Type>,
+    params: Vec<(String, Type)>,
+    body: Vec<Statement>,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let code = match text.find("//") {
+            Some(index) => &text[..index],
+            None => text,
+        };
+        let chars: Vec<char> = code.chars().collect();
+        let mut index = 0;
+        while index < chars.len() {
+            let c = chars[index];
+            if c.is_whitespace() {
+                index += 1;
+                continue;
+            }
+            if c == '\'' {
+                if index + 2 < chars.len() && chars[index + 2] == '\'' {
+                    tokens.push((Token::Number(chars[index + 1] as i64), line));
+                    index += 3;
+                    continue;
+                }
+                return Err(format!("Malformated character literal on line {}", line));
+            }
+            if c.is_ascii_alphanumeric() || c == '_' {
+                let start = index;
+                while index < chars.len()
+                    && (chars[index].is_ascii_alphanumeric() || chars[index] == '_')
+                {
+                    index += 1;
+                }
+                let word: String = chars[start..index].iter().collect();
+                match c.is_ascii_digit() {
+                    true => match parse_number(&word) {
+                        Ok(num) => tokens.push((Token::Number(num), line)),
+                        Err(_) => {
+                            return Err(format!("Malformated number {} on line {}", word, line))
+                        }
+                    },
+                    false => tokens.push((Token::Name(word), line)),
+                }
+                continue;
+            }
+            let rest: String = chars[index..].iter().collect();
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                Some(symbol) => {
+                    tokens.push((Token::Symbol(symbol), line));
+                    index += symbol.len();
+                }
+                None if "&|^~".contains(c) => {
+                    return Err(format!(
+                        "Bitwise operator {} has no instruction in Rusty Boi on line {}",
+                        c, line
+                    ))
+                }
+                None => return Err(format!("Unexpected character {} on line {}", c, line)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn precedence(symbol: &str) -> Option<u8> {
+    match symbol {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "==" | "!=" => Some(3),
+        "<" | "<=" | ">" | ">=" => Some(4),
+        "<<" | ">>" => Some(5),
+        "+" | "-" => Some(6),
+        "*" | "/" | "%" => Some(7),
+        _ => None,
+    }
+}
+
+// The operator of a compound assignment like +=
+fn compound_operator(symbol: &str) -> Option<&'static str> {
+    match symbol {
+        "+=" => Some("+"),
+        "-=" => Some("-"),
+        "*=" => Some("*"),
+        "/=" => Some("/"),
+        "%=" => Some("%"),
+        "<<=" => Some("<<"),
+        ">>=" => Some(">>"),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    constants: HashMap<String, i64>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.index + offset).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.index) {
+            Some((_, line)) => *line,
+            None => self.tokens.last().map(|(_, line)| *line).unwrap_or(1),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.index += 1;
+        token
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(found)) if *found == symbol)
+    }
+
+    fn is_name(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(word)) if word == name)
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            Some(Token::Number(num)) => num.to_string(),
+            Some(Token::Name(name)) => name.clone(),
+            Some(Token::Symbol(symbol)) => symbol.to_string(),
+            None => "the end of the file".to_string(),
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if !self.is_symbol(symbol) {
+            return Err(format!(
+                "Expected {} but found {} on line {}",
+                symbol,
+                self.describe(),
+                self.line()
+            ));
+        }
+        self.index += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.index += 1;
+                Ok(name)
+            }
+            _ => Err(format!(
+                "Expected a name but found {} on line {}",
+                self.describe(),
+                self.line()
+            )),
+        }
+    }
+
+    fn peek_type(&self) -> Option<Type> {
+        match self.peek() {
+            Some(Token::Name(name)) => Type::parse(name),
+            _ => None,
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<Type, String> {
+        match self.peek_type() {
+            Some(ty) => {
+                self.index += 1;
+                Ok(ty)
+            }
+            None => Err(format!(
+                "Expected a type but found {} on line {}",
+                self.describe(),
+                self.line()
+            )),
+        }
+    }
+
+    // A number or a constant, optionally negative
+    fn constant_value(&mut self) -> Result<i64, String> {
+        let negative = self.is_symbol("-");
+        if negative {
+            self.index += 1;
+        }
+        let line = self.line();
+        let value = match self.next() {
+            Some(Token::Number(num)) => num,
+            Some(Token::Name(name)) if self.constants.contains_key(&name) => self.constants[&name],
+            _ => return Err(format!("Expected a constant value on line {}", line)),
+        };
+        Ok(if negative { -value } else { value })
+    }
+
+    fn program(&mut self) -> Result<(Vec<Declaration>, Vec<Function>), String> {
+        let mut globals = Vec::new();
+        let mut functions = Vec::new();
+        while self.peek().is_some() {
+            let line = self.line();
+            if self.is_name("const") {
+                self.index += 1;
+                let name = self.name()?;
+                self.expect("=")?;
+                let value = self.constant_value()?;
+                self.expect(";")?;
+                if self.constants.insert(name.clone(), value).is_some() {
+                    return Err(format!(
+                        "Constant {} is already defined on line {}",
+                        name, line
+                    ));
+                }
+                continue;
+            }
+
+            let ret = match self.is_name("void") {
+                true => {
+                    self.index += 1;
+                    None
+                }
+                false => Some(self.parse_type()?),
+            };
+            if self.peek_at(1) == Some(&Token::Symbol("(")) {
+                functions.push(self.function(ret, line)?);
+                continue;
+            }
+            match ret {
+                Some(ty) => globals.push(self.declaration(ty, line)?),
+                None => return Err(format!("Variables can't be void on line {}", line)),
+            }
+        }
+        Ok((globals, functions))
+    }
+
+    fn function(&mut self, ret: Option<Type>, line: usize) -> Result<Function, String> {
+        let name = self.name()?;
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.is_symbol(")") {
+            if !params.is_empty() {
+                self.expect(",")?;
+            }
+            let ty = self.parse_type()?;
+            params.push((self.name()?, ty));
+        }
+        self.expect(")")?;
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            ret,
+            params,
+            body,
+            line,
+        })
+    }
+
+    // The type is already read, this is the name, array size and initializer
+    fn declaration(&mut self, ty: Type, line: usize) -> Result<Declaration, String> {
+        let name = self.name()?;
+        let mut count = None;
+        if self.is_symbol("[") {
+            self.index += 1;
+            let size = self.constant_value()?;
+            if !(1..=0x8000).contains(&size) {
+                return Err(format!(
+                    "Array {} can't have {} elements on line {}",
+                    name, size, line
+                ));
+            }
+            count = Some(size as u16);
+            self.expect("]")?;
+        }
+        let mut init = None;
+        if self.is_symbol("=") {
+            self.index += 1;
+            if count.is_some() {
+                return Err(format!(
+                    "Array {} can't have an initializer on line {}",
+                    name, line
+                ));
+            }
+            init = Some(self.expression(0)?);
+        }
+        self.expect(";")?;
+        Ok(Declaration {
+            name,
+            ty,
+            count,
+            init,
+            line,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, String> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.is_symbol("}") {
+            if self.peek().is_none() {
+                return Err(format!(
+                    "Missing }} at the end of the file on line {}",
+                    self.line()
+                ));
+            }
+            statements.push(self.statement()?);
+        }
+        self.expect("}")?;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        let line = self.line();
+        let kind = if let Some(ty) = self.peek_type() {
+            self.index += 1;
+            StatementKind::Declare(self.declaration(ty, line)?)
+        } else if self.is_name("if") {
+            self.index += 1;
+            self.expect("(")?;
+            let condition = self.expression(0)?;
+            self.expect(")")?;
+            let body = self.block()?;
+            let mut otherwise = Vec::new();
+            if self.is_name("else") {
+                self.index += 1;
+                otherwise = match self.is_name("if") {
+                    true => vec![self.statement()?],
+                    false => self.block()?,
+                };
+            }
+            StatementKind::If(condition, body, otherwise)
+        } else if self.is_name("while") {
+            self.index += 1;
+            self.expect("(")?;
+            let condition = self.expression(0)?;
+            self.expect(")")?;
+            StatementKind::While(condition, self.block()?)
+        } else if self.is_name("return") {
+            self.index += 1;
+            let value = match self.is_symbol(";") {
+                true => None,
+                false => Some(self.expression(0)?),
+            };
+            self.expect(";")?;
+            StatementKind::Return(value)
+        } else if self.is_name("break") || self.is_name("continue") {
+            let kind = match self.is_name("break") {
+                true => StatementKind::Break,
+                false => StatementKind::Continue,
+            };
+            self.index += 1;
+            self.expect(";")?;
+            kind
+        } else {
+            let target = self.expression(0)?;
+            let operator = match self.peek() {
+                Some(Token::Symbol(symbol)) => compound_operator(symbol),
+                _ => None,
+            };
+            let kind = if self.is_symbol("=") || operator.is_some() {
+                self.index += 1;
+                if !matches!(target, Expr::Var(..) | Expr::Index(..)) {
+                    return Err(format!(
+                        "Can only assign to a variable or array element on line {}",
+                        line
+                    ));
+                }
+                let value = self.expression(0)?;
+                let value = match operator {
+                    // x += 1 is x = x + 1, the target is evaluated twice
+                    Some(operator) => {
+                        Expr::Binary(operator, Box::new(target.clone()), Box::new(value))
+                    }
+                    None => value,
+                };
+                StatementKind::Assign(target, value)
+            } else if matches!(target, Expr::Call(..)) {
+                StatementKind::Call(target)
+            } else {
+                return Err(format!(
+                    "Only calls and assignments can be statements on line {}",
+                    line
+                ));
+            };
+            self.expect(";")?;
+            kind
+        };
+        Ok(Statement { line, kind })
+    }
+
+    // Precedence climbing, every operator is left associative
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Symbol(symbol)) = self.peek() {
+            let symbol = *symbol;
+            let precedence = match precedence(symbol) {
+                Some(precedence) if precedence > min_precedence => precedence,
+                _ => break,
+            };
+            self.index += 1;
+            let rhs = self.expression(precedence)?;
+            lhs = Expr::Binary(symbol, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.is_symbol("-") || self.is_symbol("!") {
+            let symbol = match self.next() {
+                Some(Token::Symbol(symbol)) => symbol,
+                _ => "-",
+            };
+            let operand = self.unary()?;
+            return Ok(match (symbol, operand) {
+                ("-", Expr::Number(num)) => Expr::Number(-num),
+                (symbol, operand) => Expr::Unary(symbol, Box::new(operand)),
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let line = self.line();
+        match self.peek().cloned() {
+            Some(Token::Number(num)) => {
+                self.index += 1;
+                Ok(Expr::Number(num))
+            }
+            Some(Token::Symbol("(")) => {
+                self.index += 1;
+                let expr = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if self.constants.contains_key(&name) => {
+                self.index += 1;
+                Ok(Expr::Number(self.constants[&name]))
+            }
+            Some(Token::Name(_)) => {
+                let name = self.name()?;
+                if self.is_symbol("(") {
+                    self.index += 1;
+                    let mut args = Vec::new();
+                    while !self.is_symbol(")") {
+                        if !args.is_empty() {
+                            self.expect(",")?;
+                        }
+                        args.push(self.expression(0)?);
+                    }
+                    self.expect(")")?;
+                    return Ok(Expr::Call(name, args, line));
+                }
+                if self.is_symbol("[") {
+                    self.index += 1;
+                    let index = self.expression(0)?;
+                    self.expect("]")?;
+                    return Ok(Expr::Index(name, Box::new(index), line));
+                }
+                Ok(Expr::Var(name, line))
+            }
+            _ => Err(format!(
+                "Expected an expression but found {} on line {}",
+                self.describe(),
+                line
+            )),
+        }
+    }
+}
+
+// Where a variable lives in RAM, the symbol is the name of its var directive
+struct Variable {
+    symbol: String,
+    ty: Type,
+    count: Option<u16>,
+}
+
+// Functions keep their parameters and locals in fixed RAM, so a function can't be running
+// twice at once and recursion is rejected. Every block gets its own scope of locals and each
+// local its own var, even when an earlier block used the same name
+struct Compiler<'a> {
+    source_lines: Vec<&'a str>,
+    code: Vec<String>,
+    vars: Vec<String>,
+    globals: HashMap<String, Variable>,
+    locals: Vec<HashMap<String, Variable>>,
+    // Var symbols of the locals in the current function
+    symbols: HashSet<String>,
+    functions: HashMap<String, Function>,
+    current: String,
+    labels: usize,
+    loops: Vec<(String, String)>,
+    line: usize,
+    // Registers each function saves around its calls
+    saves: HashMap<String, usize>,
+    calls: HashMap<String, Vec<(String, usize)>>,
+}
+
+impl Compiler<'_> {
+    fn emit(&mut self, text: String) {
+        self.code.push(format!("    {}", text));
+    }
+
+    fn place(&mut self, label: &str) {
+        self.code.push(format!("{}:", label));
+    }
+
+    fn new_label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!(".{}{}", kind, self.labels)
+    }
+
+    fn register(&self, register: usize) -> Result<usize, String> {
+        match register < REGISTERS {
+            true => Ok(register),
+            false => Err(format!(
+                "Expression needs more than {} registers, split it into smaller statements on line {}",
+                REGISTERS, self.line
+            )),
+        }
+    }
+
+    fn declare(&mut self, declaration: &Declaration, global: bool) -> Result<(), String> {
+        let name = &declaration.name;
+        let taken = match global {
+            true => self.globals.contains_key(name) || self.functions.contains_key(name),
+            false => self
+                .locals
+                .last()
+                .is_some_and(|scope| scope.contains_key(name)),
+        };
+        if taken || BUILTINS.contains(&name.as_str()) {
+            return Err(format!(
+                "{} is already defined on line {}",
+                name, declaration.line
+            ));
+        }
+        let mut symbol = match global {
+            true => name.clone(),
+            false => format!("{}.{}", self.current, name),
+        };
+        if !global {
+            let base = symbol.clone();
+            let mut count = 1;
+            while self.symbols.contains(&symbol) {
+                count += 1;
+                symbol = format!("{}.{}", base, count);
+            }
+            self.symbols.insert(symbol.clone());
+        }
+        let size = match declaration.count {
+            Some(count) => format!("[{}]", count as u32 * declaration.ty.size() as u32),
+            None => ["byte", "word"][declaration.ty.size() as usize - 1].to_string(),
+        };
+        self.vars.push(format!("var {} {}", symbol, size));
+        let variable = Variable {
+            symbol,
+            ty: declaration.ty,
+            count: declaration.count,
+        };
+        match global {
+            true => self.globals.insert(name.clone(), variable),
+            false => self
+                .locals
+                .last_mut()
+                .and_then(|scope| scope.insert(name.clone(), variable)),
+        };
+        Ok(())
+    }
+
+    fn variable(&self, name: &str, line: usize) -> Result<&Variable, String> {
+        let local = self.locals.iter().rev().find_map(|scope| scope.get(name));
+        match local.or_else(|| self.globals.get(name)) {
+            Some(variable) => Ok(variable),
+            None => Err(format!("Uknown variable {} on line {}", name, line)),
+        }
+    }
+
+    // The source line as a comment above the code it became
+    fn comment(&mut self, line: usize) {
+        self.line = line;
+        let text = self
+            .source_lines
+            .get(line - 1)
+            .map(|text| text.trim())
+            .unwrap_or("");
+        self.code.push(format!("    // {}: {}", line, text));
+    }
+
+    fn compile_function(&mut self, function: &Function) -> Result<(), String> {
+        self.current = function.name.clone();
+        // Parameters share the scope of the function body, like in C
+        self.locals = vec![HashMap::new()];
+        self.symbols.clear();
+        self.line = function.line;
+        for (name, ty) in function.params.iter() {
+            self.declare(
+                &Declaration {
+                    name: name.clone(),
+                    ty: *ty,
+                    count: None,
+                    init: None,
+                    line: function.line,
+                },
+                false,
+            )?;
+        }
+        self.code.push(String::new());
+        self.place(&function.name);
+        for statement in function.body.iter() {
+            self.statement(statement)?;
+        }
+        // Falling off the end returns 0
+        if falls_through(&function.body) {
+            if function.ret.is_some() {
+                self.emit("LOAD $0 #0".to_string());
+            }
+            self.emit("RET".to_string());
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, statements: &[Statement]) -> Result<(), String> {
+        self.locals.push(HashMap::new());
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        self.locals.pop();
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), String> {
+        self.comment(statement.line);
+        match &statement.kind {
+            StatementKind::Declare(declaration) => {
+                self.declare(declaration, false)?;
+                if let Some(init) = &declaration.init {
+                    self.assign(&Expr::Var(declaration.name.clone(), declaration.line), init)?;
+                }
+            }
+            StatementKind::Assign(target, value) => self.assign(target, value)?,
+            StatementKind::If(condition, body, otherwise) => {
+                let else_label = self.new_label("else");
+                let end_label = self.new_label("endif");
+                self.branch(condition, &else_label, false, 0)?;
+                self.block(body)?;
+                if !otherwise.is_empty() {
+                    self.emit(format!("JMP {}", end_label));
+                }
+                self.place(&else_label);
+                if !otherwise.is_empty() {
+                    self.block(otherwise)?;
+                    self.place(&end_label);
+                }
+            }
+            StatementKind::While(condition, body) => {
+                let top_label = self.new_label("while");
+                let end_label = self.new_label("wend");
+                self.place(&top_label);
+                self.branch(condition, &end_label, false, 0)?;
+                self.loops.push((top_label.clone(), end_label.clone()));
+                self.block(body)?;
+                self.loops.pop();
+                self.emit(format!("JMP {}", top_label));
+                if !loops_forever(condition, body) {
+                    self.place(&end_label);
+                }
+            }
+            StatementKind::Break | StatementKind::Continue => {
+                let target = match (self.loops.last(), &statement.kind) {
+                    (Some((_, end)), StatementKind::Break) => end.clone(),
+                    (Some((top, _)), _) => top.clone(),
+                    (None, _) => {
+                        return Err(format!(
+                            "break and continue have to be inside a while on line {}",
+                            statement.line
+                        ))
+                    }
+                };
+                self.emit(format!("JMP {}", target));
+            }
+            StatementKind::Return(value) => {
+                let ret = self
+                    .functions
+                    .get(&self.current)
+                    .and_then(|function| function.ret);
+                match (value, ret) {
+                    (Some(value), Some(ty)) => {
+                        self.expr(value, 0)?;
+                        self.extend(ty, 0)?;
+                    }
+                    (None, None) => (),
+                    (Some(_), None) => {
+                        return Err(format!(
+                            "{} is void and can't return a value on line {}",
+                            self.current, statement.line
+                        ))
+                    }
+                    (None, Some(_)) => {
+                        return Err(format!(
+                            "{} has to return a value on line {}",
+                            self.current, statement.line
+                        ))
+                    }
+                }
+                self.emit("RET".to_string());
+            }
+            StatementKind::Call(call) => {
+                if let Expr::Call(name, args, line) = call {
+                    self.call(name, args, *line, 0, false)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, target: &Expr, value: &Expr) -> Result<(), String> {
+        self.expr(value, 0)?;
+        match target {
+            Expr::Var(name, line) => {
+                let variable = self.variable(name, *line)?;
+                if variable.count.is_some() {
+                    return Err(format!(
+                        "{} is an array, assign to an element like {}[i] on line {}",
+                        name, name, line
+                    ));
+                }
+                let (symbol, ty) = (variable.symbol.clone(), variable.ty);
+                self.emit(format!("LOAD $7 {}", symbol));
+                self.store(ty, 0);
+            }
+            Expr::Index(name, index, line) => {
+                let ty = self.element_address(name, index, *line, 1)?;
+                self.store(ty, 0);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, ty: Type, register: usize) {
+        match ty.size() {
+            1 => self.emit(format!("STORE ${}a", register)),
+            _ => self.emit(format!("STORE ${}", register)),
+        }
+    }
+
+    // Reads from the address in $7, bytes are widened to a word
+    fn read(&mut self, ty: Type, register: usize) -> Result<(), String> {
+        match ty.size() {
+            1 => {
+                self.emit(format!("READ ${}a", register));
+                self.extend(ty, register)
+            }
+            _ => {
+                self.emit(format!("READ ${}", register));
+                Ok(())
+            }
+        }
+    }
+
+    // Fills the upper byte of a byte value, with the sign for i8
+    fn extend(&mut self, ty: Type, register: usize) -> Result<(), String> {
+        if ty.size() == 2 {
+            return Ok(());
+        }
+        self.emit(format!("LOAD ${}b #0", register));
+        if ty.signed() {
+            let scratch = self.register(register + 1)?;
+            let label = self.new_label("sign");
+            self.emit(format!("LOAD ${}a #127", scratch));
+            self.emit(format!("LES ${}a ${}a", scratch, register));
+            self.emit(format!("NJMP {}", label));
+            self.emit(format!("LOAD ${}b #255", register));
+            self.place(&label);
+        }
+        Ok(())
+    }
+
+    // Leaves the address of an array element in $7, clobbering registers from the one given
+    fn element_address(
+        &mut self,
+        name: &str,
+        index: &Expr,
+        line: usize,
+        register: usize,
+    ) -> Result<Type, String> {
+        let variable = self.variable(name, line)?;
+        if variable.count.is_none() {
+            return Err(format!("{} is not an array on line {}", name, line));
+        }
+        let (symbol, ty) = (variable.symbol.clone(), variable.ty);
+        let register = self.register(register)?;
+        self.expr(index, register)?;
+        let scratch = self.register(register + 1)?;
+        if ty.size() == 2 {
+            self.emit(format!("LOAD ${} #1", scratch));
+            self.emit(format!("SHL ${} ${} ${}", register, scratch, register));
+        }
+        self.emit(format!("LOAD ${} {}", scratch, symbol));
+        self.emit(format!("ADD ${} ${} ${}", register, scratch, register));
+        self.emit(format!("MOV ${} $7", register));
+        Ok(ty)
+    }
+
+    // Evaluates into a word register, registers above it are free to use. Returns whether
+    // the value is signed
+    fn expr(&mut self, expr: &Expr, register: usize) -> Result<bool, String> {
+        let register = self.register(register)?;
+        match expr {
+            Expr::Number(num) => {
+                if !(-0x8000..=0xFFFF).contains(num) {
+                    return Err(format!(
+                        "{} doesn't fit in 16 bits on line {}",
+                        num, self.line
+                    ));
+                }
+                self.emit(format!("LOAD ${} #{}", register, num));
+                Ok(*num < 0)
+            }
+            Expr::Var(name, line) => {
+                let variable = self.variable(name, *line)?;
+                if variable.count.is_some() {
+                    return Err(format!(
+                        "{} is an array, index it like {}[i] on line {}",
+                        name, name, line
+                    ));
+                }
+                let (symbol, ty) = (variable.symbol.clone(), variable.ty);
+                self.emit(format!("LOAD $7 {}", symbol));
+                self.read(ty, register)?;
+                Ok(ty.signed())
+            }
+            Expr::Index(name, index, line) => {
+                let ty = self.element_address(name, index, *line, register)?;
+                self.read(ty, register)?;
+                Ok(ty.signed())
+            }
+            Expr::Call(name, args, line) => self.call(name, args, *line, register, true),
+            Expr::Unary("-", operand) => {
+                self.expr(operand, register)?;
+                self.emit(format!("NEG ${}", register));
+                Ok(true)
+            }
+            Expr::Binary(symbol, lhs, rhs) if precedence(symbol).unwrap_or(0) >= 5 => {
+                let lhs_signed = self.expr(lhs, register)?;
+                let scratch = self.register(register + 1)?;
+                let rhs_signed = self.expr(rhs, scratch)?;
+                let signed = lhs_signed || rhs_signed;
+                let mnemonic = match *symbol {
+                    "+" => "ADD",
+                    "-" => "SUB",
+                    "*" => "MUL",
+                    "/" => "DIV",
+                    "%" => "MOD",
+                    "<<" => "SHL",
+                    _ => "SHR",
+                };
+                let suffix = match signed && !mnemonic.starts_with("SH") {
+                    true => "i",
+                    false => "",
+                };
+                self.emit(format!(
+                    "{}{} ${} ${} ${}",
+                    mnemonic, suffix, register, scratch, register
+                ));
+                Ok(signed)
+            }
+            // Comparisons and logic become 0 or 1
+            _ => {
+                let false_label = self.new_label("false");
+                let end_label = self.new_label("bool");
+                self.branch(expr, &false_label, false, register)?;
+                self.emit(format!("LOAD ${} #1", register));
+                self.emit(format!("JMP {}", end_label));
+                self.place(&false_label);
+                self.emit(format!("LOAD ${} #0", register));
+                self.place(&end_label);
+                Ok(false)
+            }
+        }
+    }
+
+    // Jumps to the target when the condition is the same as jump_if
+    fn branch(
+        &mut self,
+        expr: &Expr,
+        target: &str,
+        jump_if: bool,
+        register: usize,
+    ) -> Result<(), String> {
+        match expr {
+            Expr::Binary("&&", lhs, rhs) | Expr::Binary("||", lhs, rhs) => {
+                // a && b jumps on false as soon as one side is false, || is the mirror image
+                let short_circuit = matches!(expr, Expr::Binary("||", ..));
+                if jump_if == short_circuit {
+                    self.branch(lhs, target, jump_if, register)?;
+                    self.branch(rhs, target, jump_if, register)?;
+                } else {
+                    let skip = self.new_label("skip");
+                    self.branch(lhs, &skip, short_circuit, register)?;
+                    self.branch(rhs, target, jump_if, register)?;
+                    self.place(&skip);
+                }
+            }
+            Expr::Unary("!", operand) => self.branch(operand, target, !jump_if, register)?,
+            Expr::Number(num) => {
+                if (*num != 0) == jump_if {
+                    self.emit(format!("JMP {}", target));
+                }
+            }
+            Expr::Binary(symbol, lhs, rhs)
+                if precedence(symbol) == Some(3) || precedence(symbol) == Some(4) =>
+            {
+                let lhs_signed = self.expr(lhs, register)?;
+                let scratch = self.register(register + 1)?;
+                let rhs_signed = self.expr(rhs, scratch)?;
+                let les = match lhs_signed || rhs_signed {
+                    true => "LESi",
+                    false => "LES",
+                };
+                let (a, b) = (register, scratch);
+                // The compare flag either means the condition or its opposite
+                let (instruction, flag_is_condition) = match *symbol {
+                    "<" => (format!("{} ${} ${}", les, a, b), true),
+                    ">" => (format!("{} ${} ${}", les, b, a), true),
+                    "<=" => (format!("{} ${} ${}", les, b, a), false),
+                    ">=" => (format!("{} ${} ${}", les, a, b), false),
+                    "!=" => (format!("NEQ ${} ${}", a, b), true),
+                    _ => (format!("NEQ ${} ${}", a, b), false),
+                };
+                self.emit(instruction);
+                self.jump(flag_is_condition == jump_if, target);
+            }
+            _ => {
+                self.expr(expr, register)?;
+                let scratch = self.register(register + 1)?;
+                self.emit(format!("LOAD ${} #0", scratch));
+                self.emit(format!("NEQ ${} ${}", register, scratch));
+                self.jump(jump_if, target);
+            }
+        }
+        Ok(())
+    }
+
+    fn jump(&mut self, on_flag: bool, target: &str) {
+        match on_flag {
+            true => self.emit(format!("CJMP {}", target)),
+            false => self.emit(format!("NJMP {}", target)),
+        }
+    }
+
+    fn expect_args(
+        &self,
+        name: &str,
+        args: &[Expr],
+        count: usize,
+        line: usize,
+    ) -> Result<(), String> {
+        match args.len() == count {
+            true => Ok(()),
+            false => Err(format!(
+                "{} takes {} arguments but got {} on line {}",
+                name,
+                count,
+                args.len(),
+                line
+            )),
+        }
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        line: usize,
+        register: usize,
+        want_value: bool,
+    ) -> Result<bool, String> {
+        if let Some((_, address)) = INPUTS.iter().find(|(input, _)| *input == name) {
+            self.expect_args(name, args, 0, line)?;
+            self.emit(format!("LOAD $7 0x{:04X}", address));
+            self.read(Type::U8, register)?;
+            return Ok(false);
+        }
+        let no_value =
+            |name: &str| Err(format!("{} doesn't return a value on line {}", name, line));
+        match name {
+            "draw" => {
+                self.expect_args(name, args, 0, line)?;
+                if want_value {
+                    return no_value(name);
+                }
+                self.emit("DRAW".to_string());
+                return Ok(false);
+            }
+            "peek" => {
+                self.expect_args(name, args, 1, line)?;
+                self.expr(&args[0], register)?;
+                self.emit(format!("MOV ${} $7", register));
+                self.read(Type::U8, register)?;
+                return Ok(false);
+            }
+            "poke" => {
+                self.expect_args(name, args, 2, line)?;
+                if want_value {
+                    return no_value(name);
+                }
+                self.expr(&args[1], register)?;
+                let address = self.register(register + 1)?;
+                self.expr(&args[0], address)?;
+                self.emit(format!("MOV ${} $7", address));
+                self.store(Type::U8, register);
+                return Ok(false);
+            }
+            "pixel" => {
+                self.expect_args(name, args, 5, line)?;
+                if want_value {
+                    return no_value(name);
+                }
+                return self.pixel(args, register).map(|_| false);
+            }
+            _ => (),
+        }
+
+        let function = match self.functions.get(name) {
+            Some(function) => function,
+            None => return Err(format!("Uknown function {} on line {}", name, line)),
+        };
+        let params = function.params.clone();
+        let ret = function.ret;
+        self.expect_args(name, args, params.len(), line)?;
+        if want_value && ret.is_none() {
+            return no_value(name);
+        }
+        self.calls
+            .entry(self.current.clone())
+            .or_default()
+            .push((name.to_string(), line));
+
+        // Every argument is evaluated before any is stored, an argument can call the same function
+        for (offset, arg) in args.iter().enumerate() {
+            let arg_register = self.register(register + offset)?;
+            self.expr(arg, arg_register)?;
+        }
+        for (offset, (param, ty)) in params.iter().enumerate() {
+            self.emit(format!("LOAD $7 {}.{}", name, param));
+            self.store(*ty, register + offset);
+        }
+        let saves = self.saves.entry(self.current.clone()).or_insert(0);
+        *saves = (*saves).max(register);
+        for saved in 0..register {
+            self.emit(format!("LOAD $7 {}.${}", self.current, saved));
+            self.emit(format!("STORE ${}", saved));
+        }
+        self.emit(format!("CALL {}", name));
+        if register != 0 && want_value {
+            self.emit(format!("MOV $0 ${}", register));
+        }
+        for saved in 0..register {
+            self.emit(format!("LOAD $7 {}.${}", self.current, saved));
+            self.emit(format!("READ ${}", saved));
+        }
+        Ok(ret.map(|ty| ty.signed()).unwrap_or(false))
+    }
+
+    // pixel(x, y, red, green, blue) writes the three bytes of a tile
+    fn pixel(&mut self, args: &[Expr], register: usize) -> Result<(), String> {
+        for (offset, arg) in args.iter().enumerate() {
+            let arg_register = self.register(register + offset)?;
+            self.expr(arg, arg_register)?;
+        }
+        let (x, y) = (register, register + 1);
+        let scratch = self.register(register + args.len())?;
+        self.emit(format!("LOAD ${} #{}", scratch, SCREEN_HEIGHT));
+        self.emit(format!("MUL ${} ${} ${}", x, scratch, x));
+        self.emit(format!("ADD ${} ${} ${}", x, y, x));
+        self.emit(format!("LOAD ${} #3", scratch));
+        self.emit(format!("MUL ${} ${} ${}", x, scratch, x));
+        self.emit(format!("LOAD ${} 0x{:04X}", scratch, VRAM_START));
+        self.emit(format!("ADD ${} ${} $7", x, scratch));
+        self.emit(format!("LOAD ${} #1", scratch));
+        for channel in 2..5 {
+            if channel > 2 {
+                self.emit(format!("ADD $7 ${} $7", scratch));
+            }
+            self.store(Type::U8, register + channel);
+        }
+        Ok(())
+    }
+
+    fn check_recursion(&self) -> Result<(), String> {
+        let mut done = HashSet::new();
+        let mut names: Vec<&String> = self.calls.keys().collect();
+        names.sort();
+        for name in names {
+            let mut path = Vec::new();
+            self.visit(name, &mut path, &mut done)?;
+        }
+        Ok(())
+    }
+
+    fn visit(
+        &self,
+        name: &str,
+        path: &mut Vec<String>,
+        done: &mut HashSet<String>,
+    ) -> Result<(), String> {
+        if done.contains(name) {
+            return Ok(());
+        }
+        path.push(name.to_string());
+        for (callee, line) in self
+            .calls
+            .get(name)
+            .map(|calls| calls.as_slice())
+            .unwrap_or(&[])
+        {
+            if let Some(start) = path.iter().position(|caller| caller == callee) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(callee.clone());
+                return Err(format!(
+                    "Recursive call {}, functions keep their variables in fixed RAM on line {}",
+                    cycle.join(" -> "),
+                    line
+                ));
+            }
+            self.visit(callee, path, done)?;
+        }
+        path.pop();
+        done.insert(name.to_string());
+        Ok(())
+    }
+}
+
+// Whether running the statements can reach the code after them
+fn falls_through(statements: &[Statement]) -> bool {
+    match statements.last().map(|statement| &statement.kind) {
+        Some(StatementKind::Return(_)) => false,
+        Some(StatementKind::While(condition, body)) => !loops_forever(condition, body),
+        Some(StatementKind::If(_, body, otherwise)) if !otherwise.is_empty() => {
+            falls_through(body) || falls_through(otherwise)
+        }
+        _ => true,
+    }
+}
+
+fn loops_forever(condition: &Expr, body: &[Statement]) -> bool {
+    matches!(condition, Expr::Number(num) if *num != 0) && !breaks(body)
+}
+
+// A break in a nested while only leaves that loop
+fn breaks(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Break => true,
+        StatementKind::If(_, body, otherwise) => breaks(body) || breaks(otherwise),
+        _ => false,
+    })
+}
+
+fn returns(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::If(_, body, otherwise) => returns(body) || returns(otherwise),
+        StatementKind::While(_, body) => returns(body),
+        _ => false,
+    })
+}
+
+// Compiles a program to assembly source, the first error stops it
+pub fn compile(source: &str, path: &str) -> Result<String, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        index: 0,
+        constants: HashMap::new(),
+    };
+    let (globals, functions) = parser.program()?;
+
+    let mut compiler = Compiler {
+        source_lines: source.lines().collect(),
+        code: Vec::new(),
+        vars: Vec::new(),
+        globals: HashMap::new(),
+        locals: Vec::new(),
+        symbols: HashSet::new(),
+        functions: HashMap::new(),
+        current: "start".to_string(),
+        labels: 0,
+        loops: Vec::new(),
+        line: 1,
+        saves: HashMap::new(),
+        calls: HashMap::new(),
+    };
+    for function in functions.iter() {
+        if compiler.functions.contains_key(&function.name)
+            || BUILTINS.contains(&function.name.as_str())
+        {
+            return Err(format!(
+                "{} is already defined on line {}",
+                function.name, function.line
+            ));
+        }
+        compiler
+            .functions
+            .insert(function.name.clone(), function.clone());
+    }
+    match compiler.functions.get("main") {
+        Some(main) if main.params.is_empty() => (),
+        Some(main) => return Err(format!("main can't take arguments on line {}", main.line)),
+        None => return Err("The program needs a main function on line 1".to_string()),
+    }
+
+    // Globals are set up in order before main runs
+    for global in globals.iter() {
+        compiler.declare(global, true)?;
+        if let Some(init) = &global.init {
+            compiler.comment(global.line);
+            compiler.assign(&Expr::Var(global.name.clone(), global.line), init)?;
+        }
+    }
+    // A main that never returns is jumped to, otherwise the program halts with the value main
+    // returns as its exit code, or 0 without a return type
+    let main = &compiler.functions["main"];
+    let has_result = main.ret.is_some();
+    match falls_through(&main.body) || returns(&main.body) {
+        true => {
+            compiler.emit("CALL main".to_string());
+            if !has_result {
+                compiler.emit("CLR $0".to_string());
+            }
+            compiler.emit("HALT $0".to_string());
+        }
+        false => compiler.emit("JMP main".to_string()),
+    }
+    for function in functions.iter() {
+        compiler.compile_function(function)?;
+    }
+    compiler.check_recursion()?;
+
+    let mut saves: Vec<(&String, &usize)> = compiler.saves.iter().collect();
+    saves.sort();
+    for (function, count) in saves {
+        for saved in 0..*count {
+            compiler
+                .vars
+                .push(format!("var {}.${} word", function, saved));
+        }
+    }
+
+    let mut output = vec![format!("// Compiled from {}", path)];
+    output.append(&mut compiler.vars);
+    output.push(String::new());
+    output.append(&mut compiler.code);
+    output.push(String::new());
+    Ok(output.join("\n"))
+}
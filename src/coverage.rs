@@ -0,0 +1,107 @@
+use std::{collections::BTreeMap, fs::File, io::Write};
+
+use crate::assembler::DebugInfo;
+
+pub struct Coverage {
+    hits: Vec<u64>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            hits: vec![0; 65536],
+        }
+    }
+
+    pub fn record(&mut self, address: u16) {
+        self.hits[address as usize] += 1;
+    }
+
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits[address as usize]
+    }
+
+    // Hit count of every source line that assembled into at least one instruction,
+    // a line is counted by its first instruction
+    pub fn line_hits(&self, debug_info: &DebugInfo) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        for (address, line) in debug_info.lines.iter() {
+            lines.entry(*line).or_insert(self.hits(*address));
+        }
+        lines
+    }
+
+    pub fn summary(&self, debug_info: &DebugInfo) -> String {
+        let lines = self.line_hits(debug_info);
+        let hit = lines.values().filter(|hits| **hits > 0).count();
+        let percent = match lines.len() {
+            0 => 0.0,
+            total => hit as f64 * 100.0 / total as f64,
+        };
+        format!(
+            "Coverage : {} of {} instruction lines executed ({:.2}%)\n",
+            hit,
+            lines.len(),
+            percent
+        )
+    }
+
+    // gcov style listing, "-" marks lines without instructions and "#####" lines that never ran
+    pub fn listing(&self, debug_info: &DebugInfo) -> String {
+        let lines = self.line_hits(debug_info);
+        let mut listing = String::new();
+        for (index, source_line) in debug_info.source.lines().enumerate() {
+            let count = match lines.get(&(index + 1)) {
+                Some(0) => "#####".to_string(),
+                Some(hits) => hits.to_string(),
+                None => "-".to_string(),
+            };
+            listing.push_str(format!("{:>9}:{:>5}: {}\n", count, index + 1, source_line).as_str());
+        }
+        listing
+    }
+
+    pub fn lcov(&self, debug_info: &DebugInfo) -> String {
+        let mut lcov = format!("TN:\nSF:{}\n", debug_info.source_path);
+
+        let mut labels: Vec<(&String, &u16)> = debug_info.labels.iter().collect();
+        labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+        for (name, _) in labels.iter() {
+            if let Some(line) = debug_info.label_lines.get(*name) {
+                lcov.push_str(format!("FN:{},{}\n", line, name).as_str());
+            }
+        }
+        for (name, address) in labels.iter() {
+            lcov.push_str(format!("FNDA:{},{}\n", self.hits(**address), name).as_str());
+        }
+        let labels_hit = labels
+            .iter()
+            .filter(|(_, address)| self.hits(**address) > 0)
+            .count();
+        lcov.push_str(format!("FNF:{}\nFNH:{}\n", labels.len(), labels_hit).as_str());
+
+        let lines = self.line_hits(debug_info);
+        for (line, hits) in lines.iter() {
+            lcov.push_str(format!("DA:{},{}\n", line, hits).as_str());
+        }
+        let lines_hit = lines.values().filter(|hits| **hits > 0).count();
+        lcov.push_str(format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), lines_hit).as_str());
+
+        lcov
+    }
+}
+
+pub fn write_report(path: &str, report: &str) -> Result<(), String> {
+    let mut file = match File::create(path) {
+        Ok(file) => file,
+        Err(error) => return Err(format!("Error creating coverage file {} \n{}", path, error)),
+    };
+    file.write_all(report.as_bytes())
+        .map_err(|e| format!("Error writing coverage file {} \n{}", path, e))
+}
@@ -0,0 +1,643 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use crate::{
+    assembler::DebugInfo,
+    debugger::{Debugger, Stop, StopReason},
+    expr::Expr,
+    history::{History, HistoryMode},
+    json::{read_message, write_message, Json},
+    rusty_boi::{Halt, RustyBoi},
+    vm_main::CYCLES_PER_FRAME,
+};
+
+const THREAD_ID: i64 = 1;
+
+// Instructions kept for stepBack and reverseContinue
+const STEP_BACK_CAPACITY: usize = 100_000;
+
+// A step that never reaches another line gives up after this many instructions
+const MAX_STEP_INSTRUCTIONS: usize = 1_000_000;
+
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+const RAM_REFERENCE: i64 = 3;
+
+// Constants below the graphics RAM are shown as RAM variables
+const GRAPHICS_START: u16 = 0x87FF;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StepKind {
+    Instruction,
+    In,
+    Over,
+    Out,
+}
+
+// Serves the Debug Adapter Protocol on stdio, or on a loopback port when one is given
+pub fn serve(
+    rusty_boi: &mut RustyBoi,
+    debug_info: DebugInfo,
+    port: Option<u16>,
+) -> Result<(), String> {
+    let (reader, writer): (Box<dyn BufRead + Send>, Box<dyn Write>) = match port {
+        Some(port) => {
+            let listener = match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => listener,
+                Err(error) => return Err(format!("Error listening on port {} \n{}", port, error)),
+            };
+            println!("Waiting for a debug adapter client on 127.0.0.1:{}", port);
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(error) => return Err(format!("Error accepting DAP connection \n{}", error)),
+            };
+            let reader = stream.try_clone().map_err(|e| e.to_string())?;
+            (Box::new(BufReader::new(reader)), Box::new(stream))
+        }
+        None => (
+            Box::new(BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        ),
+    };
+
+    if rusty_boi.debugger().is_none() {
+        rusty_boi.set_debugger(Debugger::new(debug_info));
+    }
+    rusty_boi.set_history(History::new(HistoryMode::Instruction, STEP_BACK_CAPACITY));
+    rusty_boi.set_cycle_budget(Some(CYCLES_PER_FRAME));
+
+    // Requests are read on their own thread so a running program can still be paused
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = reader;
+        // Reading stops when the stream ends or breaks, a bad message is passed on to be reported
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = DapSession {
+        rusty_boi,
+        writer,
+        seq: 1,
+        running: false,
+        launched: false,
+        configured: false,
+        stop_on_entry: false,
+    };
+    session.run(receiver)
+}
+
+struct DapSession<'a> {
+    rusty_boi: &'a mut RustyBoi,
+    writer: Box<dyn Write>,
+    seq: i64,
+    running: bool,
+    launched: bool,
+    configured: bool,
+    stop_on_entry: bool,
+}
+
+impl DapSession<'_> {
+    fn run(&mut self, receiver: Receiver<Result<Json, String>>) -> Result<(), String> {
+        loop {
+            let message = match self.running {
+                true => match receiver.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                },
+                false => match receiver.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return Ok(()),
+                },
+            };
+
+            match message {
+                Some(Ok(message)) => {
+                    if !self.handle(&message)? {
+                        return Ok(());
+                    }
+                }
+                Some(Err(error)) => self.reject(error)?,
+                None => self.run_frame()?,
+            }
+        }
+    }
+
+    fn debug_info(&self) -> &DebugInfo {
+        &self.rusty_boi.debugger().unwrap().debug_info
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> Result<(), String> {
+        fields.insert(0, ("seq", Json::from(self.seq)));
+        self.seq += 1;
+        write_message(&mut self.writer, &Json::object(fields))
+    }
+
+    fn send_event(&mut self, event: &str, body: Json) -> Result<(), String> {
+        self.send(vec![
+            ("type", Json::from("event")),
+            ("event", Json::from(event)),
+            ("body", body),
+        ])
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> Result<(), String> {
+        let mut fields = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").clone()),
+            ("command", request.get("command").clone()),
+        ];
+        match result {
+            Ok(body) => {
+                fields.push(("success", Json::from(true)));
+                fields.push(("body", body));
+            }
+            Err(error) => {
+                fields.push(("success", Json::from(false)));
+                fields.push(("message", Json::from(error)));
+            }
+        }
+        self.send(fields)
+    }
+
+    // A message that couldn't be read has no request to answer, seq 0 is never used by a client
+    fn reject(&mut self, error: String) -> Result<(), String> {
+        self.send(vec![
+            ("type", Json::from("response")),
+            ("request_seq", Json::from(0i64)),
+            ("command", Json::from("")),
+            ("success", Json::from(false)),
+            ("message", Json::from(error)),
+        ])
+    }
+
+    fn send_stopped(&mut self, reason: &str, description: Option<String>) -> Result<(), String> {
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+        if let Some(description) = description {
+            self.send_event(
+                "output",
+                Json::object(vec![
+                    ("category", Json::from("console")),
+                    ("output", Json::from(format!("{}\n", description))),
+                ]),
+            )?;
+            body.push(("description", Json::from(description)));
+        }
+        self.send_event("stopped", Json::object(body))
+    }
+
+    fn send_stop(&mut self, stop: &Stop) -> Result<(), String> {
+        let description = self
+            .rusty_boi
+            .debugger()
+            .map(|debugger| debugger.report(stop, self.rusty_boi.prog_mem()));
+        let reason = match stop.reason {
+            StopReason::Breakpoint(_) | StopReason::ConditionError(_, _) => "breakpoint",
+            StopReason::Watchpoint(_, _) => "data breakpoint",
+        };
+        self.send_stopped(reason, description)
+    }
+
+    fn send_terminated(&mut self) -> Result<(), String> {
+        self.running = false;
+        if let Some(halt) = self.rusty_boi.halted() {
+            self.send_event(
+                "output",
+                Json::object(vec![
+                    ("category", Json::from("console")),
+                    ("output", Json::from(format!("{}\n", halt))),
+                ]),
+            )?;
+            // Running past the end has no code of its own, it's reported as a failure
+            let exit_code = match halt {
+                Halt::Exit(code) => code as i64,
+                Halt::PastEnd(_) => -1,
+            };
+            self.send_event(
+                "exited",
+                Json::object(vec![("exitCode", Json::from(exit_code))]),
+            )?;
+        }
+        self.send_event("terminated", Json::object(vec![]))
+    }
+
+    fn run_frame(&mut self) -> Result<(), String> {
+        if self.rusty_boi.halted().is_some() {
+            return self.send_terminated();
+        }
+        self.rusty_boi.run();
+        if let Some(stop) = self.rusty_boi.take_stop() {
+            self.running = false;
+            self.send_stop(&stop)?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        if !self.launched || !self.configured {
+            return Ok(());
+        }
+        match self.stop_on_entry {
+            true => self.send_stopped("entry", None),
+            false => {
+                self.running = true;
+                Ok(())
+            }
+        }
+    }
+
+    // Returns false once the client disconnects
+    fn handle(&mut self, request: &Json) -> Result<bool, String> {
+        if request.get("type").as_str() != Some("request") {
+            return Ok(true);
+        }
+        let command = request.get("command").as_str().unwrap_or("").to_string();
+        let args = request.get("arguments");
+
+        match command.as_str() {
+            "initialize" => {
+                let result = Ok(self.capabilities());
+                self.respond(request, result)?;
+                self.send_event("initialized", Json::object(vec![]))?;
+            }
+            "launch" | "attach" => {
+                self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+                self.launched = true;
+                self.respond(request, Ok(Json::object(vec![])))?;
+                self.start()?;
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(request, Ok(Json::object(vec![])))?;
+                self.start()?;
+            }
+            "setBreakpoints" => {
+                let result = self.set_breakpoints(args);
+                self.respond(request, result)?;
+            }
+            "setExceptionBreakpoints" => {
+                let body = Json::object(vec![("breakpoints", Json::Array(Vec::new()))]);
+                self.respond(request, Ok(body))?;
+            }
+            "threads" => {
+                let thread = Json::object(vec![
+                    ("id", Json::from(THREAD_ID)),
+                    ("name", Json::from("Rusty Boi")),
+                ]);
+                let body = Json::object(vec![("threads", Json::Array(vec![thread]))]);
+                self.respond(request, Ok(body))?;
+            }
+            "continue" => {
+                self.running = true;
+                let body = Json::object(vec![("allThreadsContinued", Json::from(true))]);
+                self.respond(request, Ok(body))?;
+            }
+            "pause" => {
+                self.respond(request, Ok(Json::object(vec![])))?;
+                self.running = false;
+                self.send_stopped("pause", None)?;
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let kind = match (command.as_str(), args.get("granularity").as_str()) {
+                    (_, Some("instruction")) => StepKind::Instruction,
+                    ("next", _) => StepKind::Over,
+                    ("stepIn", _) => StepKind::In,
+                    _ => StepKind::Out,
+                };
+                self.respond(request, Ok(Json::object(vec![])))?;
+                self.running = false;
+                self.step(kind)?;
+            }
+            "stepBack" => {
+                let granularity = args.get("granularity").as_str();
+                let result = self.step_back(granularity == Some("instruction"));
+                self.respond(request, result)?;
+                self.send_stopped("step", None)?;
+            }
+            "reverseContinue" => {
+                let result = self.reverse_continue();
+                self.respond(request, result)?;
+                self.send_stopped("breakpoint", None)?;
+            }
+            "stackTrace" => {
+                let result = Ok(self.stack_trace());
+                self.respond(request, result)?;
+            }
+            "scopes" => {
+                let result = Ok(scopes());
+                self.respond(request, result)?;
+            }
+            "variables" => {
+                let reference = args.get("variablesReference").as_i64().unwrap_or(0);
+                let result = Ok(self.variables(reference));
+                self.respond(request, result)?;
+            }
+            "evaluate" => {
+                let expression = args.get("expression").as_str().unwrap_or("");
+                let result = self.evaluate(expression);
+                self.respond(request, result)?;
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::object(vec![])))?;
+                return Ok(false);
+            }
+            _ => {
+                let error = format!("Unsupported request {}", command);
+                self.respond(request, Err(error))?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn capabilities(&self) -> Json {
+        Json::object(vec![
+            ("supportsConfigurationDoneRequest", Json::from(true)),
+            ("supportsConditionalBreakpoints", Json::from(true)),
+            ("supportsEvaluateForHovers", Json::from(true)),
+            ("supportsStepBack", Json::from(true)),
+            ("supportsSteppingGranularity", Json::from(true)),
+            ("supportsTerminateRequest", Json::from(true)),
+        ])
+    }
+
+    fn source(&self) -> Json {
+        let path = &self.debug_info().source_path;
+        let full_path = fs::canonicalize(path)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.clone());
+        let name = full_path.rsplit('/').next().unwrap_or("").to_string();
+        Json::object(vec![
+            ("name", Json::from(name)),
+            ("path", Json::from(full_path)),
+        ])
+    }
+
+    fn same_source(&self, path: &str) -> bool {
+        let ours = fs::canonicalize(&self.debug_info().source_path);
+        match (ours, fs::canonicalize(path)) {
+            (Ok(ours), Ok(theirs)) => ours == theirs,
+            _ => false,
+        }
+    }
+
+    // Breakpoints on lines without an instruction move down to the next instruction
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("source").get("path").as_str().unwrap_or("");
+        let same_source = self.same_source(path);
+        let lines: Vec<(u16, usize)> = self.debug_info().lines.clone();
+
+        let debugger = self.rusty_boi.debugger_mut().unwrap();
+        if same_source {
+            debugger.breakpoints.clear();
+        }
+
+        let mut results = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let line = breakpoint.get("line").as_i64().unwrap_or(0) as usize;
+            let target = lines
+                .iter()
+                .filter(|(_, source_line)| *source_line >= line)
+                .min_by_key(|(_, source_line)| *source_line)
+                .copied();
+            let result = match (same_source, target) {
+                (true, Some((address, source_line))) => {
+                    let text = match breakpoint.get("condition").as_str() {
+                        Some(condition) if !condition.trim().is_empty() => {
+                            format!("0x{:04X} if {}", address, condition)
+                        }
+                        _ => format!("0x{:04X}", address),
+                    };
+                    match debugger.add_breakpoint(text.as_str()) {
+                        Ok(()) => Json::object(vec![
+                            ("verified", Json::from(true)),
+                            ("line", Json::from(source_line)),
+                        ]),
+                        Err(error) => Json::object(vec![
+                            ("verified", Json::from(false)),
+                            ("message", Json::from(error)),
+                        ]),
+                    }
+                }
+                (true, None) => Json::object(vec![
+                    ("verified", Json::from(false)),
+                    (
+                        "message",
+                        Json::from("No instruction on or after this line"),
+                    ),
+                ]),
+                (false, _) => Json::object(vec![
+                    ("verified", Json::from(false)),
+                    ("message", Json::from("Not part of the running program")),
+                ]),
+            };
+            results.push(result);
+        }
+        Ok(Json::object(vec![("breakpoints", Json::Array(results))]))
+    }
+
+    fn step(&mut self, kind: StepKind) -> Result<(), String> {
+        let start_pc = self.rusty_boi.pc();
+        let start_line = self.debug_info().line_of(start_pc);
+        let depth = self.rusty_boi.ret_stack().len();
+
+        for count in 0..MAX_STEP_INSTRUCTIONS {
+            if self.rusty_boi.halted().is_some() {
+                return self.send_terminated();
+            }
+            // Breakpoints inside routines that are stepped over still stop
+            if count > 0 && self.rusty_boi.check_breakpoints() {
+                let stop = self.rusty_boi.take_stop().unwrap();
+                return self.send_stop(&stop);
+            }
+            self.rusty_boi.step();
+            if let Some(stop) = self.rusty_boi.take_stop() {
+                return self.send_stop(&stop);
+            }
+
+            let pc = self.rusty_boi.pc();
+            let new_depth = self.rusty_boi.ret_stack().len();
+            let line = self.debug_info().line_of(pc);
+            let new_line = line.is_some() && (line != start_line || pc == start_pc);
+            let done = match kind {
+                StepKind::Instruction => true,
+                StepKind::In => new_line || new_depth != depth,
+                StepKind::Over => new_depth <= depth && new_line,
+                StepKind::Out => new_depth < depth,
+            };
+            if done {
+                break;
+            }
+        }
+        self.send_stopped("step", None)
+    }
+
+    // Stepping back over a line also steps back over any routine it returned from
+    fn step_back(&mut self, instruction: bool) -> Result<Json, String> {
+        let depth = self.rusty_boi.ret_stack().len();
+        loop {
+            self.rusty_boi.step_back()?;
+            if instruction || self.rusty_boi.ret_stack().len() <= depth {
+                return Ok(Json::object(vec![]));
+            }
+        }
+    }
+
+    fn reverse_continue(&mut self) -> Result<Json, String> {
+        loop {
+            if self.rusty_boi.step_back().is_err() {
+                return Ok(Json::object(vec![]));
+            }
+            let pc = self.rusty_boi.pc();
+            let debugger = self.rusty_boi.debugger().unwrap();
+            if debugger
+                .breakpoints
+                .iter()
+                .any(|breakpoint| breakpoint.address == Some(pc))
+            {
+                return Ok(Json::object(vec![]));
+            }
+        }
+    }
+
+    // The line holding the instruction that contains an address
+    fn line_containing(&self, address: u16) -> Option<usize> {
+        self.debug_info()
+            .lines
+            .iter()
+            .filter(|(line_address, _)| *line_address <= address)
+            .max_by_key(|(line_address, _)| *line_address)
+            .map(|(_, line)| *line)
+    }
+
+    // The innermost frame is the PC, the callers are the calls before each return address
+    fn stack_trace(&self) -> Json {
+        let mut addresses = vec![self.rusty_boi.pc()];
+        for ret in self.rusty_boi.ret_stack().iter().rev() {
+            addresses.push(ret.wrapping_sub(1));
+        }
+
+        let frames: Vec<Json> = addresses
+            .iter()
+            .enumerate()
+            .map(|(index, address)| {
+                let mut name = self.debug_info().describe(*address);
+                if let Some(pseudo) = self.debug_info().expansions.get(address) {
+                    name.push_str(&format!(" in {}", pseudo));
+                }
+                let line = self.line_containing(*address).unwrap_or(0);
+                Json::object(vec![
+                    ("id", Json::from(index)),
+                    ("name", Json::from(name)),
+                    ("source", self.source()),
+                    ("line", Json::from(line)),
+                    ("column", Json::from(1i64)),
+                    (
+                        "instructionPointerReference",
+                        Json::from(format!("0x{:04X}", address)),
+                    ),
+                ])
+            })
+            .collect();
+
+        Json::object(vec![
+            ("totalFrames", Json::from(frames.len())),
+            ("stackFrames", Json::Array(frames)),
+        ])
+    }
+
+    fn variables(&self, reference: i64) -> Json {
+        let mut variables = Vec::new();
+        match reference {
+            REGISTERS_REFERENCE => {
+                for (index, word) in self.rusty_boi.registers().iter().enumerate() {
+                    variables.push(variable(format!("${}", index), word_value(*word)));
+                }
+                variables.push(variable("pc".to_string(), word_value(self.rusty_boi.pc())));
+                variables.push(variable("sp".to_string(), word_value(self.rusty_boi.sp())));
+            }
+            FLAGS_REFERENCE => {
+                let flags = self.rusty_boi.flags();
+                variables.push(variable("compare".to_string(), (flags & 1).to_string()));
+                variables.push(variable("carry".to_string(), (flags >> 1 & 1).to_string()));
+            }
+            RAM_REFERENCE => {
+                let mut constants: Vec<(&String, &u16)> = self
+                    .debug_info()
+                    .constants
+                    .iter()
+                    .filter(|(_, address)| **address < GRAPHICS_START)
+                    .collect();
+                constants.sort();
+                for (name, address) in constants {
+                    let memory = &self.rusty_boi.memory;
+                    let byte = memory[*address as usize];
+                    let word = u16::from_le_bytes([byte, memory[*address as usize + 1]]);
+                    variables.push(variable(
+                        name.clone(),
+                        format!(
+                            "{} (0x{:02X}) word {} at 0x{:04X}",
+                            byte, byte, word, address
+                        ),
+                    ));
+                }
+            }
+            _ => (),
+        }
+        Json::object(vec![("variables", Json::Array(variables))])
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<Json, String> {
+        let value = self.rusty_boi.evaluate(&Expr::parse(expression)?)?;
+        Ok(Json::object(vec![
+            ("result", Json::from(format!("{} (0x{:X})", value, value))),
+            ("variablesReference", Json::from(0i64)),
+        ]))
+    }
+}
+
+fn scopes() -> Json {
+    let scope = |name: &str, reference: i64, hint: Option<&str>| {
+        let mut fields = vec![
+            ("name", Json::from(name)),
+            ("variablesReference", Json::from(reference)),
+            ("expensive", Json::from(false)),
+        ];
+        if let Some(hint) = hint {
+            fields.push(("presentationHint", Json::from(hint)));
+        }
+        Json::object(fields)
+    };
+    Json::object(vec![(
+        "scopes",
+        Json::Array(vec![
+            scope("Registers", REGISTERS_REFERENCE, Some("registers")),
+            scope("Flags", FLAGS_REFERENCE, Some("registers")),
+            scope("RAM", RAM_REFERENCE, None),
+        ]),
+    )])
+}
+
+fn variable(name: String, value: String) -> Json {
+    Json::object(vec![
+        ("name", Json::from(name)),
+        ("value", Json::from(value)),
+        ("variablesReference", Json::from(0i64)),
+    ])
+}
+
+fn word_value(word: u16) -> String {
+    format!("{} (0x{:04X})", word, word)
+}
@@ -0,0 +1,347 @@
+use crate::{
+    assembler::DebugInfo,
+    disassembler::Instruction,
+    expr::{Expr, ExprContext},
+    register_file::{FlagRegister, RegisterFile},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Change,
+    Access,
+}
+
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+pub struct Breakpoint {
+    pub address: Option<u16>,
+    pub condition: Option<Expr>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum StopReason {
+    Breakpoint(usize),
+    Watchpoint(usize, Access),
+    // The condition of the breakpoint couldn't be evaluated, with the error
+    ConditionError(usize, String),
+}
+
+// Why a run stopped and the address of the instruction responsible
+#[derive(Debug, Clone)]
+pub struct Stop {
+    pub reason: StopReason,
+    pub pc: u16,
+}
+
+// Read only view of the VM used to evaluate breakpoint conditions
+pub struct VmContext<'a> {
+    pub pc: u16,
+    pub sp: u16,
+    pub registers: &'a RegisterFile,
+    pub flags: &'a FlagRegister,
+    pub memory: &'a [u8],
+    pub debug_info: &'a DebugInfo,
+}
+
+impl ExprContext for VmContext<'_> {
+    fn name(&mut self, name: &str) -> Result<i64, String> {
+        match name {
+            "pc" => Ok(self.pc as i64),
+            "sp" => Ok(self.sp as i64),
+            "compare" => Ok(self.flags.compare as i64),
+            "carry" => Ok(self.flags.carry as i64),
+            _ => symbol(self.debug_info, name),
+        }
+    }
+
+    fn register(&mut self, register: &str) -> Result<i64, String> {
+        let chars: Vec<char> = register.chars().collect();
+        let index = match chars.get(1).and_then(|c| c.to_digit(10)) {
+            Some(index) if index < 8 => index as usize,
+            _ => return Err(format!("Invalid register {}", register)),
+        };
+        let word = self.registers.words()[index];
+        match (chars.len(), chars.get(2)) {
+            (2, _) => Ok(word as i64),
+            (3, Some('a')) => Ok(word.to_le_bytes()[0] as i64),
+            (3, Some('b')) => Ok(word.to_le_bytes()[1] as i64),
+            _ => Err(format!("Invalid register {}", register)),
+        }
+    }
+
+    fn memory(&mut self, address: u16, word: bool) -> Result<i64, String> {
+        let low = self.memory[address as usize];
+        match word {
+            true => {
+                let high = self.memory[address.wrapping_add(1) as usize];
+                Ok(u16::from_le_bytes([low, high]) as i64)
+            }
+            false => Ok(low as i64),
+        }
+    }
+}
+
+fn symbol(debug_info: &DebugInfo, name: &str) -> Result<i64, String> {
+    match debug_info
+        .labels
+        .get(name)
+        .or_else(|| debug_info.constants.get(name))
+    {
+        Some(value) => Ok(*value as i64),
+        None => Err(format!("Uknown symbol {}", name)),
+    }
+}
+
+struct SymbolContext<'a> {
+    debug_info: &'a DebugInfo,
+}
+
+impl ExprContext for SymbolContext<'_> {
+    fn name(&mut self, name: &str) -> Result<i64, String> {
+        symbol(self.debug_info, name)
+    }
+}
+
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub debug_info: DebugInfo,
+    accesses: Vec<Access>,
+    skip_breakpoint: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new(debug_info: DebugInfo) -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            debug_info,
+            accesses: Vec::new(),
+            skip_breakpoint: None,
+        }
+    }
+
+    // Evaluates an expression that can only use numbers, labels and constants
+    pub fn resolve(&self, text: &str) -> Result<u16, String> {
+        let expr = Expr::parse(text)?;
+        let mut context = SymbolContext {
+            debug_info: &self.debug_info,
+        };
+        Ok(expr.eval(&mut context)? as u16)
+    }
+
+    // Accepts "location", "location if condition" or "if condition"
+    pub fn add_breakpoint(&mut self, text: &str) -> Result<(), String> {
+        let text = text.trim();
+        let (location, condition) = match text.strip_prefix("if ") {
+            Some(condition) => ("", Some(condition)),
+            None => match text.split_once(" if ") {
+                Some((location, condition)) => (location, Some(condition)),
+                None => (text, None),
+            },
+        };
+
+        let address = match location.trim() {
+            "" => None,
+            location => Some(self.resolve(location)?),
+        };
+        let condition = match condition {
+            Some(condition) => Some(Expr::parse(condition)?),
+            None => None,
+        };
+        if address.is_none() && condition.is_none() {
+            return Err(format!(
+                "Breakpoint {} needs an address or a condition",
+                text
+            ));
+        }
+
+        self.breakpoints.push(Breakpoint {
+            address,
+            condition,
+            text: text.to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn add_breakpoint_at(&mut self, address: u16) {
+        self.breakpoints.push(Breakpoint {
+            address: Some(address),
+            condition: None,
+            text: format!("0x{:04X}", address),
+        });
+    }
+
+    pub fn remove_breakpoint_at(&mut self, address: u16) {
+        if let Some(index) = self.breakpoints.iter().position(|breakpoint| {
+            breakpoint.address == Some(address) && breakpoint.condition.is_none()
+        }) {
+            self.breakpoints.remove(index);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) {
+        if let Some(index) = self.watchpoints.iter().position(|watchpoint| {
+            watchpoint.start == start && watchpoint.end == end && watchpoint.kind == kind
+        }) {
+            self.watchpoints.remove(index);
+        }
+    }
+
+    // Accepts "address", "start:end" and an optional "read", "write", "change" or "access" after a space
+    pub fn add_watchpoint(&mut self, text: &str) -> Result<(), String> {
+        let mut parts = text.split_whitespace();
+        let range = match parts.next() {
+            Some(range) => range,
+            None => return Err("Empty watchpoint".to_string()),
+        };
+        let kind = match parts.next() {
+            None | Some("write") => WatchKind::Write,
+            Some("read") => WatchKind::Read,
+            Some("change") => WatchKind::Change,
+            Some("access") => WatchKind::Access,
+            Some(kind) => return Err(format!("Uknown watchpoint kind {}", kind)),
+        };
+
+        let (start, end) = match range.split_once(':') {
+            Some((start, end)) => (self.resolve(start)?, self.resolve(end)?),
+            None => {
+                let address = self.resolve(range)?;
+                (address, address)
+            }
+        };
+
+        self.watchpoints.push(Watchpoint { start, end, kind });
+        Ok(())
+    }
+
+    pub fn clear_accesses(&mut self) {
+        self.accesses.clear();
+    }
+
+    pub fn record_access(&mut self, address: u16, old: u8, new: u8, write: bool) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        self.accesses.push(Access {
+            address,
+            old,
+            new,
+            write,
+        });
+    }
+
+    pub fn check_breakpoints(
+        &mut self,
+        pc: u16,
+        sp: u16,
+        registers: &RegisterFile,
+        flags: &FlagRegister,
+        memory: &[u8],
+    ) -> Option<Stop> {
+        // Resuming from a breakpoint must not stop on it straight away
+        if self.skip_breakpoint.take() == Some(pc) {
+            return None;
+        }
+
+        let mut context = VmContext {
+            pc,
+            sp,
+            registers,
+            flags,
+            memory,
+            debug_info: &self.debug_info,
+        };
+        let mut hit = None;
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            if breakpoint.address.is_some_and(|address| address != pc) {
+                continue;
+            }
+            // A condition that can't be evaluated stops so the error gets seen
+            hit = match &breakpoint.condition {
+                Some(condition) => match condition.eval(&mut context) {
+                    Ok(0) => continue,
+                    Ok(_) => Some(StopReason::Breakpoint(index)),
+                    Err(error) => Some(StopReason::ConditionError(index, error)),
+                },
+                None => Some(StopReason::Breakpoint(index)),
+            };
+            break;
+        }
+
+        let reason = hit?;
+        self.skip_breakpoint = Some(pc);
+        Some(Stop { reason, pc })
+    }
+
+    pub fn check_watchpoints(&self, pc: u16) -> Option<Stop> {
+        for access in self.accesses.iter() {
+            for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+                if access.address < watchpoint.start || access.address > watchpoint.end {
+                    continue;
+                }
+                let hit = match watchpoint.kind {
+                    WatchKind::Read => !access.write,
+                    WatchKind::Write => access.write,
+                    WatchKind::Change => access.write && access.old != access.new,
+                    WatchKind::Access => true,
+                };
+                if hit {
+                    return Some(Stop {
+                        reason: StopReason::Watchpoint(index, *access),
+                        pc,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    pub fn report(&self, stop: &Stop, prog_mem: &[u8]) -> String {
+        let instruction = Instruction::decode(prog_mem, stop.pc);
+        let mut location = format!(
+            "0x{:04X} ({}) {}",
+            stop.pc,
+            self.debug_info.describe(stop.pc),
+            instruction
+        );
+        if let Some(pseudo) = self.debug_info.expansions.get(&stop.pc) {
+            location.push_str(&format!(" from {}", pseudo));
+        }
+        match &stop.reason {
+            StopReason::Breakpoint(index) => format!(
+                "Breakpoint {} hit at {}",
+                self.breakpoints[*index].text, location
+            ),
+            StopReason::ConditionError(index, error) => format!(
+                "Breakpoint {} condition failed : {} at {}",
+                self.breakpoints[*index].text, error, location
+            ),
+            StopReason::Watchpoint(_, access) => match access.write {
+                true => format!(
+                    "Watchpoint hit, 0x{:04X} written from {} to {} by {}",
+                    access.address, access.old, access.new, location
+                ),
+                false => format!(
+                    "Watchpoint hit, 0x{:04X} read as {} by {}",
+                    access.address, access.new, location
+                ),
+            },
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt::Display;
+
+use crate::opcodes::{Opcode, OpcodeWidth};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register { address: u8, width: OpcodeWidth },
+    Byte(u8),
+    Word(u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub address: u16,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+    pub size: u16,
+}
+
+impl Instruction {
+    pub fn decode(prog_mem: &[u8], address: u16) -> Self {
+        let read_byte = |offset: u16| -> u8 {
+            let index = address.wrapping_add(offset) as usize;
+            match prog_mem.get(index) {
+                Some(byte) => *byte,
+                None => 0,
+            }
+        };
+        let read_word =
+            |offset: u16| -> u16 { u16::from_le_bytes([read_byte(offset), read_byte(offset + 1)]) };
+        let register = |offset: u16, width: OpcodeWidth| Operand::Register {
+            address: read_byte(offset),
+            width,
+        };
+
+        let opcode_byte = read_byte(0);
+        let opcode = Opcode::from_byte(opcode_byte);
+        let operands = match opcode {
+            Opcode::LOAD { width } => match width {
+                OpcodeWidth::Byte => vec![register(1, width), Operand::Byte(read_byte(2))],
+                OpcodeWidth::Word => vec![register(1, width), Operand::Word(read_word(2))],
+            },
+            Opcode::MOV { width }
+            | Opcode::EQ { width }
+            | Opcode::NEQ { width }
+            | Opcode::LES { width, .. } => vec![register(1, width), register(2, width)],
+            Opcode::STORE { width }
+            | Opcode::READ { width }
+            | Opcode::PUSH { width }
+            | Opcode::POP { width }
+            | Opcode::INC { width }
+            | Opcode::DEC { width }
+            | Opcode::NEG { width }
+            | Opcode::NOT { width }
+            | Opcode::HALT { width } => vec![register(1, width)],
+            Opcode::ADD { width, .. }
+            | Opcode::SUB { width, .. }
+            | Opcode::MUL { width, .. }
+            | Opcode::DIV { width, .. }
+            | Opcode::MOD { width, .. }
+            | Opcode::SHR { width }
+            | Opcode::SHL { width } => {
+                vec![register(1, width), register(2, width), register(3, width)]
+            }
+            Opcode::JMP
+            | Opcode::CJMP
+            | Opcode::NJMP
+            | Opcode::CALL
+            | Opcode::CCALL
+            | Opcode::NCALL
+            | Opcode::JDRAW => vec![Operand::Word(read_word(1))],
+            Opcode::RET | Opcode::DRAW | Opcode::RDRAW | Opcode::NOP => Vec::new(),
+            Opcode::ILG => vec![Operand::Byte(opcode_byte)],
+        };
+
+        let size = 1 + operands
+            .iter()
+            .map(|operand| match operand {
+                Operand::Register { .. } | Operand::Byte(_) => 1,
+                Operand::Word(_) => 2,
+            })
+            .sum::<u16>();
+        let size = match opcode {
+            Opcode::ILG => 1,
+            _ => size,
+        };
+
+        Self {
+            address,
+            opcode,
+            operands,
+            size,
+        }
+    }
+
+    // The address of the instruction that follows this one in program memory
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.size)
+    }
+
+    // Jump and call destination encoded in the instruction
+    pub fn target(&self) -> Option<u16> {
+        match self.opcode {
+            Opcode::JMP
+            | Opcode::CJMP
+            | Opcode::NJMP
+            | Opcode::CALL
+            | Opcode::CCALL
+            | Opcode::NCALL
+            | Opcode::JDRAW => match self.operands[0] {
+                Operand::Word(word) => Some(word),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Where execution can carry on after this instruction, calls are expected to return
+    pub fn successors(&self) -> Vec<u16> {
+        let next = self.next_address();
+        match (self.opcode, self.target()) {
+            (Opcode::JMP | Opcode::JDRAW, Some(target)) => vec![target],
+            (Opcode::CJMP | Opcode::NJMP, Some(target)) => vec![target, next],
+            (Opcode::CALL | Opcode::CCALL | Opcode::NCALL, _) => vec![next],
+            (Opcode::RDRAW, _) => vec![0],
+            (Opcode::RET | Opcode::HALT { .. } | Opcode::ILG, _) => Vec::new(),
+            _ => vec![next],
+        }
+    }
+
+    pub fn is_call(&self) -> bool {
+        matches!(self.opcode, Opcode::CALL | Opcode::CCALL | Opcode::NCALL)
+    }
+
+    // Registers bytes read by the instruction, including $7 for STORE and READ
+    pub fn reads(&self) -> u16 {
+        let operands = &self.operands;
+        match self.opcode {
+            Opcode::MOV { .. }
+            | Opcode::PUSH { .. }
+            | Opcode::INC { .. }
+            | Opcode::DEC { .. }
+            | Opcode::NEG { .. }
+            | Opcode::NOT { .. }
+            | Opcode::HALT { .. } => register_mask(&operands[0]),
+            Opcode::STORE { .. } => register_mask(&operands[0]) | ADDRESS_REGISTER_MASK,
+            Opcode::READ { .. } => ADDRESS_REGISTER_MASK,
+            Opcode::EQ { .. } | Opcode::NEQ { .. } | Opcode::LES { .. } => {
+                register_mask(&operands[0]) | register_mask(&operands[1])
+            }
+            Opcode::ADD { .. }
+            | Opcode::SUB { .. }
+            | Opcode::MUL { .. }
+            | Opcode::DIV { .. }
+            | Opcode::MOD { .. }
+            | Opcode::SHR { .. }
+            | Opcode::SHL { .. } => register_mask(&operands[0]) | register_mask(&operands[1]),
+            _ => 0,
+        }
+    }
+
+    // Register bytes written by the instruction
+    pub fn writes(&self) -> u16 {
+        let operands = &self.operands;
+        match self.opcode {
+            Opcode::LOAD { .. }
+            | Opcode::READ { .. }
+            | Opcode::POP { .. }
+            | Opcode::INC { .. }
+            | Opcode::DEC { .. }
+            | Opcode::NEG { .. }
+            | Opcode::NOT { .. } => register_mask(&operands[0]),
+            Opcode::MOV { .. } => register_mask(&operands[1]),
+            Opcode::ADD { .. }
+            | Opcode::SUB { .. }
+            | Opcode::MUL { .. }
+            | Opcode::DIV { .. }
+            | Opcode::MOD { .. }
+            | Opcode::SHR { .. }
+            | Opcode::SHL { .. } => register_mask(&operands[2]),
+            _ => 0,
+        }
+    }
+
+    // Change in the number of bytes on the data stack
+    pub fn stack_effect(&self) -> i32 {
+        match self.opcode {
+            Opcode::PUSH { width } => match width {
+                OpcodeWidth::Byte => 1,
+                OpcodeWidth::Word => 2,
+            },
+            Opcode::POP { width } => match width {
+                OpcodeWidth::Byte => -1,
+                OpcodeWidth::Word => -2,
+            },
+            _ => 0,
+        }
+    }
+}
+
+// Bit mask of the register file bytes an operand covers, bit n is byte n
+pub fn register_mask(operand: &Operand) -> u16 {
+    match operand {
+        Operand::Register { address, width } => match width {
+            OpcodeWidth::Byte => 1 << (address & 15),
+            OpcodeWidth::Word => 3 << (address & 14),
+        },
+        _ => 0,
+    }
+}
+
+// Register file bytes of $7, the address register of STORE and READ
+pub const ADDRESS_REGISTER_MASK: u16 = 3 << 14;
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Register { address, width } => match width {
+                OpcodeWidth::Word => write!(f, "${}", address / 2),
+                OpcodeWidth::Byte => match address % 2 {
+                    0 => write!(f, "${}a", address / 2),
+                    _ => write!(f, "${}b", address / 2),
+                },
+            },
+            Operand::Byte(byte) => write!(f, "#{}", byte),
+            Operand::Word(word) => write!(f, "0x{:04X}", word),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = self.opcode.mnemonic().to_string();
+        for operand in self.operands.iter() {
+            text.push(' ');
+            text.push_str(operand.to_string().as_str());
+        }
+        f.pad(text.as_str())
+    }
+}
@@ -0,0 +1,337 @@
+// Small expression language shared by the debugger conditions and the assembler,
+// names, registers and memory are looked up through an ExprContext when evaluating
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitOr,
+    BitXor,
+    BitAnd,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Name(String),
+    Register(String),
+    // Address and whether a whole word is read
+    Memory(Box<Expr>, bool),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+pub trait ExprContext {
+    fn name(&mut self, name: &str) -> Result<i64, String>;
+
+    fn register(&mut self, register: &str) -> Result<i64, String> {
+        Err(format!("Registers like {} can't be used here", register))
+    }
+
+    fn memory(&mut self, address: u16, _word: bool) -> Result<i64, String> {
+        Err(format!("Memory at 0x{:04X} can't be read here", address))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Register(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "|", "^", "&", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[", "]",
+];
+
+// Numbers are decimal, #decimal, 0x hex, 0b binary or a 'c' character, a minus can go in
+// front of the number or after the prefix and _ can separate digits
+pub fn parse_number(number_str: &str) -> Result<i64, String> {
+    let chars: Vec<char> = number_str.chars().collect();
+    if chars.len() == 3 && chars[0] == '\'' && chars[2] == '\'' {
+        return Ok(chars[1] as i64);
+    }
+
+    let cleaned = number_str.replace('_', "");
+    let (sign, unsigned) = match cleaned.strip_prefix('-') {
+        Some(unsigned) => (-1, unsigned),
+        None => (1, cleaned.as_str()),
+    };
+    let (digits, radix) = if let Some(hex) = unsigned.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = unsigned.strip_prefix("0b") {
+        (binary, 2)
+    } else if let Some(decimal) = unsigned.strip_prefix('#') {
+        (decimal, 10)
+    } else {
+        (unsigned, 10)
+    };
+
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, digits),
+    };
+
+    match i64::from_str_radix(digits, radix) {
+        Ok(num) if negative => Ok(-num * sign),
+        Ok(num) => Ok(num * sign),
+        Err(error) => Err(format!("Malformated number {} \n{}", number_str, error)),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let c = chars[index];
+        if c.is_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        if c == '\'' {
+            if index + 2 < chars.len() && chars[index + 2] == '\'' {
+                tokens.push(Token::Number(chars[index + 1] as i64));
+                index += 3;
+                continue;
+            }
+            return Err(format!("Malformated character literal in {}", text));
+        }
+
+        if c.is_ascii_digit() || c == '#' {
+            let start = index;
+            index += 1;
+            while index < chars.len()
+                && (chars[index].is_ascii_alphanumeric() || chars[index] == '_')
+            {
+                index += 1;
+            }
+            let number: String = chars[start..index].iter().collect();
+            tokens.push(Token::Number(parse_number(number.as_str())?));
+            continue;
+        }
+
+        if c == '$' || c.is_alphabetic() || c == '_' || c == '.' {
+            let start = index;
+            index += 1;
+            while index < chars.len()
+                && (chars[index].is_alphanumeric() || chars[index] == '_' || chars[index] == '.')
+            {
+                index += 1;
+            }
+            let word: String = chars[start..index].iter().collect();
+            match c {
+                '$' => tokens.push(Token::Register(word)),
+                _ => tokens.push(Token::Name(word)),
+            }
+            continue;
+        }
+
+        let rest: String = chars[index..].iter().take(2).collect();
+        match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            Some(symbol) => {
+                tokens.push(Token::Symbol(symbol));
+                index += symbol.len();
+            }
+            None => return Err(format!("Unexpected character {} in {}", c, text)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn binary_op(symbol: &str) -> Option<(BinaryOp, u8)> {
+    match symbol {
+        "||" => Some((BinaryOp::Or, 1)),
+        "&&" => Some((BinaryOp::And, 2)),
+        "==" => Some((BinaryOp::Equal, 3)),
+        "!=" => Some((BinaryOp::NotEqual, 3)),
+        "<" => Some((BinaryOp::Less, 4)),
+        "<=" => Some((BinaryOp::LessEqual, 4)),
+        ">" => Some((BinaryOp::Greater, 4)),
+        ">=" => Some((BinaryOp::GreaterEqual, 4)),
+        "|" => Some((BinaryOp::BitOr, 5)),
+        "^" => Some((BinaryOp::BitXor, 6)),
+        "&" => Some((BinaryOp::BitAnd, 7)),
+        "<<" => Some((BinaryOp::ShiftLeft, 8)),
+        ">>" => Some((BinaryOp::ShiftRight, 8)),
+        "+" => Some((BinaryOp::Add, 9)),
+        "-" => Some((BinaryOp::Sub, 9)),
+        "*" => Some((BinaryOp::Mul, 10)),
+        "/" => Some((BinaryOp::Div, 10)),
+        "%" => Some((BinaryOp::Mod, 10)),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(found)) if found == symbol => Ok(()),
+            _ => Err(format!("Expected {} in expression", symbol)),
+        }
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(Token::Symbol(symbol)) = self.peek() {
+            let (op, precedence) = match binary_op(symbol) {
+                Some(op) => op,
+                None => break,
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            self.next();
+            let rhs = self.parse_binary(precedence + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(num)) => Ok(Expr::Number(num)),
+            Some(Token::Register(register)) => Ok(Expr::Register(register)),
+            Some(Token::Name(name)) => {
+                if name == "word" {
+                    if let Some(Token::Symbol("[")) = self.peek() {
+                        self.next();
+                        let address = self.parse_binary(0)?;
+                        self.expect("]")?;
+                        return Ok(Expr::Memory(Box::new(address), true));
+                    }
+                }
+                Ok(Expr::Name(name))
+            }
+            Some(Token::Symbol("(")) => {
+                let inner = self.parse_binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Some(Token::Symbol("[")) => {
+                let address = self.parse_binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(address), false))
+            }
+            Some(Token::Symbol("-")) => {
+                Ok(Expr::Unary(UnaryOp::Negate, Box::new(self.parse_unary()?)))
+            }
+            Some(Token::Symbol("!")) => {
+                Ok(Expr::Unary(UnaryOp::Not, Box::new(self.parse_unary()?)))
+            }
+            Some(Token::Symbol("~")) => {
+                Ok(Expr::Unary(UnaryOp::BitNot, Box::new(self.parse_unary()?)))
+            }
+            Some(Token::Symbol(symbol)) => Err(format!("Unexpected {} in expression", symbol)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            index: 0,
+        };
+        let expr = parser.parse_binary(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(_) => Err(format!("Unexpected trailing input in expression {}", text)),
+        }
+    }
+
+    pub fn eval(&self, context: &mut dyn ExprContext) -> Result<i64, String> {
+        match self {
+            Expr::Number(num) => Ok(*num),
+            Expr::Name(name) => context.name(name),
+            Expr::Register(register) => context.register(register),
+            Expr::Memory(address, word) => {
+                let address = address.eval(context)?;
+                context.memory(address as u16, *word)
+            }
+            Expr::Unary(op, inner) => {
+                let value = inner.eval(context)?;
+                Ok(match op {
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::BitNot => !value,
+                })
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(context)?;
+                // Logical operators short circuit so guarded reads stay guarded
+                match op {
+                    BinaryOp::And if lhs == 0 => return Ok(0),
+                    BinaryOp::Or if lhs != 0 => return Ok(1),
+                    _ => (),
+                }
+                let rhs = rhs.eval(context)?;
+                Ok(match op {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as i64,
+                    BinaryOp::Equal => (lhs == rhs) as i64,
+                    BinaryOp::NotEqual => (lhs != rhs) as i64,
+                    BinaryOp::Less => (lhs < rhs) as i64,
+                    BinaryOp::LessEqual => (lhs <= rhs) as i64,
+                    BinaryOp::Greater => (lhs > rhs) as i64,
+                    BinaryOp::GreaterEqual => (lhs >= rhs) as i64,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::ShiftLeft => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::ShiftRight => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Mod if rhs == 0 => {
+                        return Err("Division by zero in expression".to_string())
+                    }
+                    BinaryOp::Div => lhs.wrapping_div(rhs),
+                    BinaryOp::Mod => lhs.wrapping_rem(rhs),
+                })
+            }
+        }
+    }
+}
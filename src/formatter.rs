@@ -0,0 +1,269 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+use crate::assembler::{Assembler, SourceLine};
+
+const INDENT: &str = "    ";
+// The longest mnemonics are five characters, operands start one space after them
+const MNEMONIC_WIDTH: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Blank,
+    Comment,
+    Label,
+    Const,
+    Directive,
+    Instruction,
+}
+
+struct FormatLine {
+    kind: Kind,
+    words: Vec<String>,
+    comment: Option<String>,
+}
+
+// Hex is written in upper case and padded to the width of the operand, decimal loses leading zeros
+fn normalize_number(text: &str, digits: usize) -> String {
+    if let Some(hex) = text.strip_prefix("0x") {
+        if let Ok(value) = u32::from_str_radix(hex, 16) {
+            return format!("0x{:0width$X}", value, width = digits);
+        }
+    } else if let Some(decimal) = text.strip_prefix('#') {
+        if let Ok(value) = decimal.parse::<i64>() {
+            return format!("#{}", value);
+        }
+    }
+    text.to_string()
+}
+
+fn normalize_comment(comment: &str) -> String {
+    match comment.trim() {
+        "" => "//".to_string(),
+        text => format!("// {}", text),
+    }
+}
+
+// Numbers inside conditional blocks are left as written, a branch the default build skips is
+// never assembled so a change to it couldn't be checked
+fn parse_line(line: &str, conditional: bool) -> FormatLine {
+    let source_line = SourceLine::parse(line);
+    let mut words: Vec<String> = source_line
+        .words()
+        .iter()
+        .map(|word| word.to_string())
+        .collect();
+    let comment = source_line.comment.map(normalize_comment);
+
+    let kind = match words.first().map(|word| word.as_str()) {
+        None if comment.is_some() => Kind::Comment,
+        None => Kind::Blank,
+        Some(word) if word.contains(':') => Kind::Label,
+        Some("const") => Kind::Const,
+        Some(
+            "org" | "align" | "fill" | "export" | "import" | "if" | "ifdef" | "ifndef" | "else"
+            | "endif" | "rept" | "endr" | "var" | "struct" | "endstruct",
+        ) => Kind::Directive,
+        Some(_) => Kind::Instruction,
+    };
+
+    match (kind, words.first().map(|word| word.as_str())) {
+        _ if conditional => (),
+        (Kind::Const, _) if words.len() > 2 => words[2] = normalize_number(&words[2], 4),
+        (Kind::Directive, Some("fill")) if words.len() > 1 => {
+            words[1] = normalize_number(&words[1], 2)
+        }
+        (Kind::Directive, _) if words.len() > 1 => words[1] = normalize_number(&words[1], 4),
+        (Kind::Instruction, Some("LOAD")) if words.len() > 2 => {
+            let byte = words[1].ends_with('a') || words[1].ends_with('b');
+            words[2] = normalize_number(&words[2], if byte { 2 } else { 4 });
+        }
+        (Kind::Instruction, _) if words.len() > 1 => {
+            for word in words.iter_mut().skip(1) {
+                *word = normalize_number(word, 4);
+            }
+        }
+        _ => (),
+    }
+
+    FormatLine {
+        kind,
+        words,
+        comment,
+    }
+}
+
+fn pad(text: &str, width: usize) -> String {
+    format!("{:width$}", text, width = width)
+}
+
+// Renders a run of lines without blank lines between them, operands and comments are aligned
+// across the run
+fn render_block(block: &[FormatLine], output: &mut Vec<String>) {
+    let mut operand_widths: Vec<usize> = Vec::new();
+    let mut const_width = 0;
+    for line in block {
+        match line.kind {
+            Kind::Instruction => {
+                // The last operand is never padded so it doesn't widen its column
+                let count = line.words.len().saturating_sub(2);
+                for (index, word) in line.words.iter().skip(1).take(count).enumerate() {
+                    if operand_widths.len() <= index {
+                        operand_widths.push(0);
+                    }
+                    operand_widths[index] = operand_widths[index].max(word.len());
+                }
+            }
+            Kind::Const if line.words.len() > 2 => {
+                const_width = const_width.max(line.words[1].len());
+            }
+            _ => (),
+        }
+    }
+
+    let code: Vec<String> = block
+        .iter()
+        .enumerate()
+        .map(|(index, line)| match line.kind {
+            Kind::Instruction => {
+                let mut text = format!("{}{}", INDENT, line.words[0]);
+                let operands = &line.words[1..];
+                if !operands.is_empty() {
+                    text = pad(&text, INDENT.len() + MNEMONIC_WIDTH);
+                    let last = operands.len() - 1;
+                    let columns: Vec<String> = operands
+                        .iter()
+                        .enumerate()
+                        .map(|(column, word)| match column < last {
+                            true => pad(word, operand_widths[column]),
+                            false => word.clone(),
+                        })
+                        .collect();
+                    text.push_str(columns.join(" ").as_str());
+                }
+                text
+            }
+            Kind::Const if line.words.len() > 2 => format!(
+                "const {} {}",
+                pad(&line.words[1], const_width),
+                line.words[2..].join(" ")
+            ),
+            Kind::Comment => {
+                // Comments on their own line are indented like the code after them
+                let next = block[index..]
+                    .iter()
+                    .find(|line| line.kind != Kind::Comment);
+                match next.map(|line| line.kind) {
+                    Some(Kind::Instruction) => INDENT.to_string(),
+                    _ => String::new(),
+                }
+            }
+            _ => line.words.join(" "),
+        })
+        .collect();
+
+    let comment_column = block
+        .iter()
+        .zip(code.iter())
+        .filter(|(line, _)| line.kind != Kind::Comment && line.comment.is_some())
+        .map(|(_, code)| code.len() + 1)
+        .max()
+        .unwrap_or(0);
+
+    for (line, code) in block.iter().zip(code) {
+        let text = match (&line.comment, line.kind) {
+            (Some(comment), Kind::Comment) => format!("{}{}", code, comment),
+            (Some(comment), _) => format!("{}{}", pad(&code, comment_column), comment),
+            (None, _) => code,
+        };
+        output.push(text.trim_end().to_string());
+    }
+}
+
+pub fn format_source(source: &str) -> String {
+    let mut output = Vec::new();
+    let mut block = Vec::new();
+    let mut depth = 0usize;
+    for text in source.lines() {
+        let first = SourceLine::parse(text)
+            .words()
+            .first()
+            .map(|word| word.to_string());
+        if first.as_deref() == Some("endif") {
+            depth = depth.saturating_sub(1);
+        }
+        let line = parse_line(text, depth > 0);
+        if matches!(first.as_deref(), Some("if" | "ifdef" | "ifndef")) {
+            depth += 1;
+        }
+        if line.kind != Kind::Blank {
+            block.push(line);
+            continue;
+        }
+        // Runs of blank lines collapse into one
+        if !block.is_empty() {
+            render_block(&block, &mut output);
+            block.clear();
+            output.push(String::new());
+        }
+    }
+    render_block(&block, &mut output);
+
+    while output.last().map(|line| line.is_empty()) == Some(true) {
+        output.pop();
+    }
+    let mut formatted = output.join("\n");
+    formatted.push('\n');
+    formatted
+}
+
+fn assemble_source(path: &str, source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::new();
+    assembler.load_source(path, source);
+    assembler.pre_assembly()?;
+    assembler.assemble()
+}
+
+// Formats a file in place, or only reports whether it needs formatting when checking.
+// Returns true when the file was already formatted
+pub fn format_file(path: &str, check: bool) -> Result<bool, String> {
+    let mut source = String::new();
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) => return Err(format!("Error opening file {} \n{}", path, error)),
+    };
+    if let Err(error) = file.read_to_string(&mut source) {
+        return Err(format!("Error reading file {} \n{}", path, error));
+    }
+
+    let formatted = format_source(&source);
+    if formatted == source {
+        return Ok(true);
+    }
+
+    // The formatter may only move whitespace around, anything else is a bug
+    let original = assemble_source(path, &source).map_err(|error| {
+        format!(
+            "{} doesn't assemble, leaving it untouched \n{}",
+            path, error
+        )
+    })?;
+    if assemble_source(path, &formatted) != Ok(original) {
+        return Err(format!(
+            "Formatting {} would change the assembled program, leaving it untouched",
+            path
+        ));
+    }
+
+    if !check {
+        let mut file = match File::create(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error creating file {} \n{}", path, error)),
+        };
+        file.write_all(formatted.as_bytes())
+            .map_err(|e| format!("Error writing file {} \n{}", path, e))?;
+    }
+    Ok(false)
+}
@@ -0,0 +1,488 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    assembler::DebugInfo,
+    debugger::{Debugger, Stop, StopReason, WatchKind},
+    rusty_boi::{Halt, RustyBoi},
+    vm_main::CYCLES_PER_FRAME,
+};
+
+// RAM is exposed at its own addresses, program memory is placed right after it
+pub const PROG_MEM_OFFSET: u32 = 0x10000;
+
+const REGISTER_COUNT: usize = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustyboi.core">
+    <flags id="rusty_boi_flags" size="1">
+      <field name="compare" start="0" end="0"/>
+      <field name="carry" start="1" end="1"/>
+    </flags>
+    <reg name="r0" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="flags" bitsize="8" type="rusty_boi_flags"/>
+  </feature>
+</target>
+"#;
+
+const MEMORY_MAP_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE memory-map PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN" "http://sourceware.org/gdb/gdb-memory-map.dtd">
+<memory-map>
+  <memory type="ram" start="0x0" length="0x10000"/>
+  <memory type="ram" start="0x10000" length="0x10000"/>
+</memory-map>
+"#;
+
+pub fn serve(rusty_boi: &mut RustyBoi, port: u16, debug_info: DebugInfo) -> Result<(), String> {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(error) => return Err(format!("Error listening on port {} \n{}", port, error)),
+    };
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    let stream = match listener.accept() {
+        Ok((stream, _)) => stream,
+        Err(error) => return Err(format!("Error accepting GDB connection \n{}", error)),
+    };
+
+    if rusty_boi.debugger().is_none() {
+        rusty_boi.set_debugger(Debugger::new(debug_info));
+    }
+    rusty_boi.set_cycle_budget(Some(CYCLES_PER_FRAME));
+
+    let mut session = GdbSession { stream, rusty_boi };
+    session.run()
+}
+
+struct GdbSession<'a> {
+    stream: TcpStream,
+    rusty_boi: &'a mut RustyBoi,
+}
+
+impl GdbSession<'_> {
+    fn run(&mut self) -> Result<(), String> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            match self.handle(packet.as_str())? {
+                Some(reply) => self.send_packet(reply.as_str())?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, String> {
+        let mut byte = [0u8; 1];
+        match self.stream.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(error) => Err(format!("Error reading from GDB \n{}", error)),
+        }
+    }
+
+    // Returns the packet data without framing, None once GDB hangs up
+    fn read_packet(&mut self) -> Result<Option<String>, String> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                // Acks and interrupts outside of a continue are ignored
+                Some(_) => continue,
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+            }
+        }
+        let mut checksum = [0u8; 2];
+        for digit in checksum.iter_mut() {
+            match self.read_byte()? {
+                Some(byte) => *digit = byte,
+                None => return Ok(None),
+            }
+        }
+
+        let expected = u8::from_str_radix(String::from_utf8_lossy(&checksum).as_ref(), 16).ok();
+        if expected != Some(packet_checksum(&data)) {
+            self.write_raw(b"-")?;
+            return self.read_packet();
+        }
+        self.write_raw(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&data).to_string()))
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.stream
+            .write_all(bytes)
+            .map_err(|e| format!("Error writing to GDB \n{}", e))
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), String> {
+        let packet = format!("${}#{:02x}", data, packet_checksum(data.as_bytes()));
+        self.write_raw(packet.as_bytes())?;
+
+        // Wait for the ack, resending when GDB asks for it
+        loop {
+            match self.read_byte()? {
+                Some(b'+') | None => return Ok(()),
+                Some(b'-') => self.write_raw(packet.as_bytes())?,
+                Some(_) => (),
+            }
+        }
+    }
+
+    fn interrupt_pending(&mut self) -> Result<bool, String> {
+        let _ = self.stream.set_nonblocking(true);
+        let mut byte = [0u8; 1];
+        let result = match self.stream.read(&mut byte) {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(format!("Error reading from GDB \n{}", error)),
+        };
+        let _ = self.stream.set_nonblocking(false);
+        result
+    }
+
+    fn handle(&mut self, packet: &str) -> Result<Option<String>, String> {
+        // The packet went through from_utf8_lossy, so the command may be more than a byte
+        let (command, rest) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(rest),
+            "p" => self.read_register(rest),
+            "P" => self.write_register(rest),
+            "m" => self.read_memory(rest),
+            "M" => self.write_memory(rest),
+            "c" => {
+                self.resume_at(rest);
+                self.resume()?
+            }
+            "s" => {
+                self.resume_at(rest);
+                self.single_step()
+            }
+            "Z" => self.set_point(rest, true),
+            "z" => self.set_point(rest, false),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "D" => {
+                self.send_packet("OK")?;
+                return Ok(None);
+            }
+            "k" => return Ok(None),
+            "q" => self.query(packet),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;qXfer:memory-map:read+;swbreak+;hwbreak+"
+                .to_string();
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return xfer(TARGET_XML, args);
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:memory-map:read::") {
+            return xfer(MEMORY_MAP_XML, args);
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn register_values(&self) -> [u16; REGISTER_COUNT] {
+        let mut values = [0u16; REGISTER_COUNT];
+        values[0..8].copy_from_slice(&self.rusty_boi.registers());
+        values[8] = self.rusty_boi.pc();
+        values[9] = self.rusty_boi.sp();
+        values[10] = self.rusty_boi.flags() as u16;
+        values
+    }
+
+    fn encode_register(index: usize, value: u16) -> String {
+        match index {
+            10 => format!("{:02x}", value as u8),
+            _ => hex_encode(&value.to_le_bytes()),
+        }
+    }
+
+    fn set_register_value(&mut self, index: usize, value: u16) {
+        match index {
+            0..=7 => self.rusty_boi.set_register(index as u8, value),
+            8 => self.rusty_boi.set_pc(value),
+            9 => self.rusty_boi.set_sp(value),
+            _ => self.rusty_boi.set_flags(value as u8),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        self.register_values()
+            .iter()
+            .enumerate()
+            .map(|(index, value)| Self::encode_register(index, *value))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let bytes = match hex_decode(data) {
+            Some(bytes) if bytes.len() >= 21 => bytes,
+            _ => return "E01".to_string(),
+        };
+        for index in 0..10 {
+            let value = u16::from_le_bytes([bytes[index * 2], bytes[index * 2 + 1]]);
+            self.set_register_value(index, value);
+        }
+        self.set_register_value(10, bytes[20] as u16);
+        "OK".to_string()
+    }
+
+    fn read_register(&self, data: &str) -> String {
+        match usize::from_str_radix(data, 16) {
+            Ok(index) if index < REGISTER_COUNT => {
+                Self::encode_register(index, self.register_values()[index])
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, data: &str) -> String {
+        let (index, value) = match data.split_once('=') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let index = match usize::from_str_radix(index, 16) {
+            Ok(index) if index < REGISTER_COUNT => index,
+            _ => return "E01".to_string(),
+        };
+        let value = match hex_decode(value) {
+            Some(bytes) if bytes.len() == 1 => bytes[0] as u16,
+            Some(bytes) if bytes.len() == 2 => u16::from_le_bytes([bytes[0], bytes[1]]),
+            _ => return "E01".to_string(),
+        };
+        self.set_register_value(index, value);
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, data: &str) -> String {
+        let (address, length) = match parse_address_length(data) {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let mut bytes = Vec::new();
+        for offset in 0..length {
+            match address.checked_add(offset) {
+                Some(address) if address < PROG_MEM_OFFSET => {
+                    bytes.push(self.rusty_boi.memory[address as usize])
+                }
+                Some(address) if address < PROG_MEM_OFFSET * 2 => {
+                    bytes.push(self.rusty_boi.prog_mem()[(address - PROG_MEM_OFFSET) as usize])
+                }
+                _ => break,
+            }
+        }
+        match bytes.is_empty() && length > 0 {
+            true => "E01".to_string(),
+            false => hex_encode(&bytes),
+        }
+    }
+
+    fn write_memory(&mut self, data: &str) -> String {
+        let (header, values) = match data.split_once(':') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let (address, length) = match parse_address_length(header) {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let bytes = match hex_decode(values) {
+            Some(bytes) if bytes.len() == length as usize => bytes,
+            _ => return "E01".to_string(),
+        };
+        for (offset, byte) in bytes.iter().enumerate() {
+            match address.checked_add(offset as u32) {
+                Some(address) if address < PROG_MEM_OFFSET => {
+                    self.rusty_boi.memory[address as usize] = *byte
+                }
+                Some(address) if address < PROG_MEM_OFFSET * 2 => self
+                    .rusty_boi
+                    .write_prog_byte((address - PROG_MEM_OFFSET) as u16, *byte),
+                _ => return "E01".to_string(),
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn resume_at(&mut self, data: &str) {
+        if let Ok(address) = u32::from_str_radix(data, 16) {
+            self.rusty_boi.set_pc((address & 0xFFFF) as u16);
+        }
+    }
+
+    fn resume(&mut self) -> Result<String, String> {
+        loop {
+            if let Some(reply) = self.halt_reply() {
+                return Ok(reply);
+            }
+            self.rusty_boi.run();
+            if let Some(stop) = self.rusty_boi.take_stop() {
+                return Ok(self.stop_reply(&stop));
+            }
+            if self.interrupt_pending()? {
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    fn single_step(&mut self) -> String {
+        self.rusty_boi.step();
+        if let Some(reply) = self.halt_reply() {
+            return reply;
+        }
+        match self.rusty_boi.take_stop() {
+            Some(stop) => self.stop_reply(&stop),
+            None => "S05".to_string(),
+        }
+    }
+
+    // A halted program has exited, running past the end is reported like a crash, as SIGSEGV
+    fn halt_reply(&self) -> Option<String> {
+        match self.rusty_boi.halted()? {
+            Halt::Exit(code) => Some(format!("W{:02x}", code.min(255))),
+            Halt::PastEnd(_) => Some("X0b".to_string()),
+        }
+    }
+
+    fn stop_reply(&self, stop: &Stop) -> String {
+        match stop.reason {
+            StopReason::Breakpoint(_) | StopReason::ConditionError(_, _) => {
+                "T05swbreak:;".to_string()
+            }
+            StopReason::Watchpoint(index, access) => {
+                let kind = match self.rusty_boi.debugger().map(|d| d.watchpoints[index].kind) {
+                    Some(WatchKind::Read) => "rwatch",
+                    Some(WatchKind::Access) => "awatch",
+                    _ => "watch",
+                };
+                format!("T05{}:{:x};", kind, access.address)
+            }
+        }
+    }
+
+    // Z and z packets, type 0 and 1 are breakpoints and 2 to 4 write, read and access watchpoints
+    fn set_point(&mut self, data: &str, insert: bool) -> String {
+        let parts: Vec<&str> = data.split(',').collect();
+        if parts.len() < 3 {
+            return "E01".to_string();
+        }
+        let address = match u32::from_str_radix(parts[1], 16) {
+            Ok(address) => address,
+            Err(_) => return "E01".to_string(),
+        };
+        let length = u32::from_str_radix(parts[2], 16).unwrap_or(1).max(1);
+        let debugger = match self.rusty_boi.debugger_mut() {
+            Some(debugger) => debugger,
+            None => return "E01".to_string(),
+        };
+
+        let kind = match parts[0] {
+            "0" | "1" => {
+                let address = (address & 0xFFFF) as u16;
+                match insert {
+                    true => debugger.add_breakpoint_at(address),
+                    false => debugger.remove_breakpoint_at(address),
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        // Watchpoints only cover RAM
+        let end = match address.checked_add(length - 1) {
+            Some(end) if end < PROG_MEM_OFFSET => end,
+            _ => return "E01".to_string(),
+        };
+        match insert {
+            true => debugger.watchpoints.push(crate::debugger::Watchpoint {
+                start: address as u16,
+                end: end as u16,
+                kind,
+            }),
+            false => debugger.remove_watchpoint(address as u16, end as u16, kind),
+        }
+        "OK".to_string()
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn parse_address_length(data: &str) -> Option<(u32, u32)> {
+    let (address, length) = data.split_once(',')?;
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        u32::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+// Serves a qXfer read of "offset,length" from a document
+fn xfer(document: &str, args: &str) -> String {
+    let (offset, length) = match parse_address_length(args) {
+        Some(parts) => (parts.0 as usize, parts.1 as usize),
+        None => return "E01".to_string(),
+    };
+    if offset >= document.len() {
+        return "l".to_string();
+    }
+    let end = (offset + length).min(document.len());
+    let marker = match end == document.len() {
+        true => 'l',
+        false => 'm',
+    };
+    format!("{}{}", marker, &document[offset..end])
+}
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HistoryMode {
+    // One entry per executed instruction, allows stepping back
+    Instruction,
+    // One entry per frame, only the first write to each address is kept
+    Frame,
+}
+
+// Machine state from before an entry started, memory is kept separately as a write journal
+#[derive(Clone, Copy)]
+pub struct Snapshot {
+    pub pc: u16,
+    pub sp: u16,
+    pub compare: bool,
+    pub carry: bool,
+    pub registers: [[u8; 2]; 8],
+    pub ret_len: usize,
+    pub cycles: u64,
+    pub frame_cycles: u64,
+}
+
+struct Entry {
+    state: Snapshot,
+    frame_start: bool,
+    // Lowest return stack depth reached and the addresses popped to get there, top first
+    ret_low: usize,
+    ret_popped: Vec<u16>,
+    memory_writes: usize,
+    serial: u64,
+}
+
+pub struct History {
+    mode: HistoryMode,
+    capacity: usize,
+    entries: VecDeque<Entry>,
+    memory: VecDeque<(u16, u8)>,
+    written: Vec<u64>,
+    serial: u64,
+    frame_pending: bool,
+}
+
+// An entry taken off the history, applied back onto the VM by RustyBoi
+pub struct Undo {
+    pub state: Snapshot,
+    pub ret_low: usize,
+    pub ret_popped: Vec<u16>,
+    pub memory: Vec<(u16, u8)>,
+    pub frame_start: bool,
+}
+
+impl History {
+    // Capacity is counted in instructions or in frames depending on the mode
+    pub fn new(mode: HistoryMode, capacity: usize) -> Self {
+        Self {
+            mode,
+            capacity,
+            entries: VecDeque::new(),
+            memory: VecDeque::new(),
+            written: match mode {
+                HistoryMode::Frame => vec![0; 65536],
+                HistoryMode::Instruction => Vec::new(),
+            },
+            serial: 0,
+            frame_pending: false,
+        }
+    }
+
+    pub fn mode(&self) -> HistoryMode {
+        self.mode
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn begin_frame(&mut self, state: Snapshot) {
+        match self.mode {
+            HistoryMode::Frame => self.push_entry(state, true),
+            HistoryMode::Instruction => self.frame_pending = true,
+        }
+    }
+
+    pub fn begin_step(&mut self, state: Snapshot) {
+        if let HistoryMode::Instruction = self.mode {
+            let frame_start = self.frame_pending;
+            self.frame_pending = false;
+            self.push_entry(state, frame_start);
+        }
+    }
+
+    fn push_entry(&mut self, state: Snapshot, frame_start: bool) {
+        self.serial += 1;
+        self.entries.push_back(Entry {
+            state,
+            frame_start,
+            ret_low: state.ret_len,
+            ret_popped: Vec::new(),
+            memory_writes: 0,
+            serial: self.serial,
+        });
+
+        while self.entries.len() > self.capacity {
+            let dropped = self.entries.pop_front().unwrap();
+            self.memory.drain(0..dropped.memory_writes);
+        }
+    }
+
+    pub fn record_memory(&mut self, address: u16, old: u8) {
+        let entry = match self.entries.back_mut() {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if let HistoryMode::Frame = self.mode {
+            if self.written[address as usize] == entry.serial {
+                return;
+            }
+            self.written[address as usize] = entry.serial;
+        }
+
+        self.memory.push_back((address, old));
+        entry.memory_writes += 1;
+    }
+
+    pub fn record_ret_pop(&mut self, address: u16, new_len: usize) {
+        if let Some(entry) = self.entries.back_mut() {
+            if new_len < entry.ret_low {
+                entry.ret_popped.push(address);
+                entry.ret_low = new_len;
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Undo> {
+        let entry = self.entries.pop_back()?;
+        let start = self.memory.len() - entry.memory_writes;
+        let mut memory: Vec<(u16, u8)> = self.memory.drain(start..).collect();
+        memory.reverse();
+        Some(Undo {
+            state: entry.state,
+            ret_low: entry.ret_low,
+            ret_popped: entry.ret_popped,
+            memory,
+            frame_start: entry.frame_start,
+        })
+    }
+}
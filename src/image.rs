@@ -0,0 +1,311 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+use crate::rusty_boi::{fnv1a, FNV_OFFSET};
+
+const IMAGE_MAGIC: &[u8; 4] = b"RBIM";
+const IMAGE_VERSION: u8 = 1;
+const CHECKSUM_SIZE: usize = 8;
+const MEMORY_SIZE: usize = 65536;
+
+// Features an image can ask for, loading fails when the VM doesn't know one of them
+pub const SUPPORTED_FEATURES: [&str; 1] = ["halt"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Program,
+    Ram,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+// A program file, all numbers are little endian:
+// magic "RBIM", version byte, entry word, title and author as a length word and UTF-8 text,
+// a feature count byte followed by each feature as a length byte and text, a segment count
+// word followed by each segment as a kind byte, load address word, four byte length and data,
+// and an FNV-1a hash of everything before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub title: String,
+    pub author: String,
+    pub entry: u16,
+    pub features: Vec<String>,
+    pub segments: Vec<Segment>,
+}
+
+// Walks the bytes of an image or object file, every read names what it was reading for the error
+pub struct BinaryReader<'a> {
+    pub bytes: &'a [u8],
+    pub index: usize,
+}
+
+impl BinaryReader<'_> {
+    pub fn take(&mut self, count: usize, what: &str) -> Result<&[u8], String> {
+        if self.index + count > self.bytes.len() {
+            return Err(format!("File ends in the middle of the {}", what));
+        }
+        let bytes = &self.bytes[self.index..self.index + count];
+        self.index += count;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self, what: &str) -> Result<u8, String> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    pub fn word(&mut self, what: &str) -> Result<u16, String> {
+        let bytes = self.take(2, what)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn long(&mut self, what: &str) -> Result<u32, String> {
+        let bytes = self.take(4, what)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn text(&mut self, length: usize, what: &str) -> Result<String, String> {
+        let bytes = self.take(length, what)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| format!("The {} is not valid UTF-8", what))
+    }
+}
+
+impl Image {
+    // A plain program loaded at address 0 that also starts there
+    pub fn from_program(program: Vec<u8>) -> Self {
+        Self {
+            title: String::new(),
+            author: String::new(),
+            entry: 0,
+            features: Vec::new(),
+            segments: vec![Segment {
+                kind: SegmentKind::Program,
+                address: 0,
+                data: program,
+            }],
+        }
+    }
+
+    // Program memory as it looks after loading, up to the end of the last program segment
+    pub fn program_memory(&self) -> Vec<u8> {
+        let mut memory = vec![0; self.program_size()];
+        for segment in self.segments.iter() {
+            if segment.kind == SegmentKind::Program {
+                let start = segment.address as usize;
+                memory[start..start + segment.data.len()].copy_from_slice(&segment.data);
+            }
+        }
+        memory
+    }
+
+    pub fn program_size(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| segment.kind == SegmentKind::Program)
+            .map(|segment| segment.address as usize + segment.data.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Address of the last program byte
+    pub fn program_end(&self) -> u16 {
+        self.program_size().saturating_sub(1) as u16
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = IMAGE_MAGIC.to_vec();
+        buffer.push(IMAGE_VERSION);
+        buffer.extend_from_slice(&self.entry.to_le_bytes());
+        for text in [&self.title, &self.author] {
+            buffer.extend_from_slice(&(text.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(text.as_bytes());
+        }
+        buffer.push(self.features.len() as u8);
+        for feature in self.features.iter() {
+            buffer.push(feature.len() as u8);
+            buffer.extend_from_slice(feature.as_bytes());
+        }
+        buffer.extend_from_slice(&(self.segments.len() as u16).to_le_bytes());
+        for segment in self.segments.iter() {
+            buffer.push(match segment.kind {
+                SegmentKind::Program => 0,
+                SegmentKind::Ram => 1,
+            });
+            buffer.extend_from_slice(&segment.address.to_le_bytes());
+            buffer.extend_from_slice(&(segment.data.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&segment.data);
+        }
+        let checksum = fnv1a(FNV_OFFSET, &buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < IMAGE_MAGIC.len() + 1 + CHECKSUM_SIZE || &bytes[0..4] != IMAGE_MAGIC {
+            return Err("Not a Rusty Boi program image".to_string());
+        }
+        if bytes[4] != IMAGE_VERSION {
+            return Err(format!(
+                "Unsupported image version {}, expected version {}",
+                bytes[4], IMAGE_VERSION
+            ));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+        if fnv1a(FNV_OFFSET, body).to_le_bytes() != checksum {
+            return Err("Image checksum doesn't match, the file is damaged".to_string());
+        }
+
+        let mut reader = BinaryReader {
+            bytes: body,
+            index: 5,
+        };
+        let entry = reader.word("entry point")?;
+        let title_length = reader.word("title")? as usize;
+        let title = reader.text(title_length, "title")?;
+        let author_length = reader.word("author")? as usize;
+        let author = reader.text(author_length, "author")?;
+
+        let mut features = Vec::new();
+        for _ in 0..reader.byte("feature list")? {
+            let length = reader.byte("feature list")? as usize;
+            features.push(reader.text(length, "feature list")?);
+        }
+
+        let mut segments = Vec::new();
+        for index in 0..reader.word("segment list")? {
+            let what = format!("segment {}", index);
+            let kind = match reader.byte(&what)? {
+                0 => SegmentKind::Program,
+                1 => SegmentKind::Ram,
+                kind => return Err(format!("Uknown kind {} for segment {}", kind, index)),
+            };
+            let address = reader.word(&what)?;
+            let length = reader.long(&what)? as usize;
+            let data = reader.take(length, &what)?.to_vec();
+            segments.push(Segment {
+                kind,
+                address,
+                data,
+            });
+        }
+        if reader.index != body.len() {
+            return Err("Unexpected data after the last segment of the image".to_string());
+        }
+
+        let image = Self {
+            title,
+            author,
+            entry,
+            features,
+            segments,
+        };
+        image.validate()?;
+        Ok(image)
+    }
+
+    // Checks what a damaged or hand made image could get wrong
+    pub fn validate(&self) -> Result<(), String> {
+        let unsupported: Vec<&str> = self
+            .features
+            .iter()
+            .map(|feature| feature.as_str())
+            .filter(|feature| !SUPPORTED_FEATURES.contains(feature))
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(format!(
+                "Image needs VM features this VM doesn't have: {}",
+                unsupported.join(", ")
+            ));
+        }
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            let end = segment.address as usize + segment.data.len();
+            if end > MEMORY_SIZE {
+                return Err(format!(
+                    "Segment {} at 0x{:04X} with {} bytes runs past the end of memory",
+                    index,
+                    segment.address,
+                    segment.data.len()
+                ));
+            }
+            for (other_index, other) in self.segments.iter().enumerate().skip(index + 1) {
+                let other_end = other.address as usize + other.data.len();
+                if other.kind == segment.kind
+                    && (other.address as usize) < end
+                    && (segment.address as usize) < other_end
+                {
+                    return Err(format!(
+                        "Segments {} and {} overlap at 0x{:04X}",
+                        index,
+                        other_index,
+                        segment.address.max(other.address)
+                    ));
+                }
+            }
+        }
+
+        // A source with no code still builds an image, it just halts as soon as it starts
+        let mut program = self
+            .segments
+            .iter()
+            .filter(|segment| segment.kind == SegmentKind::Program && !segment.data.is_empty())
+            .peekable();
+        let has_program = program.peek().is_some();
+        let entry_in_program = program.any(|segment| {
+            segment.address <= self.entry
+                && (self.entry as usize) < segment.address as usize + segment.data.len()
+        });
+        if has_program && !entry_in_program {
+            return Err(format!(
+                "Entry point 0x{:04X} is outside every program segment",
+                self.entry
+            ));
+        }
+        Ok(())
+    }
+
+    // Reads a program file, raw files are plain program memory images from before the
+    // container format
+    pub fn load(path: &str, raw: bool) -> Result<Self, String> {
+        let mut buffer = Vec::new();
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error opening program {} \n{}", path, error)),
+        };
+        if let Err(error) = file.read_to_end(&mut buffer) {
+            return Err(format!("Error reading program {} \n{}", path, error));
+        }
+
+        if raw {
+            if buffer.len() > MEMORY_SIZE {
+                return Err(format!(
+                    "Raw program {} is bigger than program memory",
+                    path
+                ));
+            }
+            return Ok(Self::from_program(buffer));
+        }
+        if !buffer.starts_with(IMAGE_MAGIC) {
+            return Err(format!(
+                "{} is not a Rusty Boi program image, add --raw to load a raw program",
+                path
+            ));
+        }
+        Self::from_bytes(&buffer).map_err(|e| format!("Error loading {} \n{}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut file = match File::create(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error creating program {} \n{}", path, error)),
+        };
+        file.write_all(&self.to_bytes())
+            .map_err(|e| format!("Error writing program {} \n{}", path, e))
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read, Write},
+};
+
+// Bodies larger than this are skipped instead of read into memory
+const MAX_MESSAGE_LENGTH: usize = 1 << 24;
+
+// Just enough JSON for the editor protocols, objects keep their keys in insertion order
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(field, _)| field == key)
+                .map(|(_, value)| value)
+                .unwrap_or(&Json::Null),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(string) => Some(string.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(num) => Some(*num as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items.as_slice(),
+            _ => &[],
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = JsonParser {
+            chars: text.chars().collect(),
+            index: 0,
+        };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        match parser.index == parser.chars.len() {
+            true => Ok(value),
+            false => Err("Unexpected trailing input in JSON".to_string()),
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(string: &str) -> Self {
+        Json::String(string.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(string: String) -> Self {
+        Json::String(string)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(num: i64) -> Self {
+        Json::Number(num as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(num: usize) -> Self {
+        Json::Number(num as f64)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(num) if num.fract() == 0.0 && num.abs() < 1e15 => {
+                write!(f, "{}", *num as i64)
+            }
+            Json::Number(num) => write!(f, "{}", num),
+            Json::String(string) => write_string(f, string),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    index: usize,
+}
+
+impl JsonParser {
+    fn skip_whitespace(&mut self) {
+        while self.index < self.chars.len() && self.chars[self.index].is_whitespace() {
+            self.index += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.get(self.index) {
+            Some(found) if *found == c => {
+                self.index += 1;
+                Ok(())
+            }
+            _ => Err(format!("Expected {} in JSON at {}", c, self.index)),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        let c = match self.chars.get(self.index) {
+            Some(c) => *c,
+            None => return Err("Unexpected end of JSON".to_string()),
+        };
+        match c {
+            '{' => self.parse_object(),
+            '[' => self.parse_array(),
+            '"' => Ok(Json::String(self.parse_string()?)),
+            't' => self.parse_word("true", Json::Bool(true)),
+            'f' => self.parse_word("false", Json::Bool(false)),
+            'n' => self.parse_word("null", Json::Null),
+            _ => self.parse_number(),
+        }
+    }
+
+    fn parse_word(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        let end = self.index + word.len();
+        if end <= self.chars.len() && self.chars[self.index..end].iter().copied().eq(word.chars()) {
+            self.index = end;
+            return Ok(value);
+        }
+        Err(format!("Unexpected character in JSON at {}", self.index))
+    }
+
+    fn parse_number(&mut self) -> Result<Json, String> {
+        let start = self.index;
+        while self.index < self.chars.len()
+            && (self.chars[self.index].is_ascii_digit() || "+-.eE".contains(self.chars[self.index]))
+        {
+            self.index += 1;
+        }
+        let number: String = self.chars[start..self.index].iter().collect();
+        match number.parse::<f64>() {
+            Ok(num) => Ok(Json::Number(num)),
+            Err(_) => Err(format!("Malformated number {} in JSON", number)),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            let c = match self.chars.get(self.index) {
+                Some(c) => *c,
+                None => return Err("Unterminated string in JSON".to_string()),
+            };
+            self.index += 1;
+            match c {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escape = match self.chars.get(self.index) {
+                        Some(escape) => *escape,
+                        None => return Err("Unterminated string in JSON".to_string()),
+                    };
+                    self.index += 1;
+                    match escape {
+                        'n' => string.push('\n'),
+                        'r' => string.push('\r'),
+                        't' => string.push('\t'),
+                        'b' => string.push('\u{8}'),
+                        'f' => string.push('\u{c}'),
+                        'u' => {
+                            let code = self.parse_hex4()?;
+                            // Surrogate pairs come as two escapes in a row
+                            let code = if (0xD800..0xDC00).contains(&code)
+                                && self.chars.get(self.index) == Some(&'\\')
+                                && self.chars.get(self.index + 1) == Some(&'u')
+                            {
+                                self.index += 2;
+                                let low = self.parse_hex4()?;
+                                0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00))
+                            } else {
+                                code
+                            };
+                            string.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        escape => string.push(escape),
+                    }
+                }
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let end = self.index + 4;
+        if end > self.chars.len() {
+            return Err("Unterminated escape in JSON".to_string());
+        }
+        let hex: String = self.chars[self.index..end].iter().collect();
+        self.index = end;
+        u32::from_str_radix(hex.as_str(), 16).map_err(|_| format!("Malformated escape \\u{}", hex))
+    }
+
+    fn parse_array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.index) == Some(&']') {
+            self.index += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.chars.get(self.index) {
+                Some(',') => self.index += 1,
+                Some(']') => {
+                    self.index += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(format!("Expected , or ] in JSON at {}", self.index)),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.index) == Some(&'}') {
+            self.index += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.expect(':')?;
+            fields.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.chars.get(self.index) {
+                Some(',') => self.index += 1,
+                Some('}') => {
+                    self.index += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(format!("Expected , or }} in JSON at {}", self.index)),
+            }
+        }
+    }
+}
+
+// Reads one "Content-Length" framed message as used by DAP and LSP, None at the end of the stream.
+// A message that is too large or isn't JSON is skipped and comes back as the inner error, the
+// stream can still be read after it
+pub fn read_message(reader: &mut dyn BufRead) -> Result<Option<Result<Json, String>>, String> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        match reader.read_line(&mut header) {
+            Ok(0) => return Ok(None),
+            Ok(_) => (),
+            Err(error) => return Err(format!("Error reading message header \n{}", error)),
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE_LENGTH {
+        if let Err(error) = io::copy(&mut reader.take(length as u64), &mut io::sink()) {
+            return Err(format!("Error reading message body \n{}", error));
+        }
+        return Ok(Some(Err(format!(
+            "Message of {} bytes is larger than the {} byte limit",
+            length, MAX_MESSAGE_LENGTH
+        ))));
+    }
+    let mut body = vec![0u8; length];
+    if let Err(error) = reader.read_exact(&mut body) {
+        return Err(format!("Error reading message body \n{}", error));
+    }
+    Ok(Some(Json::parse(String::from_utf8_lossy(&body).as_ref())))
+}
+
+pub fn write_message(writer: &mut dyn Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| writer.flush())
+        .map_err(|e| format!("Error writing message \n{}", e))
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use crate::{
+    image::{Image, Segment, SegmentKind},
+    object::Object,
+};
+
+// Where one object ended up in the linked program
+pub struct Section {
+    pub path: String,
+    pub start: u16,
+    pub size: u16,
+}
+
+pub struct Linked {
+    pub image: Image,
+    pub sections: Vec<Section>,
+    pub symbols: HashMap<String, u16>,
+}
+
+impl Linked {
+    // The sections in address order with the symbols each one exports
+    pub fn map(&self) -> String {
+        let mut map = String::new();
+        for section in self.sections.iter() {
+            // A section can end right at the top of program memory
+            let end = section.start as u32 + section.size as u32;
+            map.push_str(&format!(
+                "0x{:04X}-0x{:04X} {:>6} bytes {}\n",
+                section.start,
+                end.saturating_sub(1),
+                section.size,
+                section.path
+            ));
+            let mut symbols: Vec<(&String, &u16)> = self
+                .symbols
+                .iter()
+                .filter(|(_, address)| **address >= section.start && (**address as u32) < end)
+                .collect();
+            symbols.sort_by_key(|(name, address)| (**address, name.to_string()));
+            for (name, address) in symbols {
+                map.push_str(&format!("    0x{:04X} {}\n", address, name));
+            }
+        }
+        map
+    }
+}
+
+// Places the objects one after another from address 0 in the order given, so the first
+// object holds the code that runs first, then fixes every address that points into code
+pub fn link(objects: &[(String, Object)]) -> Result<Linked, String> {
+    let mut errors = Vec::new();
+    let mut sections = Vec::new();
+    let mut address = 0u32;
+    for (path, object) in objects.iter() {
+        let alignment = object.alignment.max(1) as u32;
+        address = address.div_ceil(alignment) * alignment;
+        if address + object.code.len() as u32 > 0x10000 {
+            return Err(format!("{} doesn't fit in program memory", path));
+        }
+        sections.push(Section {
+            path: path.clone(),
+            start: address as u16,
+            size: object.code.len() as u16,
+        });
+        address += object.code.len() as u32;
+    }
+
+    // Every exported label becomes a global symbol
+    let mut symbols: HashMap<String, u16> = HashMap::new();
+    let mut exporters: HashMap<String, &str> = HashMap::new();
+    for ((path, object), section) in objects.iter().zip(sections.iter()) {
+        for (name, offset) in object.exports.iter() {
+            match exporters.get(name) {
+                Some(other) => errors.push(format!(
+                    "Symbol {} is exported by both {} and {}",
+                    name, other, path
+                )),
+                None => match section.start.checked_add(*offset) {
+                    Some(address) => {
+                        exporters.insert(name.clone(), path);
+                        symbols.insert(name.clone(), address);
+                    }
+                    // A label after the last byte of program memory
+                    None => errors.push(format!(
+                        "Symbol {} of {} is past the end of program memory",
+                        name, path
+                    )),
+                },
+            }
+        }
+    }
+
+    let mut segments = Vec::new();
+    for ((_, object), section) in objects.iter().zip(sections.iter()) {
+        let mut code = object.code.clone();
+        for relocation in object.relocations.iter() {
+            let index = relocation.offset as usize;
+            let word = u16::from_le_bytes([code[index], code[index + 1]]);
+            let fixed = match &relocation.symbol {
+                None => word.wrapping_add(section.start),
+                Some(symbol) => match symbols.get(symbol) {
+                    Some(address) => *address,
+                    None => {
+                        errors.push(format!(
+                            "Undefined symbol {} used in {} on line {}",
+                            symbol, object.source, relocation.line
+                        ));
+                        continue;
+                    }
+                },
+            };
+            code[index..index + 2].copy_from_slice(&fixed.to_le_bytes());
+        }
+        if code.is_empty() {
+            continue;
+        }
+        segments.push(Segment {
+            kind: SegmentKind::Program,
+            address: section.start,
+            data: code,
+        });
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    let mut image = Image::from_program(Vec::new());
+    image.segments = segments;
+    for (_, object) in objects.iter() {
+        for feature in object.features.iter() {
+            if !image.features.contains(feature) {
+                image.features.push(feature.clone());
+            }
+        }
+    }
+    Ok(Linked {
+        image,
+        sections,
+        symbols,
+    })
+}
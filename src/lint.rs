@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use crate::{
+    assembler::{is_anonymous_reference, qualify_label, DebugInfo, SourceLine, MNEMONICS},
+    cfg::ControlFlowGraph,
+    disassembler::{Instruction, ADDRESS_REGISTER_MASK},
+    opcodes::Opcode,
+};
+
+pub struct Warning {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "warning: {} on line {}", self.message, line),
+            None => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+// The assembled program decoded at every address the assembler emitted an instruction
+struct Program<'a> {
+    instructions: BTreeMap<u16, Instruction>,
+    debug_info: &'a DebugInfo,
+    warnings: Vec<Warning>,
+}
+
+pub fn lint(program: &[u8], debug_info: &DebugInfo) -> Vec<Warning> {
+    let instructions = debug_info
+        .lines
+        .iter()
+        .map(|(address, _)| (*address, Instruction::decode(program, *address)))
+        .collect();
+    let mut lint = Program {
+        instructions,
+        debug_info,
+        warnings: Vec::new(),
+    };
+
+    lint.check_source();
+    let cfg = ControlFlowGraph::build(program, debug_info);
+    let reachable = lint.reachable(&cfg);
+    lint.check_unreachable(&reachable);
+    let routines: Vec<u16> = cfg
+        .routines
+        .iter()
+        .map(|routine| routine.entry)
+        .filter(|entry| *entry != 0)
+        .collect();
+    for entry in routines.iter() {
+        lint.check_routine(*entry, true);
+    }
+    if lint.instructions.contains_key(&0) {
+        lint.check_routine(0, false);
+    }
+    lint.check_fall_through(&reachable, &routines);
+    lint.check_register_reads(&reachable);
+    lint.check_address_register(&routines);
+
+    lint.warnings.sort_by_key(|warning| warning.line);
+    lint.warnings
+}
+
+impl Program<'_> {
+    fn warn(&mut self, line: Option<usize>, message: String) {
+        self.warnings.push(Warning { line, message });
+    }
+
+    fn line_of(&self, address: u16) -> Option<usize> {
+        self.debug_info.line_of(address)
+    }
+
+    fn name(&self, address: u16) -> String {
+        self.debug_info.describe(address)
+    }
+
+    // Checks that only need the source text, unused names and text the assembler ignores
+    fn check_source(&mut self) {
+        let mut used = HashSet::new();
+        let mut labels = Vec::new();
+        let mut constants = Vec::new();
+        let mut variables = Vec::new();
+        let mut scope = String::new();
+        for (index, text) in self.debug_info.source.lines().enumerate() {
+            let words = SourceLine::parse(text).words();
+            // Conditions still use the constants they name
+            let condition = matches!(words.first(), Some(&"if" | &"ifdef" | &"ifndef"));
+            if self.debug_info.inactive_lines.contains(&(index + 1)) && !condition {
+                continue;
+            }
+            match words.first() {
+                Some(word) if word.contains(':') => {
+                    let name = word.trim_end_matches(':');
+                    if !name.starts_with('.') && !is_anonymous_reference(name) {
+                        scope = name.to_string();
+                    }
+                    labels.push((qualify_label(&scope, name), index + 1));
+                    if words.len() > 1 {
+                        self.warn(
+                            Some(index + 1),
+                            format!("{} after label {} is ignored", words[1..].join(" "), name),
+                        );
+                    }
+                }
+                Some(&"const") if words.len() > 1 => {
+                    constants.push((words[1].to_string(), index + 1));
+                }
+                Some(&"var") if words.len() > 1 => {
+                    variables.push((words[1].to_string(), index + 1));
+                }
+                Some(_) => used.extend(words[1..].iter().map(|word| qualify_label(&scope, word))),
+                None => (),
+            }
+        }
+
+        for (name, line) in labels {
+            if MNEMONICS.iter().any(|(mnemonic, _)| *mnemonic == name) {
+                self.warn(
+                    Some(line),
+                    format!(
+                        "Label {} is named like an opcode, is the colon a typo",
+                        name
+                    ),
+                );
+            } else if !used.contains(&name) && name != "main" && !is_anonymous_reference(&name) {
+                self.warn(Some(line), format!("Unused label {}", name));
+            }
+        }
+        for (name, line) in constants {
+            if !used.contains(&name) {
+                self.warn(Some(line), format!("Unused constant {}", name));
+            }
+        }
+        // Using a field of a struct var counts as using the var
+        for (name, line) in variables {
+            let field = format!("{}.", name);
+            if !used
+                .iter()
+                .any(|word| *word == name || word.starts_with(&field))
+            {
+                self.warn(Some(line), format!("Unused variable {}", name));
+            }
+        }
+    }
+
+    // Every instruction in a block some routine reaches
+    fn reachable(&self, cfg: &ControlFlowGraph) -> HashSet<u16> {
+        cfg.routines
+            .iter()
+            .flat_map(|routine| routine.blocks.iter())
+            .flat_map(|start| cfg.blocks[start].instructions.iter())
+            .map(|instruction| instruction.address)
+            .collect()
+    }
+
+    fn check_unreachable(&mut self, reachable: &HashSet<u16>) {
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for address in self.instructions.keys() {
+            if reachable.contains(address) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((_, last)) if self.instructions[last].next_address() == *address => {
+                    *last = *address
+                }
+                _ => runs.push((*address, *address)),
+            }
+        }
+
+        for (start, end) in runs {
+            let (first, last) = (self.line_of(start), self.line_of(end));
+            let message = match first == last {
+                true => "Unreachable code".to_string(),
+                false => format!("Unreachable code through line {}", last.unwrap_or(0)),
+            };
+            self.warn(first, message);
+        }
+    }
+
+    // Walks one routine without following calls, checking that it returns and that
+    // every path leaves the stack as it found it
+    fn check_routine(&mut self, entry: u16, called: bool) {
+        let mut depths: HashMap<u16, i32> = HashMap::new();
+        let mut queue = VecDeque::from([(entry, 0)]);
+        let mut returns = false;
+        let name = match called {
+            true => self.name(entry),
+            false => "The main loop".to_string(),
+        };
+
+        while let Some((address, depth)) = queue.pop_front() {
+            let instruction = match self.instructions.get(&address) {
+                Some(instruction) => instruction.clone(),
+                None => continue,
+            };
+            let line = self.line_of(address);
+            match depths.get(&address) {
+                Some(seen) if *seen == depth => continue,
+                Some(seen) => {
+                    let message = format!(
+                        "{} reaches this line with {} and {} bytes pushed",
+                        name, seen, depth
+                    );
+                    self.warn(line, message);
+                    continue;
+                }
+                None => depths.insert(address, depth),
+            };
+
+            let depth = depth + instruction.stack_effect();
+            if depth < 0 {
+                self.warn(line, format!("{} pops more than it pushed", name));
+                continue;
+            }
+
+            // Nothing runs after a HALT, so the stack left behind doesn't matter
+            if matches!(instruction.opcode, Opcode::HALT { .. }) {
+                returns = true;
+                continue;
+            }
+            let ends_routine = match instruction.opcode {
+                Opcode::RET => true,
+                Opcode::JDRAW | Opcode::RDRAW => !called,
+                _ => false,
+            };
+            if ends_routine {
+                returns = true;
+                if depth != 0 {
+                    let message = match called {
+                        true => format!("{} returns with {} bytes still pushed", name, depth),
+                        false => {
+                            format!("{} ends the frame with {} bytes still pushed", name, depth)
+                        }
+                    };
+                    self.warn(line, message);
+                }
+                continue;
+            }
+            for successor in instruction.successors() {
+                queue.push_back((successor, depth));
+            }
+        }
+
+        if called && !returns {
+            self.warn(
+                self.line_of(entry),
+                format!("Routine {} is called but never returns", name),
+            );
+        }
+    }
+
+    fn check_fall_through(&mut self, reachable: &HashSet<u16>, routines: &[u16]) {
+        let mut falls = Vec::new();
+        for instruction in self.instructions.values() {
+            if !reachable.contains(&instruction.address) {
+                continue;
+            }
+            let next = instruction.next_address();
+            if routines.contains(&next)
+                && next != 0
+                && instruction.successors().contains(&next)
+                && instruction.target() != Some(next)
+            {
+                falls.push((instruction.address, next));
+            }
+        }
+        for (address, routine) in falls {
+            let message = format!("Code falls through into routine {}", self.name(routine));
+            self.warn(self.line_of(address), message);
+        }
+    }
+
+    fn check_register_reads(&mut self, reachable: &HashSet<u16>) {
+        let written = self
+            .instructions
+            .values()
+            .filter(|instruction| reachable.contains(&instruction.address))
+            .fold(0u16, |mask, instruction| mask | instruction.writes());
+
+        let mut reported = 0u16;
+        let mut unwritten = Vec::new();
+        for instruction in self.instructions.values() {
+            // $7 as an address gets its own check
+            let reads = match instruction.opcode {
+                Opcode::STORE { .. } | Opcode::READ { .. } => {
+                    instruction.reads() & !ADDRESS_REGISTER_MASK
+                }
+                _ => instruction.reads(),
+            };
+            let missing = reads & !written & !reported;
+            if missing == 0 || !reachable.contains(&instruction.address) {
+                continue;
+            }
+            reported |= missing;
+            unwritten.push((instruction.address, missing));
+        }
+        for (address, missing) in unwritten {
+            let registers: Vec<String> = (0..8)
+                .filter(|register| missing & (3 << (register * 2)) != 0)
+                .map(|register| format!("${}", register))
+                .collect();
+            let message = format!("{} is read but never written", registers.join(", "));
+            self.warn(self.line_of(address), message);
+        }
+    }
+
+    // A forward pass that tracks whether both bytes of $7 have certainly been written,
+    // routines start from whatever their callers had
+    fn check_address_register(&mut self, routines: &[u16]) {
+        let mut state: HashMap<u16, u16> = HashMap::new();
+        let mut queue = VecDeque::from([(0u16, 0u16)]);
+        while let Some((address, set)) = queue.pop_front() {
+            let instruction = match self.instructions.get(&address) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            // Meeting paths keep only what is set on all of them
+            let set = match state.get(&address) {
+                Some(seen) if *seen & set == *seen => continue,
+                Some(seen) => *seen & set,
+                None => set,
+            };
+            state.insert(address, set);
+
+            let after = set | instruction.writes();
+            for successor in instruction.successors() {
+                queue.push_back((successor, after));
+            }
+            if instruction.is_call() {
+                queue.extend(instruction.target().map(|target| (target, after)));
+            }
+        }
+
+        let mut missing = Vec::new();
+        for (address, instruction) in self.instructions.iter() {
+            let uses_address = matches!(
+                instruction.opcode,
+                Opcode::STORE { .. } | Opcode::READ { .. }
+            );
+            match state.get(address) {
+                Some(set)
+                    if uses_address && set & ADDRESS_REGISTER_MASK != ADDRESS_REGISTER_MASK =>
+                {
+                    missing.push((*address, instruction.opcode.mnemonic()))
+                }
+                _ => (),
+            }
+        }
+        for (address, mnemonic) in missing {
+            let in_routine = routines
+                .iter()
+                .filter(|routine| **routine <= address)
+                .max()
+                .map(|routine| format!(" in {}", self.name(*routine)))
+                .unwrap_or_default();
+            let message = format!("{} may run before $7 is set{}", mnemonic, in_routine);
+            self.warn(self.line_of(address), message);
+        }
+    }
+}
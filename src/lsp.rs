@@ -0,0 +1,592 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufReader},
+};
+
+use crate::{
+    assembler::{is_anonymous_reference, qualify_label, Assembler, DebugInfo, SourceLine},
+    json::{read_message, write_message, Json},
+};
+
+// Errors after the first are found by blanking the failing line and assembling again
+const MAX_DIAGNOSTICS: usize = 50;
+
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_CONSTANT: i64 = 14;
+
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_CONSTANT: i64 = 21;
+
+const PARSE_ERROR: i64 = -32700;
+
+const DESCRIPTIONS: [(&str, &str); 50] = [
+    ("LOAD", "Loads an immediate or constant into a register"),
+    ("MOV", "Copies the first register into the second"),
+    ("STORE", "Writes the register to the address in $7"),
+    ("READ", "Reads the address in $7 into the register"),
+    ("PUSH", "Pushes the register onto the stack"),
+    ("POP", "Pops the stack into the register"),
+    ("EQ", "Sets the compare bit when the registers are equal"),
+    ("NEQ", "Sets the compare bit when the registers differ"),
+    (
+        "LES",
+        "Sets the compare bit when the first register is less, unsigned",
+    ),
+    (
+        "LESi",
+        "Sets the compare bit when the first register is less, signed",
+    ),
+    ("ADD", "Adds the first two registers into the third"),
+    ("ADDi", "Signed ADD"),
+    (
+        "SUB",
+        "Subtracts the second register from the first into the third",
+    ),
+    ("SUBi", "Signed SUB"),
+    ("MUL", "Multiplies the first two registers into the third"),
+    ("MULi", "Signed MUL"),
+    (
+        "DIV",
+        "Divides the first register by the second into the third",
+    ),
+    ("DIVi", "Signed DIV"),
+    (
+        "MOD",
+        "Remainder of the first register by the second into the third",
+    ),
+    ("MODi", "Signed MOD"),
+    (
+        "SHR",
+        "Shifts the first register right by the second into the third",
+    ),
+    (
+        "SHL",
+        "Shifts the first register left by the second into the third",
+    ),
+    ("JMP", "Jumps to the address"),
+    ("CJMP", "Jumps when the compare bit is 1"),
+    ("NJMP", "Jumps when the compare bit is 0"),
+    ("CALL", "Calls the routine at the address"),
+    ("CCALL", "Calls when the compare bit is 1"),
+    ("NCALL", "Calls when the compare bit is 0"),
+    ("RET", "Returns to the most recent return address"),
+    ("DRAW", "Redraws the window and carries on"),
+    ("RDRAW", "Redraws the window and restarts at 0"),
+    ("JDRAW", "Redraws the window and jumps to the address"),
+    ("INC", "Adds 1 to the register"),
+    ("DEC", "Subtracts 1 from the register"),
+    ("NEG", "Negates the register"),
+    ("NOT", "Flips every bit of the register"),
+    ("NOP", "Does nothing for a cycle"),
+    (
+        "HALT",
+        "Stops the program with the register as its exit code",
+    ),
+    ("CLR", "Sets the register to 0, expands to LOAD"),
+    (
+        "BEQ",
+        "Jumps when the registers are equal, expands to NEQ and NJMP",
+    ),
+    (
+        "BNE",
+        "Jumps when the registers differ, expands to NEQ and CJMP",
+    ),
+    (
+        "BLT",
+        "Jumps when the first register is less, expands to LES and CJMP",
+    ),
+    ("BLTi", "Signed BLT"),
+    (
+        "BGE",
+        "Jumps when the first register isn't less, expands to LES and NJMP",
+    ),
+    ("BGEi", "Signed BGE"),
+    (
+        "BGT",
+        "Jumps when the first register is greater, expands to LES and CJMP",
+    ),
+    ("BGTi", "Signed BGT"),
+    (
+        "BLE",
+        "Jumps when the first register isn't greater, expands to LES and NJMP",
+    ),
+    ("BLEi", "Signed BLE"),
+    ("const", "Declares a constant, const NAME value"),
+];
+
+// A space separated word of the source, lines and columns count from 0 like LSP positions
+struct Word {
+    line: usize,
+    start: usize,
+    end: usize,
+    text: String,
+    // The word without a label colon, local labels get the name of their global label
+    name: String,
+    // Position of the word on its line, 0 is the mnemonic
+    index: usize,
+}
+
+struct Analysis {
+    words: Vec<Word>,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, usize>,
+    debug_info: DebugInfo,
+    program: Vec<u8>,
+    diagnostics: Vec<(usize, String)>,
+}
+
+fn split_words(source: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut scope = String::new();
+    for (line, text) in source.lines().enumerate() {
+        for (index, token) in SourceLine::parse(text).tokens.iter().enumerate() {
+            let name = token.text.strip_suffix(':').unwrap_or(token.text);
+            if index == 0 && token.text.ends_with(':') && !name.starts_with('.') {
+                scope = name.to_string();
+            }
+            words.push(Word {
+                line,
+                start: token.column,
+                end: token.column + token.text.len(),
+                text: token.text.to_string(),
+                name: qualify_label(&scope, name),
+                index,
+            });
+        }
+    }
+    words
+}
+
+fn error_line(error: &str) -> Option<usize> {
+    let (_, rest) = error.rsplit_once("on line ")?;
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse::<usize>().ok()
+}
+
+fn assemble(path: &str, source: &str) -> (Assembler, Result<Vec<u8>, String>) {
+    let mut assembler = Assembler::new();
+    assembler.load_source(path, source);
+    let result = assembler.pre_assembly().and_then(|_| assembler.assemble());
+    (assembler, result)
+}
+
+fn analyze(path: &str, source: &str) -> Analysis {
+    let words = split_words(source);
+    let mut labels = HashMap::new();
+    let mut constants = HashMap::new();
+    for (position, word) in words.iter().enumerate() {
+        if word.index == 0 && word.text.ends_with(':') && !is_anonymous_reference(&word.name) {
+            labels.entry(word.name.clone()).or_insert(position);
+        }
+        if word.index == 1 && position > 0 && words[position - 1].text == "const" {
+            constants.entry(word.text.clone()).or_insert(position);
+        }
+    }
+
+    let (assembler, result) = assemble(path, source);
+    let debug_info = assembler.debug_info();
+    let program = result.clone().unwrap_or_default();
+
+    let mut diagnostics = Vec::new();
+    let mut lines: Vec<&str> = source.lines().collect();
+    let mut result = result;
+    while let Err(error) = result {
+        let line = error_line(&error).unwrap_or(1);
+        diagnostics.push((line.saturating_sub(1), error));
+        if diagnostics.len() >= MAX_DIAGNOSTICS || line == 0 || line > lines.len() {
+            break;
+        }
+        lines[line - 1] = "";
+        result = assemble(path, lines.join("\n").as_str()).1;
+    }
+
+    Analysis {
+        words,
+        labels,
+        constants,
+        debug_info,
+        program,
+        diagnostics,
+    }
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object(vec![
+        ("line", Json::from(line)),
+        ("character", Json::from(character)),
+    ])
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    Json::object(vec![
+        ("start", position(line, start)),
+        ("end", position(line, end)),
+    ])
+}
+
+fn word_range(word: &Word) -> Json {
+    range(word.line, word.start, word.end)
+}
+
+fn location(uri: &str, word: &Word) -> Json {
+    Json::object(vec![("uri", Json::from(uri)), ("range", word_range(word))])
+}
+
+fn markdown(text: String) -> Json {
+    Json::object(vec![(
+        "contents",
+        Json::object(vec![
+            ("kind", Json::from("markdown")),
+            ("value", Json::from(text)),
+        ]),
+    )])
+}
+
+fn completion(label: &str, kind: i64, detail: String) -> Json {
+    Json::object(vec![
+        ("label", Json::from(label)),
+        ("kind", Json::from(kind)),
+        ("detail", Json::from(detail)),
+    ])
+}
+
+impl Analysis {
+    fn word_at(&self, line: usize, character: usize) -> Option<&Word> {
+        self.words
+            .iter()
+            .find(|word| word.line == line && word.start <= character && character <= word.end)
+    }
+
+    // The definition of a label or constant named by a word
+    fn definition(&self, name: &str) -> Option<&Word> {
+        self.labels
+            .get(name)
+            .or_else(|| self.constants.get(name))
+            .map(|position| &self.words[*position])
+    }
+
+    fn references(&self, name: &str, include_declaration: bool) -> Vec<&Word> {
+        let definition = self.definition(name).map(|word| (word.line, word.start));
+        self.words
+            .iter()
+            .filter(|word| {
+                let is_definition = Some((word.line, word.start)) == definition;
+                match is_definition {
+                    true => include_declaration,
+                    false => word.index > 0 && word.name == name,
+                }
+            })
+            .collect()
+    }
+
+    fn hover(&self, word: &Word) -> Option<String> {
+        let name = word.name.as_str();
+        if let Some(value) = self.debug_info.constants.get(name) {
+            return Some(format!("const {} = {} (0x{:04X})", name, value, value));
+        }
+        if let Some(address) = self.debug_info.labels.get(name) {
+            return Some(format!("label {} at 0x{:04X}", name, address));
+        }
+        // Only real registers are described, a $ being typed is left alone
+        if let [b'$', number @ b'0'..=b'7', half @ ..] = word.text.as_bytes() {
+            let half = match half {
+                [] => "16bit ",
+                [b'a'] => "first 8bit half of ",
+                [b'b'] => "second 8bit half of ",
+                _ => return None,
+            };
+            return Some(format!("{}register ${}", half, *number as char));
+        }
+        if word.index == 0 {
+            return self.instruction_hover(word);
+        }
+        None
+    }
+
+    fn instruction_hover(&self, word: &Word) -> Option<String> {
+        let (_, description) = DESCRIPTIONS
+            .iter()
+            .find(|(mnemonic, _)| *mnemonic == word.text)?;
+        let mut text = format!("**{}** {}", word.text, description);
+
+        let lines = &self.debug_info.lines;
+        let index = lines.iter().position(|(_, line)| *line == word.line + 1);
+        if let Some(index) = index {
+            // A pseudo-instruction covers every instruction it expands to
+            let start = lines[index].0 as usize;
+            let end = match lines[index..]
+                .iter()
+                .find(|(_, line)| *line != word.line + 1)
+            {
+                Some((address, _)) => *address as usize,
+                None => self.program.len(),
+            };
+            if let Some(bytes) = self.program.get(start..end) {
+                let encoding: Vec<String> =
+                    bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                text.push_str(
+                    format!(
+                        "\n\n`{}` {} bytes at 0x{:04X}",
+                        encoding.join(" "),
+                        bytes.len(),
+                        start
+                    )
+                    .as_str(),
+                );
+            }
+        }
+        Some(text)
+    }
+
+    fn completions(&self, line: usize, character: usize) -> Vec<Json> {
+        let first_word = !self
+            .words
+            .iter()
+            .any(|word| word.line == line && word.end < character);
+
+        let mut items = Vec::new();
+        if first_word {
+            for (mnemonic, description) in DESCRIPTIONS.iter() {
+                items.push(completion(
+                    mnemonic,
+                    COMPLETION_KEYWORD,
+                    description.to_string(),
+                ));
+            }
+            return items;
+        }
+
+        for register in 0..8 {
+            for half in ["", "a", "b"] {
+                let name = format!("${}{}", register, half);
+                let detail = match half {
+                    "" => "16bit register",
+                    _ => "8bit register half",
+                };
+                items.push(completion(
+                    name.as_str(),
+                    COMPLETION_VARIABLE,
+                    detail.to_string(),
+                ));
+            }
+        }
+        for (name, value) in self.debug_info.constants.iter() {
+            items.push(completion(
+                name,
+                COMPLETION_CONSTANT,
+                format!("const {}", value),
+            ));
+        }
+        for (name, address) in self.debug_info.labels.iter() {
+            items.push(completion(
+                name,
+                COMPLETION_FUNCTION,
+                format!("label 0x{:04X}", address),
+            ));
+        }
+        items
+    }
+
+    fn symbols(&self) -> Vec<Json> {
+        let mut definitions: Vec<(&String, usize, i64)> = self
+            .labels
+            .iter()
+            .map(|(name, position)| (name, *position, SYMBOL_FUNCTION))
+            .chain(
+                self.constants
+                    .iter()
+                    .map(|(name, position)| (name, *position, SYMBOL_CONSTANT)),
+            )
+            .collect();
+        definitions.sort_by_key(|(_, position, _)| *position);
+
+        definitions
+            .into_iter()
+            .map(|(name, position, kind)| {
+                let word = &self.words[position];
+                Json::object(vec![
+                    ("name", Json::from(name.as_str())),
+                    ("kind", Json::from(kind)),
+                    ("range", word_range(word)),
+                    ("selectionRange", word_range(word)),
+                ])
+            })
+            .collect()
+    }
+}
+
+fn uri_path(uri: &str) -> &str {
+    uri.strip_prefix("file://").unwrap_or(uri)
+}
+
+// Serves the Language Server Protocol on stdin and stdout until the client exits
+pub fn serve() -> Result<(), String> {
+    let mut reader = BufReader::new(io::stdin());
+    let mut writer = io::stdout();
+    let mut documents: HashMap<String, Analysis> = HashMap::new();
+
+    while let Some(message) = read_message(&mut reader)? {
+        // A message that isn't JSON gets the JSON-RPC parse error and the server carries on
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                let response = Json::object(vec![
+                    ("jsonrpc", Json::from("2.0")),
+                    ("id", Json::Null),
+                    (
+                        "error",
+                        Json::object(vec![
+                            ("code", Json::from(PARSE_ERROR)),
+                            ("message", Json::from(error)),
+                        ]),
+                    ),
+                ]);
+                write_message(&mut writer, &response)?;
+                continue;
+            }
+        };
+        let method = message.get("method").as_str().unwrap_or("").to_string();
+        let params = message.get("params");
+        let uri = params
+            .get("textDocument")
+            .get("uri")
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        let result = match method.as_str() {
+            "initialize" => Some(capabilities()),
+            "shutdown" => Some(Json::Null),
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").get("text").as_str();
+                update(&mut writer, &mut documents, &uri, text.unwrap_or(""))?;
+                None
+            }
+            "textDocument/didChange" => {
+                // Only full document sync is offered so the last change is the whole text
+                let changes = params.get("contentChanges").as_array();
+                if let Some(text) = changes
+                    .last()
+                    .and_then(|change| change.get("text").as_str())
+                {
+                    update(&mut writer, &mut documents, &uri, text)?;
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                None
+            }
+            _ => match documents.get(&uri) {
+                Some(analysis) => request(analysis, &uri, method.as_str(), params),
+                None => Some(Json::Null),
+            },
+        };
+
+        // Notifications have no id and get no response
+        if let (Some(result), Json::Number(_) | Json::String(_)) = (result, message.get("id")) {
+            let response = Json::object(vec![
+                ("jsonrpc", Json::from("2.0")),
+                ("id", message.get("id").clone()),
+                ("result", result),
+            ]);
+            write_message(&mut writer, &response)?;
+        }
+    }
+    Ok(())
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                ("textDocumentSync", Json::from(1i64)),
+                ("definitionProvider", Json::from(true)),
+                ("referencesProvider", Json::from(true)),
+                ("hoverProvider", Json::from(true)),
+                ("documentSymbolProvider", Json::from(true)),
+                (
+                    "completionProvider",
+                    Json::object(vec![(
+                        "triggerCharacters",
+                        Json::from(vec![Json::from("$")]),
+                    )]),
+                ),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object(vec![("name", Json::from("rusty-boi-lsp"))]),
+        ),
+    ])
+}
+
+fn update(
+    writer: &mut io::Stdout,
+    documents: &mut HashMap<String, Analysis>,
+    uri: &str,
+    text: &str,
+) -> Result<(), String> {
+    let analysis = analyze(uri_path(uri), text);
+    let diagnostics: Vec<Json> = analysis
+        .diagnostics
+        .iter()
+        .map(|(line, error)| {
+            let length = text.lines().nth(*line).map(|line| line.len()).unwrap_or(0);
+            Json::object(vec![
+                ("range", range(*line, 0, length)),
+                ("severity", Json::from(1i64)),
+                ("source", Json::from("rusty-boi")),
+                ("message", Json::from(error.as_str())),
+            ])
+        })
+        .collect();
+    documents.insert(uri.to_string(), analysis);
+
+    let notification = Json::object(vec![
+        ("jsonrpc", Json::from("2.0")),
+        ("method", Json::from("textDocument/publishDiagnostics")),
+        (
+            "params",
+            Json::object(vec![
+                ("uri", Json::from(uri)),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ]);
+    write_message(writer, &notification)
+}
+
+fn request(analysis: &Analysis, uri: &str, method: &str, params: &Json) -> Option<Json> {
+    let line = params.get("position").get("line").as_i64().unwrap_or(0) as usize;
+    let character = params
+        .get("position")
+        .get("character")
+        .as_i64()
+        .unwrap_or(0) as usize;
+    let word = analysis.word_at(line, character);
+
+    let result = match method {
+        "textDocument/definition" => word
+            .and_then(|word| analysis.definition(&word.name))
+            .map(|definition| location(uri, definition)),
+        "textDocument/references" => word.map(|word| {
+            let include = params
+                .get("context")
+                .get("includeDeclaration")
+                .as_bool()
+                .unwrap_or(true);
+            let references = analysis.references(&word.name, include);
+            Json::Array(references.iter().map(|word| location(uri, word)).collect())
+        }),
+        "textDocument/hover" => word.and_then(|word| analysis.hover(word)).map(markdown),
+        "textDocument/completion" => Some(Json::Array(analysis.completions(line, character))),
+        "textDocument/documentSymbol" => Some(Json::Array(analysis.symbols())),
+        _ => None,
+    };
+    Some(result.unwrap_or(Json::Null))
+}
@@ -1,20 +1,49 @@
 use std::{
+    collections::HashMap,
     env::{self, consts::OS},
     fs::File,
     io::{Read, Write},
 };
 
-use assembler::Assembler;
+use assembler::{Assembler, DebugInfo};
+use coverage::Coverage;
+use debugger::Debugger;
+use history::{History, HistoryMode};
+use image::{Image, Segment, SegmentKind};
 //use assembler::Assembler;
-use rusty_boi::RustyBoi;
-use vm_main::run_vm;
+use movie::Movie;
+use object::Object;
+use profiler::Profiler;
+use rusty_boi::{Halt, RustyBoi};
+use trace::{Trace, TraceFormat};
+use vm_main::{run_headless, run_vm};
 
 pub mod assembler;
+pub mod cfg;
 pub mod compare;
+pub mod compiler;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod disassembler;
+pub mod expr;
+pub mod formatter;
+pub mod gdb_stub;
+pub mod history;
+pub mod image;
+pub mod json;
+pub mod linker;
+pub mod lint;
+pub mod lsp;
 pub mod math;
+pub mod movie;
+pub mod object;
 pub mod opcodes;
+pub mod profiler;
 pub mod register_file;
 pub mod rusty_boi;
+pub mod stack_usage;
+pub mod trace;
 pub mod vm_main;
 
 fn main() -> Result<(), String> {
@@ -23,23 +52,501 @@ fn main() -> Result<(), String> {
     match args[1].as_str() {
         "c" => {
             let mut assembler = Assembler::new();
-            assembler.load_file(args[2].as_str())?;
+            load_source(&mut assembler, args[2].as_str())?;
+            define_constants(&mut assembler, &args)?;
             assembler.pre_assembly()?;
+            if has_flag(&args, "--object") {
+                let object = assembler.assemble_object()?;
+                object.save(args[3].as_str())?;
+                return Ok(());
+            }
             let mut program = assembler.assemble()?;
-            let mut out_file = File::create(args[3].clone()).map_err(|e| e.to_string())?;
-            out_file.write(&mut program).map_err(|e| e.to_string())?;
+            let debug_info = assembler.debug_info();
+            if let Some(budget) = option_value(&args, "--stack-budget") {
+                let cfg = cfg::ControlFlowGraph::build(&program, &debug_info);
+                let report = stack_usage::analyze(&cfg, &debug_info);
+                report.check_budget(parse_address(budget)?)?;
+                report.check_ram(assembler.ram_end())?;
+            }
+            if has_flag(&args, "--raw") {
+                let mut out_file = File::create(args[3].clone()).map_err(|e| e.to_string())?;
+                out_file.write(&mut program).map_err(|e| e.to_string())?;
+            } else {
+                build_image(&program, &assembler, &debug_info, &args)?.save(args[3].as_str())?;
+            }
+            if has_flag(&args, "--layout") {
+                print!("{}", assembler.layout());
+            }
+            if has_flag(&args, "--listing") {
+                print!("{}", assembler.listing());
+            }
         }
         "e" => {
-            let mut program_buffer: Vec<u8> = Vec::new();
-            let mut file = File::open(args[2].clone()).map_err(|e| e.to_string())?;
-            file.read_to_end(&mut program_buffer)
-                .map_err(|e| e.to_string())?;
+            let (image, debug_info) = load_program(&args)?;
+            let mut rusty_boi = RustyBoi::new();
+            let program_end = image.program_end();
+            rusty_boi.load_image(&image);
+            setup_tools(&mut rusty_boi, &args, debug_info.as_ref(), program_end)?;
+            let replay = load_replay(&rusty_boi, &args)?;
+            let mut record = option_value(&args, "--record")
+                .map(|path| (path, Movie::new(rusty_boi.program_hash())));
+            run_vm(
+                &mut rusty_boi,
+                replay.as_ref(),
+                record.as_mut().map(|(_, movie)| movie),
+            )?;
+            if let Some((path, movie)) = record {
+                movie.save(path)?;
+            }
+            finish_tools(&mut rusty_boi, &args, debug_info.as_ref())?;
+        }
+        "h" => {
+            let (image, debug_info) = load_program(&args)?;
+            let mut rusty_boi = RustyBoi::new();
+            let program_end = image.program_end();
+            rusty_boi.load_image(&image);
+            let replay = load_replay(&rusty_boi, &args)?;
+            // The frame count can be left out when replaying a movie
+            let frames = match (args.get(3).map(|frames| frames.parse::<u64>()), &replay) {
+                (Some(Ok(frames)), _) => frames,
+                (_, Some(movie)) => movie.frames.len() as u64,
+                _ => return Err("Expected a frame count for the headless runner".to_string()),
+            };
+            setup_tools(&mut rusty_boi, &args, debug_info.as_ref(), program_end)?;
+            let halted = run_headless(&mut rusty_boi, frames, replay.as_ref())?;
+            finish_tools(&mut rusty_boi, &args, debug_info.as_ref())?;
+            // The exit code of HALT becomes the exit status, so test programs can report failure
+            match halted {
+                Some((Halt::Exit(code), frame)) => {
+                    println!("{} on frame {}", Halt::Exit(code), frame);
+                    if code != 0 {
+                        // Exit statuses only keep a byte, larger codes still have to fail
+                        std::process::exit(code.min(255) as i32);
+                    }
+                }
+                Some((halt, frame)) => return Err(format!("{} on frame {}", halt, frame)),
+                None => (),
+            }
+        }
+        "g" => {
+            let (image, debug_info) = load_program(&args)?;
             let mut rusty_boi = RustyBoi::new();
-            rusty_boi.load_program(program_buffer);
-            run_vm(rusty_boi)?;
+            let program_end = image.program_end();
+            rusty_boi.load_image(&image);
+            let port = match args.get(3).map(|port| port.parse::<u16>()) {
+                Some(Ok(port)) => port,
+                _ => 1234,
+            };
+            setup_tools(&mut rusty_boi, &args, debug_info.as_ref(), program_end)?;
+            gdb_stub::serve(&mut rusty_boi, port, debug_info.clone().unwrap_or_default())?;
+            finish_tools(&mut rusty_boi, &args, debug_info.as_ref())?;
+        }
+        "d" => {
+            let (image, debug_info) = load_program(&args)?;
+            let mut rusty_boi = RustyBoi::new();
+            let program_end = image.program_end();
+            rusty_boi.load_image(&image);
+            // Without a port the adapter talks over stdin and stdout
+            let port = args.get(3).and_then(|port| port.parse::<u16>().ok());
+            setup_tools(&mut rusty_boi, &args, debug_info.as_ref(), program_end)?;
+            dap::serve(&mut rusty_boi, debug_info.clone().unwrap_or_default(), port)?;
+            finish_tools(&mut rusty_boi, &args, debug_info.as_ref())?;
+        }
+        "l" => lsp::serve()?,
+        "boi" => {
+            let source = read_source(args[2].as_str())?;
+            let assembly = compiler::compile(&source, args[2].as_str())?;
+            let mut out_file = File::create(args[3].clone()).map_err(|e| e.to_string())?;
+            out_file
+                .write_all(assembly.as_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        "fmt" => {
+            let check = has_flag(&args, "--check");
+            let mut unformatted = 0;
+            let mut failed = 0;
+            // A file that can't be formatted is reported and the rest still get their turn
+            for path in args[2..].iter().filter(|arg| !arg.starts_with("--")) {
+                match formatter::format_file(path, check) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        unformatted += 1;
+                        match check {
+                            true => println!("{} needs formatting", path),
+                            false => println!("Formatted {}", path),
+                        }
+                    }
+                    Err(error) => {
+                        failed += 1;
+                        println!("{}", error);
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(format!("{} files couldn't be formatted", failed));
+            }
+            if check && unformatted > 0 {
+                return Err(format!("{} files need formatting", unformatted));
+            }
+        }
+        "lint" => {
+            let (image, debug_info) = load_program(&args)?;
+            let program = image.program_memory();
+            let debug_info = match debug_info {
+                Some(debug_info) => debug_info,
+                None => return Err("The linter needs an .iga source file".to_string()),
+            };
+            let warnings = lint::lint(&program, &debug_info);
+            for warning in warnings.iter() {
+                println!("{}", warning);
+            }
+            println!("{} warnings in {}", warnings.len(), args[2]);
+            if has_flag(&args, "--deny") && !warnings.is_empty() {
+                return Err(format!("Linting {} failed", args[2]));
+            }
+        }
+        "graph" => {
+            let (image, debug_info) = load_program(&args)?;
+            let program = image.program_memory();
+            let debug_info = match debug_info {
+                Some(debug_info) => debug_info,
+                None => return Err("Graphs need an .iga source file".to_string()),
+            };
+            let cfg = cfg::ControlFlowGraph::build(&program, &debug_info);
+            let dot = match has_flag(&args, "--calls") {
+                true => cfg.call_graph_dot(&debug_info),
+                false => cfg.to_dot(&debug_info),
+            };
+            match option_value(&args, "--out") {
+                Some(path) => {
+                    let mut file = File::create(path).map_err(|e| e.to_string())?;
+                    file.write_all(dot.as_bytes()).map_err(|e| e.to_string())?;
+                }
+                None => print!("{}", dot),
+            }
+        }
+        "stack" => {
+            let (image, debug_info) = load_program(&args)?;
+            let program = image.program_memory();
+            let debug_info = match debug_info {
+                Some(debug_info) => debug_info,
+                None => return Err("Stack analysis needs an .iga source file".to_string()),
+            };
+            let cfg = cfg::ControlFlowGraph::build(&program, &debug_info);
+            let report = stack_usage::analyze(&cfg, &debug_info);
+            println!("{}", report);
+            let budget = match option_value(&args, "--budget") {
+                Some(budget) => parse_address(budget)?,
+                None => stack_usage::STACK_REGION,
+            };
+            report.check_budget(budget)?;
+        }
+        "link" => {
+            let mut objects = Vec::new();
+            let mut index = 3;
+            while index < args.len() {
+                match args[index].as_str() {
+                    "--entry" | "--title" | "--author" | "--ram" => index += 1,
+                    flag if flag.starts_with("--") => (),
+                    path => objects.push((path.to_string(), Object::load(path)?)),
+                }
+                index += 1;
+            }
+            if objects.is_empty() {
+                return Err("Expected object files to link".to_string());
+            }
+
+            let mut linked = linker::link(&objects)?;
+            set_image_options(&mut linked.image, &linked.symbols, &args)?;
+            match has_flag(&args, "--raw") {
+                true => {
+                    let mut out_file = File::create(args[2].clone()).map_err(|e| e.to_string())?;
+                    out_file
+                        .write_all(&linked.image.program_memory())
+                        .map_err(|e| e.to_string())?;
+                }
+                false => linked.image.save(args[2].as_str())?,
+            }
+            if has_flag(&args, "--map") {
+                print!("{}", linked.map());
+            }
         }
         _ => return Err(format!("Uknown operation {}", args[1])),
     }
 
     Ok(())
 }
+
+// Programs can be ran from a compiled image or straight from an .iga or .boi source file,
+// running from source also keeps the label table around for the debugging tools
+fn load_program(args: &[String]) -> Result<(Image, Option<DebugInfo>), String> {
+    let path = args[2].as_str();
+    if path.ends_with(".iga") || path.ends_with(".boi") {
+        let mut assembler = Assembler::new();
+        load_source(&mut assembler, path)?;
+        define_constants(&mut assembler, args)?;
+        assembler.pre_assembly()?;
+        let program = assembler.assemble()?;
+        return Ok((Image::from_program(program), Some(assembler.debug_info())));
+    }
+
+    Ok((Image::load(path, has_flag(args, "--raw"))?, None))
+}
+
+// .boi programs are compiled to assembly first, the debug info then points at the
+// generated source
+fn load_source(assembler: &mut Assembler, path: &str) -> Result<(), String> {
+    if !path.ends_with(".boi") {
+        return assembler.load_file(path);
+    }
+    let assembly = compiler::compile(&read_source(path)?, path)?;
+    assembler.load_source(&format!("{}.iga", path), &assembly);
+    Ok(())
+}
+
+fn read_source(path: &str) -> Result<String, String> {
+    let mut source = String::new();
+    match File::open(path) {
+        Ok(mut file) => match file.read_to_string(&mut source) {
+            Ok(_) => Ok(source),
+            Err(error) => Err(format!("Error reading file {} \n{}", path, error)),
+        },
+        Err(error) => Err(format!("Error opening file {} \n{}", path, error)),
+    }
+}
+
+// Constants given as -D NAME=value, a name without a value is defined as 1
+fn define_constants(assembler: &mut Assembler, args: &[String]) -> Result<(), String> {
+    for define in option_values(args, "-D") {
+        let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+        let value = match expr::parse_number(value) {
+            Ok(value) if (-0x8000..=0xFFFF).contains(&value) => value as u16,
+            Ok(_) => return Err(format!("Value of -D {} doesn't fit in a word", name)),
+            Err(error) => return Err(format!("Invalid value for -D {} \n{}", name, error)),
+        };
+        assembler.define(name, value);
+    }
+    Ok(())
+}
+
+// Wraps an assembled program with the metadata and RAM data given on the command line
+fn build_image(
+    program: &[u8],
+    assembler: &Assembler,
+    debug_info: &DebugInfo,
+    args: &[String],
+) -> Result<Image, String> {
+    // Every region placed with org becomes its own segment
+    let mut image = Image::from_program(Vec::new());
+    image.segments = assembler
+        .regions()
+        .iter()
+        .map(|region| Segment {
+            kind: SegmentKind::Program,
+            address: region.start,
+            data: program[region.start as usize..region.end as usize].to_vec(),
+        })
+        .collect();
+    image.features = assembler.features().to_vec();
+    set_image_options(&mut image, &debug_info.labels, args)?;
+    Ok(image)
+}
+
+// Metadata, entry point and RAM data given on the command line, the entry can be a label
+fn set_image_options(
+    image: &mut Image,
+    labels: &HashMap<String, u16>,
+    args: &[String],
+) -> Result<(), String> {
+    image.title = option_value(args, "--title")
+        .unwrap_or_default()
+        .to_string();
+    image.author = option_value(args, "--author")
+        .unwrap_or_default()
+        .to_string();
+    if let Some(entry) = option_value(args, "--entry") {
+        image.entry = match labels.get(entry) {
+            Some(address) => *address,
+            None => parse_address(entry)?,
+        };
+    }
+
+    // RAM data is given as address:file
+    for ram_data in option_values(args, "--ram") {
+        let (address, path) = match ram_data.split_once(':') {
+            Some(pair) => pair,
+            None => return Err(format!("Expected address:file for --ram, got {}", ram_data)),
+        };
+        let mut data = Vec::new();
+        let mut file = File::open(path).map_err(|e| format!("Error opening {} \n{}", path, e))?;
+        file.read_to_end(&mut data)
+            .map_err(|e| format!("Error reading {} \n{}", path, e))?;
+        image.segments.push(Segment {
+            kind: SegmentKind::Ram,
+            address: parse_address(address)?,
+            data,
+        });
+    }
+
+    image.validate()
+}
+
+fn load_replay(rusty_boi: &RustyBoi, args: &[String]) -> Result<Option<Movie>, String> {
+    let path = match option_value(args, "--replay") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let movie = Movie::load(path)?;
+    if movie.program_hash != rusty_boi.program_hash() {
+        return Err(format!(
+            "Movie {} was recorded with a different program",
+            path
+        ));
+    }
+    Ok(Some(movie))
+}
+
+fn option_values<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
+
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    option_values(args, name).last().copied()
+}
+
+fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|arg| arg == name)
+}
+
+fn parse_address(address_str: &str) -> Result<u16, String> {
+    let result = match address_str.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => address_str.parse::<u16>(),
+    };
+    result.map_err(|e| format!("Invalid address {} \n{}", address_str, e))
+}
+
+// Attaches the optional debugging tools selected on the command line
+fn setup_tools(
+    rusty_boi: &mut RustyBoi,
+    args: &[String],
+    debug_info: Option<&DebugInfo>,
+    program_end: u16,
+) -> Result<(), String> {
+    setup_trace(rusty_boi, args, debug_info, program_end)?;
+    if has_flag(args, "--profile") {
+        rusty_boi.set_profiler(Profiler::new());
+    }
+    if option_value(args, "--coverage").is_some()
+        || option_value(args, "--coverage-listing").is_some()
+    {
+        if debug_info.is_none() {
+            return Err("Coverage reports need an .iga source file".to_string());
+        }
+        rusty_boi.set_coverage(Coverage::new());
+    }
+    let breakpoints = option_values(args, "--break");
+    let watchpoints = option_values(args, "--watch");
+    if !breakpoints.is_empty() || !watchpoints.is_empty() {
+        let mut debugger = Debugger::new(debug_info.cloned().unwrap_or_default());
+        for breakpoint in breakpoints {
+            debugger.add_breakpoint(breakpoint)?;
+        }
+        for watchpoint in watchpoints {
+            debugger.add_watchpoint(watchpoint)?;
+        }
+        rusty_boi.set_debugger(debugger);
+    }
+    if let Some(frames) = option_value(args, "--rewind") {
+        let frames = match frames.parse::<usize>() {
+            Ok(frames) => frames,
+            Err(error) => return Err(format!("Invalid rewind frame count {} \n{}", frames, error)),
+        };
+        rusty_boi.set_history(History::new(HistoryMode::Frame, frames));
+    }
+    Ok(())
+}
+
+fn finish_tools(
+    rusty_boi: &mut RustyBoi,
+    args: &[String],
+    debug_info: Option<&DebugInfo>,
+) -> Result<(), String> {
+    finish_trace(rusty_boi)?;
+    print_profile(rusty_boi, debug_info);
+    write_coverage(rusty_boi, args, debug_info)?;
+    Ok(())
+}
+
+fn setup_trace(
+    rusty_boi: &mut RustyBoi,
+    args: &[String],
+    debug_info: Option<&DebugInfo>,
+    program_end: u16,
+) -> Result<(), String> {
+    let path = match option_value(args, "--trace") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let format = match has_flag(args, "--trace-binary") {
+        true => TraceFormat::Binary,
+        false => TraceFormat::Text,
+    };
+    let mut trace = Trace::create(path, format)?;
+
+    for range in option_values(args, "--trace-range") {
+        let (start, end) = match range.split_once(':') {
+            Some(bounds) => bounds,
+            None => return Err(format!("Invalid trace range {}, expected start:end", range)),
+        };
+        trace.add_range(parse_address(start)?, parse_address(end)?);
+    }
+
+    for label in option_values(args, "--trace-label") {
+        match debug_info {
+            Some(debug_info) => trace.add_label(&debug_info.labels, label, program_end)?,
+            None => return Err("Label filters need an .iga source file".to_string()),
+        }
+    }
+
+    rusty_boi.set_trace(trace);
+    Ok(())
+}
+
+fn finish_trace(rusty_boi: &mut RustyBoi) -> Result<(), String> {
+    match rusty_boi.take_trace() {
+        Some(trace) => trace.finish(),
+        None => Ok(()),
+    }
+}
+
+fn print_profile(rusty_boi: &RustyBoi, debug_info: Option<&DebugInfo>) {
+    if let Some(profiler) = rusty_boi.profiler() {
+        print!("{}", profiler.report(rusty_boi.prog_mem(), debug_info));
+    }
+}
+
+fn write_coverage(
+    rusty_boi: &RustyBoi,
+    args: &[String],
+    debug_info: Option<&DebugInfo>,
+) -> Result<(), String> {
+    let (coverage, debug_info) = match (rusty_boi.coverage(), debug_info) {
+        (Some(coverage), Some(debug_info)) => (coverage, debug_info),
+        _ => return Ok(()),
+    };
+
+    print!("{}", coverage.summary(debug_info));
+    if let Some(path) = option_value(args, "--coverage") {
+        coverage::write_report(path, coverage.lcov(debug_info).as_str())?;
+    }
+    if let Some(path) = option_value(args, "--coverage-listing") {
+        coverage::write_report(path, coverage.listing(debug_info).as_str())?;
+    }
+    Ok(())
+}
@@ -263,4 +263,58 @@ impl RustyBoi {
         self.write_reg_word(reg3, word);
         self.flag_register.carry = carry;
     }
+
+    pub fn inc_bytes(&mut self) {
+        let reg = self.parse_reg();
+        let (byte, carry) = self.read_reg_byte(reg).overflowing_add(1);
+        self.write_reg_byte(reg, byte);
+        self.flag_register.carry = carry;
+    }
+
+    pub fn inc_words(&mut self) {
+        let reg = self.parse_reg();
+        let (word, carry) = self.read_reg_word(reg).overflowing_add(1);
+        self.write_reg_word(reg, word);
+        self.flag_register.carry = carry;
+    }
+
+    pub fn dec_bytes(&mut self) {
+        let reg = self.parse_reg();
+        let (byte, carry) = self.read_reg_byte(reg).overflowing_sub(1);
+        self.write_reg_byte(reg, byte);
+        self.flag_register.carry = carry;
+    }
+
+    pub fn dec_words(&mut self) {
+        let reg = self.parse_reg();
+        let (word, carry) = self.read_reg_word(reg).overflowing_sub(1);
+        self.write_reg_word(reg, word);
+        self.flag_register.carry = carry;
+    }
+
+    pub fn neg_bytes(&mut self) {
+        let reg = self.parse_reg();
+        let (byte, carry) = 0u8.overflowing_sub(self.read_reg_byte(reg));
+        self.write_reg_byte(reg, byte);
+        self.flag_register.carry = carry;
+    }
+
+    pub fn neg_words(&mut self) {
+        let reg = self.parse_reg();
+        let (word, carry) = 0u16.overflowing_sub(self.read_reg_word(reg));
+        self.write_reg_word(reg, word);
+        self.flag_register.carry = carry;
+    }
+
+    pub fn not_bytes(&mut self) {
+        let reg = self.parse_reg();
+        let byte = !self.read_reg_byte(reg);
+        self.write_reg_byte(reg, byte);
+    }
+
+    pub fn not_words(&mut self) {
+        let reg = self.parse_reg();
+        let word = !self.read_reg_word(reg);
+        self.write_reg_word(reg, word);
+    }
 }
@@ -0,0 +1,97 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+const MOVIE_MAGIC: &[u8; 4] = b"RBMV";
+const MOVIE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 25;
+
+pub const BUTTON_UP: u8 = 1;
+pub const BUTTON_DOWN: u8 = 2;
+pub const BUTTON_LEFT: u8 = 4;
+pub const BUTTON_RIGHT: u8 = 8;
+
+// The button state of every frame of a run, with hashes of the program and the final VM state
+// so a replay can tell when it ran something else or diverged
+pub struct Movie {
+    pub program_hash: u64,
+    pub frames: Vec<u8>,
+    pub final_hash: u64,
+}
+
+impl Movie {
+    pub fn new(program_hash: u64) -> Self {
+        Self {
+            program_hash,
+            frames: Vec::new(),
+            final_hash: 0,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut buffer = Vec::new();
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error opening movie {} \n{}", path, error)),
+        };
+        if let Err(error) = file.read_to_end(&mut buffer) {
+            return Err(format!("Error reading movie {} \n{}", path, error));
+        }
+
+        if buffer.len() < HEADER_SIZE || &buffer[0..4] != MOVIE_MAGIC {
+            return Err(format!("{} is not a Rusty Boi movie", path));
+        }
+        if buffer[4] != MOVIE_VERSION {
+            return Err(format!(
+                "Unsupported movie version {} in {}",
+                buffer[4], path
+            ));
+        }
+
+        let program_hash = u64::from_le_bytes(buffer[5..13].try_into().unwrap());
+        let frame_count = u32::from_le_bytes(buffer[13..17].try_into().unwrap()) as usize;
+        let final_hash = u64::from_le_bytes(buffer[17..25].try_into().unwrap());
+        if buffer.len() != HEADER_SIZE + frame_count {
+            return Err(format!(
+                "Movie {} should have {} frames but has {}",
+                path,
+                frame_count,
+                buffer.len() - HEADER_SIZE
+            ));
+        }
+
+        Ok(Self {
+            program_hash,
+            frames: buffer[HEADER_SIZE..].to_vec(),
+            final_hash,
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut buffer = MOVIE_MAGIC.to_vec();
+        buffer.push(MOVIE_VERSION);
+        buffer.extend_from_slice(&self.program_hash.to_le_bytes());
+        buffer.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&self.final_hash.to_le_bytes());
+        buffer.extend_from_slice(&self.frames);
+
+        let mut file = match File::create(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error creating movie {} \n{}", path, error)),
+        };
+        file.write_all(&buffer)
+            .map_err(|e| format!("Error writing movie {} \n{}", path, e))
+    }
+
+    // Compares the state reached at the end of a replay with the recorded one
+    pub fn check(&self, final_hash: u64) -> Result<(), String> {
+        match final_hash == self.final_hash {
+            true => Ok(()),
+            false => Err(format!(
+                "Replay diverged, expected final state {:016X} but got {:016X}",
+                self.final_hash, final_hash
+            )),
+        }
+    }
+}
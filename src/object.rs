@@ -0,0 +1,188 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+use crate::{
+    image::BinaryReader,
+    rusty_boi::{fnv1a, FNV_OFFSET},
+};
+
+const OBJECT_MAGIC: &[u8; 4] = b"RBOB";
+const OBJECT_VERSION: u8 = 2;
+const CHECKSUM_SIZE: usize = 8;
+
+// An absolute address in the code that has to be fixed once the object is placed. Without a
+// symbol the word holds an offset into this object, otherwise it gets the symbol's address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub symbol: Option<String>,
+    pub line: usize,
+}
+
+// Code assembled as if it started at address 0, all numbers in the file are little endian:
+// magic "RBOB", version byte, source path as a length word and text, alignment word,
+// code length word and code, export count word followed by each export as a length byte,
+// name and offset word, import count word followed by each import as a length byte and
+// name, relocation count word followed by each relocation as an offset word, line word
+// and symbol (a length byte and name, 0 for a local address), feature count byte followed by
+// each VM feature the code needs as a length byte and name, and an FNV-1a hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub source: String,
+    pub alignment: u16,
+    pub code: Vec<u8>,
+    pub exports: Vec<(String, u16)>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    pub features: Vec<String>,
+}
+
+fn push_name(buffer: &mut Vec<u8>, name: &str) {
+    buffer.push(name.len() as u8);
+    buffer.extend_from_slice(name.as_bytes());
+}
+
+fn read_name(reader: &mut BinaryReader, what: &str) -> Result<String, String> {
+    let length = reader.byte(what)? as usize;
+    reader.text(length, what)
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = OBJECT_MAGIC.to_vec();
+        buffer.push(OBJECT_VERSION);
+        buffer.extend_from_slice(&(self.source.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(self.source.as_bytes());
+        buffer.extend_from_slice(&self.alignment.to_le_bytes());
+        buffer.extend_from_slice(&(self.code.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(&self.code);
+
+        buffer.extend_from_slice(&(self.exports.len() as u16).to_le_bytes());
+        for (name, offset) in self.exports.iter() {
+            push_name(&mut buffer, name);
+            buffer.extend_from_slice(&offset.to_le_bytes());
+        }
+        buffer.extend_from_slice(&(self.imports.len() as u16).to_le_bytes());
+        for name in self.imports.iter() {
+            push_name(&mut buffer, name);
+        }
+        buffer.extend_from_slice(&(self.relocations.len() as u16).to_le_bytes());
+        for relocation in self.relocations.iter() {
+            buffer.extend_from_slice(&relocation.offset.to_le_bytes());
+            buffer.extend_from_slice(&(relocation.line as u16).to_le_bytes());
+            match &relocation.symbol {
+                Some(symbol) => push_name(&mut buffer, symbol),
+                None => buffer.push(0),
+            }
+        }
+        buffer.push(self.features.len() as u8);
+        for feature in self.features.iter() {
+            push_name(&mut buffer, feature);
+        }
+
+        let checksum = fnv1a(FNV_OFFSET, &buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < OBJECT_MAGIC.len() + 1 + CHECKSUM_SIZE || &bytes[0..4] != OBJECT_MAGIC {
+            return Err("Not a Rusty Boi object file".to_string());
+        }
+        if bytes[4] != OBJECT_VERSION {
+            return Err(format!(
+                "Unsupported object version {}, expected version {}",
+                bytes[4], OBJECT_VERSION
+            ));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+        if fnv1a(FNV_OFFSET, body).to_le_bytes() != checksum {
+            return Err("Object checksum doesn't match, the file is damaged".to_string());
+        }
+
+        let mut reader = BinaryReader {
+            bytes: body,
+            index: 5,
+        };
+        let source_length = reader.word("source path")? as usize;
+        let source = reader.text(source_length, "source path")?;
+        let alignment = reader.word("alignment")?;
+        let code_length = reader.word("code")? as usize;
+        let code = reader.take(code_length, "code")?.to_vec();
+
+        let mut exports = Vec::new();
+        for _ in 0..reader.word("export list")? {
+            let name = read_name(&mut reader, "export list")?;
+            let offset = reader.word("export list")?;
+            // A label can sit right after the last instruction
+            if offset as usize > code.len() {
+                return Err(format!(
+                    "Export {} at 0x{:04X} is outside the code of the object",
+                    name, offset
+                ));
+            }
+            exports.push((name, offset));
+        }
+        let mut imports = Vec::new();
+        for _ in 0..reader.word("import list")? {
+            imports.push(read_name(&mut reader, "import list")?);
+        }
+        let mut relocations = Vec::new();
+        for _ in 0..reader.word("relocation list")? {
+            let offset = reader.word("relocation list")?;
+            let line = reader.word("relocation list")? as usize;
+            let symbol = read_name(&mut reader, "relocation list")?;
+            if offset as usize + 2 > code.len() {
+                return Err(format!(
+                    "Relocation at 0x{:04X} is outside the code of the object",
+                    offset
+                ));
+            }
+            relocations.push(Relocation {
+                offset,
+                symbol: (!symbol.is_empty()).then_some(symbol),
+                line,
+            });
+        }
+        let mut features = Vec::new();
+        for _ in 0..reader.byte("feature list")? {
+            features.push(read_name(&mut reader, "feature list")?);
+        }
+        if reader.index != body.len() {
+            return Err("Unexpected data after the features of the object".to_string());
+        }
+
+        Ok(Self {
+            source,
+            alignment,
+            code,
+            exports,
+            imports,
+            relocations,
+            features,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut buffer = Vec::new();
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error opening object {} \n{}", path, error)),
+        };
+        if let Err(error) = file.read_to_end(&mut buffer) {
+            return Err(format!("Error reading object {} \n{}", path, error));
+        }
+        Self::from_bytes(&buffer).map_err(|e| format!("Error loading {} \n{}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut file = match File::create(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error creating object {} \n{}", path, error)),
+        };
+        file.write_all(&self.to_bytes())
+            .map_err(|e| format!("Error writing object {} \n{}", path, e))
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 
 pub enum Opcode {
     JMP,
@@ -20,6 +20,12 @@ pub enum Opcode {
     MOD { width: OpcodeWidth, signed: bool },
     SHR { width: OpcodeWidth },
     SHL { width: OpcodeWidth },
+    INC { width: OpcodeWidth },
+    DEC { width: OpcodeWidth },
+    NEG { width: OpcodeWidth },
+    NOT { width: OpcodeWidth },
+    NOP,
+    HALT { width: OpcodeWidth },
     RET,
     DRAW,
     RDRAW,
@@ -212,11 +218,131 @@ impl Opcode {
             54 => Self::CCALL,
             55 => Self::NCALL,
 
+            56 => Self::INC {
+                width: OpcodeWidth::Byte,
+            },
+            57 => Self::INC {
+                width: OpcodeWidth::Word,
+            },
+            58 => Self::DEC {
+                width: OpcodeWidth::Byte,
+            },
+            59 => Self::DEC {
+                width: OpcodeWidth::Word,
+            },
+            60 => Self::NEG {
+                width: OpcodeWidth::Byte,
+            },
+            61 => Self::NEG {
+                width: OpcodeWidth::Word,
+            },
+            62 => Self::NOT {
+                width: OpcodeWidth::Byte,
+            },
+            63 => Self::NOT {
+                width: OpcodeWidth::Word,
+            },
+            64 => Self::NOP,
+            65 => Self::HALT {
+                width: OpcodeWidth::Byte,
+            },
+            66 => Self::HALT {
+                width: OpcodeWidth::Word,
+            },
+
             _ => Self::ILG,
         }
     }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::JMP => "JMP",
+            Self::CJMP => "CJMP",
+            Self::NJMP => "NJMP",
+            Self::MOV { .. } => "MOV",
+            Self::LOAD { .. } => "LOAD",
+            Self::STORE { .. } => "STORE",
+            Self::READ { .. } => "READ",
+            Self::PUSH { .. } => "PUSH",
+            Self::POP { .. } => "POP",
+            Self::EQ { .. } => "EQ",
+            Self::NEQ { .. } => "NEQ",
+            Self::LES { signed, .. } => match signed {
+                true => "LESi",
+                false => "LES",
+            },
+            Self::ADD { signed, .. } => match signed {
+                true => "ADDi",
+                false => "ADD",
+            },
+            Self::SUB { signed, .. } => match signed {
+                true => "SUBi",
+                false => "SUB",
+            },
+            Self::MUL { signed, .. } => match signed {
+                true => "MULi",
+                false => "MUL",
+            },
+            Self::DIV { signed, .. } => match signed {
+                true => "DIVi",
+                false => "DIV",
+            },
+            Self::MOD { signed, .. } => match signed {
+                true => "MODi",
+                false => "MOD",
+            },
+            Self::SHR { .. } => "SHR",
+            Self::SHL { .. } => "SHL",
+            Self::INC { .. } => "INC",
+            Self::DEC { .. } => "DEC",
+            Self::NEG { .. } => "NEG",
+            Self::NOT { .. } => "NOT",
+            Self::NOP => "NOP",
+            Self::HALT { .. } => "HALT",
+            Self::RET => "RET",
+            Self::DRAW => "DRAW",
+            Self::RDRAW => "RDRAW",
+            Self::JDRAW => "JDRAW",
+            Self::CALL => "CALL",
+            Self::CCALL => "CCALL",
+            Self::NCALL => "NCALL",
+            Self::ILG => "ILG",
+        }
+    }
+
+    // Cost of each opcode in VM cycles, see the Timing section of the README
+    pub fn cycles(&self) -> u32 {
+        match self {
+            Self::MOV { .. } => 1,
+            Self::LOAD { width } => match width {
+                OpcodeWidth::Byte => 2,
+                OpcodeWidth::Word => 3,
+            },
+            Self::STORE { width } | Self::READ { width } => match width {
+                OpcodeWidth::Byte => 2,
+                OpcodeWidth::Word => 3,
+            },
+            Self::PUSH { width } | Self::POP { width } => match width {
+                OpcodeWidth::Byte => 2,
+                OpcodeWidth::Word => 3,
+            },
+            Self::EQ { .. } | Self::NEQ { .. } | Self::LES { .. } => 1,
+            Self::ADD { .. } | Self::SUB { .. } => 1,
+            Self::SHR { .. } | Self::SHL { .. } => 1,
+            Self::INC { .. } | Self::DEC { .. } | Self::NEG { .. } | Self::NOT { .. } => 1,
+            Self::NOP | Self::HALT { .. } => 1,
+            Self::MUL { .. } => 4,
+            Self::DIV { .. } | Self::MOD { .. } => 8,
+            Self::JMP | Self::CJMP | Self::NJMP => 2,
+            Self::CALL | Self::CCALL | Self::NCALL => 3,
+            Self::RET => 3,
+            Self::DRAW | Self::RDRAW => 1,
+            Self::JDRAW => 2,
+            Self::ILG => 0,
+        }
+    }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpcodeWidth {
     Word,
     Byte,
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+
+use crate::{assembler::DebugInfo, disassembler::Instruction};
+
+const TOP_ADDRESSES: usize = 20;
+
+struct CallNode {
+    routine: u16,
+    calls: u64,
+    instructions: u64,
+    cycles: u64,
+    children: HashMap<u16, usize>,
+}
+
+impl CallNode {
+    fn new(routine: u16) -> Self {
+        Self {
+            routine,
+            calls: 0,
+            instructions: 0,
+            cycles: 0,
+            children: HashMap::new(),
+        }
+    }
+}
+
+pub struct Profiler {
+    instructions: Vec<u64>,
+    cycles: Vec<u64>,
+    nodes: Vec<CallNode>,
+    call_stack: Vec<usize>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        let mut root = CallNode::new(0);
+        root.calls = 1;
+        Self {
+            instructions: vec![0; 65536],
+            cycles: vec![0; 65536],
+            nodes: vec![root],
+            call_stack: vec![0],
+        }
+    }
+
+    // Called after every instruction with the return stack depth from before and after it ran
+    pub fn record(
+        &mut self,
+        address: u16,
+        cycles: u64,
+        depth_before: usize,
+        depth_after: usize,
+        pc: u16,
+    ) {
+        self.instructions[address as usize] += 1;
+        self.cycles[address as usize] += cycles;
+
+        let current = *self.call_stack.last().unwrap();
+        self.nodes[current].instructions += 1;
+        self.nodes[current].cycles += cycles;
+
+        if depth_after > depth_before {
+            let child = match self.nodes[current].children.get(&pc) {
+                Some(child) => *child,
+                None => {
+                    self.nodes.push(CallNode::new(pc));
+                    let child = self.nodes.len() - 1;
+                    self.nodes[current].children.insert(pc, child);
+                    child
+                }
+            };
+            self.nodes[child].calls += 1;
+            self.call_stack.push(child);
+        } else if depth_after < depth_before && self.call_stack.len() > 1 {
+            self.call_stack.pop();
+        }
+    }
+
+    pub fn report(&self, prog_mem: &[u8], debug_info: Option<&DebugInfo>) -> String {
+        let total_instructions: u64 = self.instructions.iter().sum();
+        let total_cycles: u64 = self.cycles.iter().sum();
+        let mut report = format!(
+            "Profile : {} instructions, {} cycles\n",
+            total_instructions, total_cycles
+        );
+
+        report.push_str("\nFlat profile by label\n");
+        report.push_str(
+            format!(
+                "{:<24} {:>12} {:>12} {:>7}\n",
+                "label", "instructions", "cycles", "%"
+            )
+            .as_str(),
+        );
+        let mut labels: HashMap<String, (u64, u64)> = HashMap::new();
+        for address in 0..65536usize {
+            if self.instructions[address] == 0 {
+                continue;
+            }
+            let label = match debug_info.and_then(|info| info.label_before(address as u16)) {
+                Some((name, _)) => name.to_string(),
+                None => "<no label>".to_string(),
+            };
+            let entry = labels.entry(label).or_insert((0, 0));
+            entry.0 += self.instructions[address];
+            entry.1 += self.cycles[address];
+        }
+        let mut labels: Vec<(String, (u64, u64))> = labels.into_iter().collect();
+        labels.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(&b.0)));
+        for (label, (instructions, cycles)) in labels.iter() {
+            report.push_str(
+                format!(
+                    "{:<24} {:>12} {:>12} {:>6.2}%\n",
+                    label,
+                    instructions,
+                    cycles,
+                    percent(*cycles, total_cycles)
+                )
+                .as_str(),
+            );
+        }
+
+        report.push_str("\nHottest addresses\n");
+        report.push_str(
+            format!(
+                "{:<6} {:<24} {:<20} {:>12} {:>12} {:>7}\n",
+                "pc", "location", "instruction", "count", "cycles", "%"
+            )
+            .as_str(),
+        );
+        let mut addresses: Vec<usize> = (0..65536usize)
+            .filter(|address| self.instructions[*address] > 0)
+            .collect();
+        addresses.sort_by(|a, b| self.cycles[*b].cmp(&self.cycles[*a]).then(a.cmp(b)));
+        for address in addresses.iter().take(TOP_ADDRESSES) {
+            let location = match debug_info {
+                Some(info) => info.describe(*address as u16),
+                None => String::new(),
+            };
+            let instruction = Instruction::decode(prog_mem, *address as u16);
+            report.push_str(
+                format!(
+                    "{:04X}   {:<24} {:<20} {:>12} {:>12} {:>6.2}%\n",
+                    address,
+                    location,
+                    instruction,
+                    self.instructions[*address],
+                    self.cycles[*address],
+                    percent(self.cycles[*address], total_cycles)
+                )
+                .as_str(),
+            );
+        }
+
+        report.push_str("\nCall tree (inclusive cycles)\n");
+        report.push_str(
+            format!(
+                "{:<40} {:>8} {:>12} {:>12} {:>7}\n",
+                "routine", "calls", "self", "total", "%"
+            )
+            .as_str(),
+        );
+        self.report_node(&mut report, 0, 0, debug_info, total_cycles);
+
+        report
+    }
+
+    fn inclusive_cycles(&self, node: usize) -> u64 {
+        self.nodes[node].cycles
+            + self.nodes[node]
+                .children
+                .values()
+                .map(|child| self.inclusive_cycles(*child))
+                .sum::<u64>()
+    }
+
+    fn report_node(
+        &self,
+        report: &mut String,
+        node: usize,
+        depth: usize,
+        debug_info: Option<&DebugInfo>,
+        total_cycles: u64,
+    ) {
+        let call_node = &self.nodes[node];
+        let name = match (node, debug_info) {
+            (0, _) => "<entry>".to_string(),
+            (_, Some(info)) => info.describe(call_node.routine),
+            (_, None) => format!("0x{:04X}", call_node.routine),
+        };
+        let inclusive = self.inclusive_cycles(node);
+        report.push_str(
+            format!(
+                "{:<40} {:>8} {:>12} {:>12} {:>6.2}%\n",
+                format!("{}{}", "  ".repeat(depth), name),
+                call_node.calls,
+                call_node.cycles,
+                inclusive,
+                percent(inclusive, total_cycles)
+            )
+            .as_str(),
+        );
+
+        let mut children: Vec<(usize, u64)> = call_node
+            .children
+            .values()
+            .map(|child| (*child, self.inclusive_cycles(*child)))
+            .collect();
+        children.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (child, _) in children {
+            self.report_node(report, child, depth + 1, debug_info, total_cycles);
+        }
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        _ => part as f64 * 100.0 / total as f64,
+    }
+}
@@ -1,10 +1,12 @@
 use sdl2::libc::DT_REG;
 
+#[derive(Clone, Copy)]
 pub struct RegisterAddress {
     register: u8,
     index: RegisterIndex,
 }
 
+#[derive(Clone, Copy)]
 pub enum RegisterIndex {
     First,
     Last,
@@ -119,6 +121,18 @@ impl RegisterFile {
         let bytes = word.to_le_bytes();
         self.registers[register.register as usize] = bytes;
     }
+
+    pub fn snapshot(&self) -> [[u8; 2]; 8] {
+        self.registers
+    }
+
+    pub fn restore(&mut self, registers: [[u8; 2]; 8]) {
+        self.registers = registers;
+    }
+
+    pub fn words(&self) -> [u16; 8] {
+        self.registers.map(u16::from_le_bytes)
+    }
 }
 
 pub struct FlagRegister {
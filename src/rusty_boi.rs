@@ -1,9 +1,53 @@
 use crate::{
+    coverage::Coverage,
+    debugger::{Debugger, Stop, VmContext},
+    disassembler::Instruction,
+    expr::Expr,
+    history::{History, HistoryMode, Snapshot, Undo},
+    image::{Image, SegmentKind},
     opcodes::{Opcode, OpcodeWidth},
+    profiler::Profiler,
     register_file::{FlagRegister, RegisterAddress, RegisterFile},
+    trace::Trace,
 };
 
-const STACK_START: u16 = 0x87FE;
+use std::fmt::Display;
+
+pub const RAM_START: u16 = 0x0004;
+pub const STACK_START: u16 = 0x87FE;
+
+// Why the VM stopped for good
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Halt {
+    // HALT ran, with the exit code from its register
+    Exit(u16),
+    // The PC reached an address after the end of the program image
+    PastEnd(u16),
+}
+
+impl Display for Halt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exit(code) => write!(f, "Halted with exit code {}", code),
+            Self::PastEnd(address) => write!(
+                f,
+                "Halted, ran past the end of the program at 0x{:04X}",
+                address
+            ),
+        }
+    }
+}
+
+pub const FNV_OFFSET: u64 = 0xCBF29CE484222325;
+const FNV_PRIME: u64 = 0x100000001B3;
+
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
 
 pub struct RustyBoi {
     sp: u16,
@@ -13,6 +57,19 @@ pub struct RustyBoi {
     pub flag_register: FlagRegister,
     pub memory: [u8; 65536],
     ret_ptr: Vec<u16>,
+    cycles: u64,
+    frame_cycles: u64,
+    cycle_budget: Option<u64>,
+    trace: Option<Trace>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    history: Option<History>,
+    debugger: Option<Debugger>,
+    stop: Option<Stop>,
+    mid_frame: bool,
+    halted: Option<Halt>,
+    // Bytes of program memory filled by the loaded image, running past them halts
+    program_size: usize,
 }
 
 impl RustyBoi {
@@ -25,125 +82,471 @@ impl RustyBoi {
             sp: STACK_START,
             ret_ptr: Vec::new(),
             pc: 0,
+            cycles: 0,
+            frame_cycles: 0,
+            cycle_budget: None,
+            trace: None,
+            profiler: None,
+            coverage: None,
+            history: None,
+            debugger: None,
+            stop: None,
+            mid_frame: false,
+            halted: None,
+            program_size: 65536,
         }
     }
 
     pub fn run(&mut self) {
         //println!("Runnng");
-        if self.pc == u16::MAX {
+        if self.halted.is_some() {
             return;
         }
+        // A run cut short by the debugger carries on with the same frame
+        if !self.mid_frame {
+            let state = self.history.is_some().then(|| self.snapshot_state());
+            if let (Some(state), Some(history)) = (state, self.history.as_mut()) {
+                history.begin_frame(state);
+            }
+            self.frame_cycles = 0;
+        }
+        self.mid_frame = false;
         loop {
-            //println!(
-            //     "{}",
-            //     self.register_file.read_byte(RegisterAddress::from_byte(1))
-            // );
-            //    println!("Loading file");
-
-            //println!("{:?}", self.pc);
-            let opcode = Opcode::from_byte(self.read_prog_byte());
-            //println!("{:?} @ {} ret ptr len is {}", opcode, self.pc, self.ret_ptr.len());
-            match opcode {
-                Opcode::JMP => self.jmp(),
-                Opcode::CJMP => self.tjmp(),
-                Opcode::NJMP => self.njmp(),
-                Opcode::MOV { width } => match width {
-                    OpcodeWidth::Byte => self.mov_byte(),
-                    OpcodeWidth::Word => self.mov_word(),
-                },
-                Opcode::LOAD { width } => match width {
-                    OpcodeWidth::Byte => self.load_byte(),
-                    OpcodeWidth::Word => self.load_word(),
-                },
-                Opcode::STORE { width } => match width {
-                    OpcodeWidth::Byte => self.store_byte(),
-                    OpcodeWidth::Word => self.store_word(),
-                },
-                Opcode::READ { width } => match width {
-                    OpcodeWidth::Byte => self.read_byte(),
-                    OpcodeWidth::Word => self.read_word(),
-                },
-                Opcode::PUSH { width } => match width {
-                    OpcodeWidth::Byte => self.push_byte(),
-                    OpcodeWidth::Word => self.push_word(),
-                },
-                Opcode::POP { width } => match width {
-                    OpcodeWidth::Byte => self.pop_byte(),
-                    OpcodeWidth::Word => self.pop_word(),
-                },
-                Opcode::EQ { width } => match width {
-                    OpcodeWidth::Byte => self.eq_bytes(),
-                    OpcodeWidth::Word => self.eq_word(),
-                },
-                Opcode::NEQ { width } => match width {
-                    OpcodeWidth::Byte => self.neq_bytes(),
-                    OpcodeWidth::Word => self.neq_word(),
-                },
-                Opcode::LES { width, signed } => match (width, signed) {
-                    (OpcodeWidth::Word, true) => self.les_signed_words(),
-                    (OpcodeWidth::Word, false) => self.les_words(),
-                    (OpcodeWidth::Byte, true) => self.les_signed_bytes(),
-                    (OpcodeWidth::Byte, false) => self.les_bytes(),
-                },
-                Opcode::ADD { width, signed } => match (width, signed) {
-                    (OpcodeWidth::Word, true) => self.add_signed_words(),
-                    (OpcodeWidth::Word, false) => self.add_words(),
-                    (OpcodeWidth::Byte, true) => self.add_signed_bytes(),
-                    (OpcodeWidth::Byte, false) => self.add_bytes(),
-                },
-                Opcode::SUB { width, signed } => match (width, signed) {
-                    (OpcodeWidth::Word, true) => self.sub_signed_words(),
-                    (OpcodeWidth::Word, false) => self.sub_words(),
-                    (OpcodeWidth::Byte, true) => self.sub_signed_bytes(),
-                    (OpcodeWidth::Byte, false) => self.sub_bytes(),
-                },
-                Opcode::MUL { width, signed } => match (width, signed) {
-                    (OpcodeWidth::Word, true) => self.mul_signed_words(),
-                    (OpcodeWidth::Word, false) => self.mul_words(),
-                    (OpcodeWidth::Byte, true) => self.mul_signed_bytes(),
-                    (OpcodeWidth::Byte, false) => self.mul_bytes(),
-                },
-                Opcode::DIV { width, signed } => match (width, signed) {
-                    (OpcodeWidth::Word, true) => self.div_signed_words(),
-                    (OpcodeWidth::Word, false) => self.div_words(),
-                    (OpcodeWidth::Byte, true) => self.div_signed_bytes(),
-                    (OpcodeWidth::Byte, false) => self.div_bytes(),
-                },
-                Opcode::MOD { width, signed } => match (width, signed) {
-                    (OpcodeWidth::Word, true) => self.mod_signed_words(),
-                    (OpcodeWidth::Word, false) => self.mod_words(),
-                    (OpcodeWidth::Byte, true) => self.mod_signed_bytes(),
-                    (OpcodeWidth::Byte, false) => self.mod_bytes(),
-                },
-                Opcode::SHR { width } => match width {
-                    OpcodeWidth::Byte => self.shr_bytes(),
-                    OpcodeWidth::Word => self.shr_words(),
-                },
-                Opcode::SHL { width } => match width {
-                    OpcodeWidth::Byte => self.shl_bytes(),
-                    OpcodeWidth::Word => self.shl_words(),
-                },
-                Opcode::RET => self.ret(),
-                Opcode::DRAW => return,
-                Opcode::RDRAW => {
-                    self.pc = 0;
-                    return;
-                }
-                Opcode::JDRAW => {
-                    let dest = self.read_prog_word();
-                    self.jump(dest);
+            if self.debugger.is_some() && self.check_breakpoints() {
+                self.mid_frame = true;
+                return;
+            }
+            if self.step() {
+                return;
+            }
+            if self.stop.is_some() {
+                self.mid_frame = true;
+                return;
+            }
+            if let Some(budget) = self.cycle_budget {
+                if self.frame_cycles >= budget {
                     return;
                 }
-                Opcode::CALL => self.call_func(),
-                Opcode::CCALL => self.ccall_func(),
-                Opcode::NCALL => self.ncall_func(),
-                Opcode::ILG => panic!("Illegal opcode"),
             }
         }
     }
 
+    // Executes a single instruction, returns true when the instruction ends the frame. A halted
+    // VM doesn't run anything and always ends the frame
+    pub fn step(&mut self) -> bool {
+        if self.halted.is_some() {
+            return true;
+        }
+        if self.pc as usize >= self.program_size {
+            self.halted = Some(Halt::PastEnd(self.pc));
+            return true;
+        }
+        let state = self.history.is_some().then(|| self.snapshot_state());
+        if let (Some(state), Some(history)) = (state, self.history.as_mut()) {
+            history.begin_step(state);
+        }
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.clear_accesses();
+        }
+        let address = self.pc;
+        let depth = self.ret_ptr.len();
+        let opcode_byte = self.read_prog_byte();
+        let opcode = Opcode::from_byte(opcode_byte);
+        let cycles = opcode.cycles() as u64;
+        self.cycles += cycles;
+        self.frame_cycles += cycles;
+        let mut frame_end = false;
+        match opcode {
+            Opcode::JMP => self.jmp(),
+            Opcode::CJMP => self.tjmp(),
+            Opcode::NJMP => self.njmp(),
+            Opcode::MOV { width } => match width {
+                OpcodeWidth::Byte => self.mov_byte(),
+                OpcodeWidth::Word => self.mov_word(),
+            },
+            Opcode::LOAD { width } => match width {
+                OpcodeWidth::Byte => self.load_byte(),
+                OpcodeWidth::Word => self.load_word(),
+            },
+            Opcode::STORE { width } => match width {
+                OpcodeWidth::Byte => self.store_byte(),
+                OpcodeWidth::Word => self.store_word(),
+            },
+            Opcode::READ { width } => match width {
+                OpcodeWidth::Byte => self.read_byte(),
+                OpcodeWidth::Word => self.read_word(),
+            },
+            Opcode::PUSH { width } => match width {
+                OpcodeWidth::Byte => self.push_byte(),
+                OpcodeWidth::Word => self.push_word(),
+            },
+            Opcode::POP { width } => match width {
+                OpcodeWidth::Byte => self.pop_byte(),
+                OpcodeWidth::Word => self.pop_word(),
+            },
+            Opcode::EQ { width } => match width {
+                OpcodeWidth::Byte => self.eq_bytes(),
+                OpcodeWidth::Word => self.eq_word(),
+            },
+            Opcode::NEQ { width } => match width {
+                OpcodeWidth::Byte => self.neq_bytes(),
+                OpcodeWidth::Word => self.neq_word(),
+            },
+            Opcode::LES { width, signed } => match (width, signed) {
+                (OpcodeWidth::Word, true) => self.les_signed_words(),
+                (OpcodeWidth::Word, false) => self.les_words(),
+                (OpcodeWidth::Byte, true) => self.les_signed_bytes(),
+                (OpcodeWidth::Byte, false) => self.les_bytes(),
+            },
+            Opcode::ADD { width, signed } => match (width, signed) {
+                (OpcodeWidth::Word, true) => self.add_signed_words(),
+                (OpcodeWidth::Word, false) => self.add_words(),
+                (OpcodeWidth::Byte, true) => self.add_signed_bytes(),
+                (OpcodeWidth::Byte, false) => self.add_bytes(),
+            },
+            Opcode::SUB { width, signed } => match (width, signed) {
+                (OpcodeWidth::Word, true) => self.sub_signed_words(),
+                (OpcodeWidth::Word, false) => self.sub_words(),
+                (OpcodeWidth::Byte, true) => self.sub_signed_bytes(),
+                (OpcodeWidth::Byte, false) => self.sub_bytes(),
+            },
+            Opcode::MUL { width, signed } => match (width, signed) {
+                (OpcodeWidth::Word, true) => self.mul_signed_words(),
+                (OpcodeWidth::Word, false) => self.mul_words(),
+                (OpcodeWidth::Byte, true) => self.mul_signed_bytes(),
+                (OpcodeWidth::Byte, false) => self.mul_bytes(),
+            },
+            Opcode::DIV { width, signed } => match (width, signed) {
+                (OpcodeWidth::Word, true) => self.div_signed_words(),
+                (OpcodeWidth::Word, false) => self.div_words(),
+                (OpcodeWidth::Byte, true) => self.div_signed_bytes(),
+                (OpcodeWidth::Byte, false) => self.div_bytes(),
+            },
+            Opcode::MOD { width, signed } => match (width, signed) {
+                (OpcodeWidth::Word, true) => self.mod_signed_words(),
+                (OpcodeWidth::Word, false) => self.mod_words(),
+                (OpcodeWidth::Byte, true) => self.mod_signed_bytes(),
+                (OpcodeWidth::Byte, false) => self.mod_bytes(),
+            },
+            Opcode::SHR { width } => match width {
+                OpcodeWidth::Byte => self.shr_bytes(),
+                OpcodeWidth::Word => self.shr_words(),
+            },
+            Opcode::SHL { width } => match width {
+                OpcodeWidth::Byte => self.shl_bytes(),
+                OpcodeWidth::Word => self.shl_words(),
+            },
+            Opcode::INC { width } => match width {
+                OpcodeWidth::Byte => self.inc_bytes(),
+                OpcodeWidth::Word => self.inc_words(),
+            },
+            Opcode::DEC { width } => match width {
+                OpcodeWidth::Byte => self.dec_bytes(),
+                OpcodeWidth::Word => self.dec_words(),
+            },
+            Opcode::NEG { width } => match width {
+                OpcodeWidth::Byte => self.neg_bytes(),
+                OpcodeWidth::Word => self.neg_words(),
+            },
+            Opcode::NOT { width } => match width {
+                OpcodeWidth::Byte => self.not_bytes(),
+                OpcodeWidth::Word => self.not_words(),
+            },
+            Opcode::NOP => (),
+            Opcode::HALT { width } => {
+                let reg = self.parse_reg();
+                let code = match width {
+                    OpcodeWidth::Byte => self.read_reg_byte(reg) as u16,
+                    OpcodeWidth::Word => self.read_reg_word(reg),
+                };
+                self.halted = Some(Halt::Exit(code));
+                frame_end = true;
+            }
+            Opcode::RET => self.ret(),
+            Opcode::DRAW => frame_end = true,
+            Opcode::RDRAW => {
+                self.pc = 0;
+                frame_end = true;
+            }
+            Opcode::JDRAW => {
+                let dest = self.read_prog_word();
+                self.jump(dest);
+                frame_end = true;
+            }
+            Opcode::CALL => self.call_func(),
+            Opcode::CCALL => self.ccall_func(),
+            Opcode::NCALL => self.ncall_func(),
+            Opcode::ILG => panic!("Illegal opcode"),
+        }
+
+        if let Some(trace) = self.trace.as_mut() {
+            if trace.wants(address) {
+                let instruction = Instruction::decode(&self.prog_mem, address);
+                trace.record(
+                    &instruction,
+                    opcode_byte,
+                    &self.register_file,
+                    &self.flag_register,
+                );
+            }
+        }
+
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(address);
+        }
+
+        if let Some(debugger) = self.debugger.as_ref() {
+            if let Some(stop) = debugger.check_watchpoints(address) {
+                self.stop = Some(stop);
+            }
+        }
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(address, cycles, depth, self.ret_ptr.len(), self.pc);
+        }
+
+        frame_end
+    }
+
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn set_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.debugger.as_ref()
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.debugger.as_mut()
+    }
+
+    // The reason the last run stopped early, if it did
+    pub fn take_stop(&mut self) -> Option<Stop> {
+        self.stop.take()
+    }
+
+    // Stops on a breakpoint at the current PC, used by run and by debuggers stepping over calls
+    pub fn check_breakpoints(&mut self) -> bool {
+        let stop = self.debugger.as_mut().unwrap().check_breakpoints(
+            self.pc,
+            self.sp,
+            &self.register_file,
+            &self.flag_register,
+            &self.memory,
+        );
+        match stop {
+            Some(stop) => {
+                self.stop = Some(stop);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Evaluates a debugger expression against the current state
+    pub fn evaluate(&self, expr: &Expr) -> Result<i64, String> {
+        let debug_info = match self.debugger.as_ref() {
+            Some(debugger) => &debugger.debug_info,
+            None => return Err("No debugger attached".to_string()),
+        };
+        let mut context = VmContext {
+            pc: self.pc,
+            sp: self.sp,
+            registers: &self.register_file,
+            flags: &self.flag_register,
+            memory: &self.memory,
+            debug_info,
+        };
+        expr.eval(&mut context)
+    }
+
+    pub fn set_history(&mut self, history: History) {
+        self.history = Some(history);
+    }
+
+    fn snapshot_state(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            sp: self.sp,
+            compare: self.flag_register.compare,
+            carry: self.flag_register.carry,
+            registers: self.register_file.snapshot(),
+            ret_len: self.ret_ptr.len(),
+            cycles: self.cycles,
+            frame_cycles: self.frame_cycles,
+        }
+    }
+
+    fn apply_undo(&mut self, undo: Undo) {
+        for (address, byte) in undo.memory {
+            self.memory[address as usize] = byte;
+        }
+        self.ret_ptr.truncate(undo.ret_low);
+        self.ret_ptr.extend(undo.ret_popped.iter().rev());
+        self.ret_ptr.truncate(undo.state.ret_len);
+
+        self.pc = undo.state.pc;
+        self.sp = undo.state.sp;
+        self.flag_register.compare = undo.state.compare;
+        self.flag_register.carry = undo.state.carry;
+        self.register_file.restore(undo.state.registers);
+        self.cycles = undo.state.cycles;
+        self.frame_cycles = undo.state.frame_cycles;
+        // Every recorded state is from before the halt
+        self.halted = None;
+    }
+
+    pub fn step_back(&mut self) -> Result<(), String> {
+        let history = match self.history.as_mut() {
+            Some(history) => history,
+            None => return Err("History recording is not enabled".to_string()),
+        };
+        if history.mode() != HistoryMode::Instruction {
+            return Err("Stepping back needs per instruction history".to_string());
+        }
+        match history.pop() {
+            Some(undo) => {
+                self.apply_undo(undo);
+                Ok(())
+            }
+            None => Err("No more history to step back through".to_string()),
+        }
+    }
+
+    // Undoes whole frames, returns how many could be rewound
+    pub fn rewind_frames(&mut self, frames: usize) -> usize {
+        let mut rewound = 0;
+        while rewound < frames {
+            let undo = match self.history.as_mut().and_then(|history| history.pop()) {
+                Some(undo) => undo,
+                None => break,
+            };
+            if undo.frame_start {
+                rewound += 1;
+            }
+            self.apply_undo(undo);
+        }
+        rewound
+    }
+
+    // Hash of everything that affects execution, two runs that end with the same hash took the same path
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = fnv1a(FNV_OFFSET, &self.memory);
+        hash = fnv1a(hash, &self.pc.to_le_bytes());
+        hash = fnv1a(hash, &self.sp.to_le_bytes());
+        hash = fnv1a(
+            hash,
+            &[
+                self.flag_register.compare as u8,
+                self.flag_register.carry as u8,
+            ],
+        );
+        for register in self.register_file.snapshot().iter() {
+            hash = fnv1a(hash, register);
+        }
+        for address in self.ret_ptr.iter() {
+            hash = fnv1a(hash, &address.to_le_bytes());
+        }
+        fnv1a(hash, &self.cycles.to_le_bytes())
+    }
+
+    pub fn program_hash(&self) -> u64 {
+        fnv1a(FNV_OFFSET, &self.prog_mem)
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // Moving the PC of a halted VM lets it run again
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+        self.halted = None;
+    }
+
+    pub fn halted(&self) -> Option<Halt> {
+        self.halted
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn registers(&self) -> [u16; 8] {
+        self.register_file.words()
+    }
+
+    pub fn set_register(&mut self, register: u8, word: u16) {
+        self.write_reg_word(RegisterAddress::from_byte(register * 2), word);
+    }
+
+    // Bit 0 is the compare flag and bit 1 the carry flag
+    pub fn flags(&self) -> u8 {
+        self.flag_register.compare as u8 | (self.flag_register.carry as u8) << 1
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flag_register.compare = flags & 1 != 0;
+        self.flag_register.carry = flags & 2 != 0;
+    }
+
+    pub fn ret_stack(&self) -> &[u16] {
+        &self.ret_ptr
+    }
+
+    pub fn write_prog_byte(&mut self, address: u16, byte: u8) {
+        self.prog_mem[address as usize] = byte;
+    }
+
+    pub fn prog_mem(&self) -> &[u8] {
+        &self.prog_mem
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn frame_cycles(&self) -> u64 {
+        self.frame_cycles
+    }
+
+    pub fn set_cycle_budget(&mut self, budget: Option<u64>) {
+        self.cycle_budget = budget;
+    }
+
     pub fn ret(&mut self) {
         let dest = self.ret_ptr.pop().unwrap();
+        if let Some(history) = self.history.as_mut() {
+            history.record_ret_pop(dest, self.ret_ptr.len());
+        }
         self.pc = dest;
     }
 
@@ -207,11 +610,22 @@ impl RustyBoi {
     }
 
     pub fn write_memory_byte(&mut self, address: u16, byte: u8) {
+        let old = self.memory[address as usize];
+        if let Some(history) = self.history.as_mut() {
+            history.record_memory(address, old);
+        }
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.record_access(address, old, byte, true);
+        }
         self.memory[address as usize] = byte;
     }
 
     pub fn read_memory_byte(&mut self, address: u16) -> u8 {
-        self.memory[address as usize]
+        let byte = self.memory[address as usize];
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.record_access(address, byte, byte, false);
+        }
+        byte
     }
 
     pub fn read_memory_word(&mut self, address: u16) -> u16 {
@@ -224,6 +638,21 @@ impl RustyBoi {
 
     pub fn load_program(&mut self, program: Vec<u8>) {
         self.prog_mem[0..program.len()].copy_from_slice(&program);
+        self.program_size = program.len();
+    }
+
+    // Copies every segment to where it belongs and starts at the entry point
+    pub fn load_image(&mut self, image: &Image) {
+        for segment in image.segments.iter() {
+            let start = segment.address as usize;
+            let end = start + segment.data.len();
+            match segment.kind {
+                SegmentKind::Program => self.prog_mem[start..end].copy_from_slice(&segment.data),
+                SegmentKind::Ram => self.memory[start..end].copy_from_slice(&segment.data),
+            }
+        }
+        self.pc = image.entry;
+        self.program_size = image.program_size();
     }
 
     pub fn load_byte(&mut self) {
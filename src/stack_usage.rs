@@ -0,0 +1,279 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    assembler::DebugInfo,
+    cfg::{ControlFlowGraph, Routine},
+    opcodes::Opcode,
+    rusty_boi::{RAM_START, STACK_START},
+};
+
+// Everything from the stack start down to the first workable RAM address
+pub const STACK_REGION: u16 = STACK_START - RAM_START;
+
+// Worst case stack use of a routine including everything it calls, None when it is unbounded
+pub struct RoutineUsage {
+    pub entry: u16,
+    pub name: String,
+    pub bytes: Option<u32>,
+    pub calls: Option<u32>,
+}
+
+pub struct StackReport {
+    pub routines: Vec<RoutineUsage>,
+    pub problems: Vec<String>,
+}
+
+// Deepest point of a routine on its own and the depth at each of its call sites
+struct LocalUsage {
+    max: Option<u32>,
+    call_sites: Vec<(u32, u16)>,
+}
+
+#[derive(Clone, Copy)]
+enum Visit {
+    InProgress,
+    Done(Option<u32>, Option<u32>),
+}
+
+struct Analysis<'a> {
+    cfg: &'a ControlFlowGraph,
+    debug_info: &'a DebugInfo,
+    local: HashMap<u16, LocalUsage>,
+    visits: HashMap<u16, Visit>,
+    path: Vec<u16>,
+    problems: Vec<String>,
+}
+
+pub fn analyze(cfg: &ControlFlowGraph, debug_info: &DebugInfo) -> StackReport {
+    let mut analysis = Analysis {
+        cfg,
+        debug_info,
+        local: HashMap::new(),
+        visits: HashMap::new(),
+        path: Vec::new(),
+        problems: Vec::new(),
+    };
+    for routine in cfg.routines.iter() {
+        let usage = analysis.local_usage(routine);
+        analysis.local.insert(routine.entry, usage);
+    }
+    for routine in cfg.routines.iter() {
+        analysis.total_usage(routine.entry);
+    }
+
+    let routines = cfg
+        .routines
+        .iter()
+        .map(|routine| {
+            let (bytes, calls) = match analysis.visits.get(&routine.entry) {
+                Some(Visit::Done(bytes, calls)) => (*bytes, *calls),
+                _ => (None, None),
+            };
+            RoutineUsage {
+                entry: routine.entry,
+                name: analysis.name(routine.entry),
+                bytes,
+                calls,
+            }
+        })
+        .collect();
+    StackReport {
+        routines,
+        problems: analysis.problems,
+    }
+}
+
+impl Analysis<'_> {
+    fn name(&self, entry: u16) -> String {
+        match entry {
+            0 => "main".to_string(),
+            _ => self.debug_info.describe(entry),
+        }
+    }
+
+    // Pushes the deepest stack depth seen at each block through the routine, a block whose
+    // depth keeps growing sits on a loop that pushes more than it pops
+    fn local_usage(&mut self, routine: &Routine) -> LocalUsage {
+        let mut depths: HashMap<u16, i32> = HashMap::new();
+        let mut updates: HashMap<u16, usize> = HashMap::new();
+        let mut queue = vec![(routine.entry, 0)];
+        let mut max = 0;
+        let mut call_sites: HashMap<(u16, u16), i32> = HashMap::new();
+
+        while let Some((start, depth)) = queue.pop() {
+            let block = match self.cfg.blocks.get(&start) {
+                Some(block) => block,
+                None => continue,
+            };
+            match depths.get(&start) {
+                Some(seen) if *seen >= depth => continue,
+                _ => (),
+            }
+            let count = updates.entry(start).or_insert(0);
+            *count += 1;
+            if *count > routine.blocks.len() + 1 {
+                let line = self.debug_info.line_of(start).unwrap_or(0);
+                self.problems.push(format!(
+                    "Stack use in {} grows without bound on line {}",
+                    self.name(routine.entry),
+                    line
+                ));
+                return LocalUsage {
+                    max: None,
+                    call_sites: Vec::new(),
+                };
+            }
+            depths.insert(start, depth);
+
+            let mut depth = depth;
+            for instruction in block.instructions.iter() {
+                if let Some(target) = instruction.target().filter(|_| instruction.is_call()) {
+                    let site = call_sites.entry((instruction.address, target)).or_insert(0);
+                    *site = (*site).max(depth);
+                }
+                depth += instruction.stack_effect();
+                max = max.max(depth);
+            }
+            if block.last().opcode == Opcode::RET {
+                continue;
+            }
+            for successor in block.successors.iter() {
+                queue.push((*successor, depth));
+            }
+        }
+
+        LocalUsage {
+            max: Some(max as u32),
+            call_sites: call_sites
+                .into_iter()
+                .map(|((_, target), depth)| (depth.max(0) as u32, target))
+                .collect(),
+        }
+    }
+
+    // Adds the worst callee on top of each call site, walking the call graph depth first so
+    // a routine met again on the current path is recursion
+    fn total_usage(&mut self, entry: u16) -> (Option<u32>, Option<u32>) {
+        match self.visits.get(&entry) {
+            Some(Visit::Done(bytes, calls)) => return (*bytes, *calls),
+            Some(Visit::InProgress) => {
+                let start = self.path.iter().position(|routine| *routine == entry);
+                let cycle: Vec<String> = self.path[start.unwrap_or(0)..]
+                    .iter()
+                    .chain([entry].iter())
+                    .map(|routine| self.name(*routine))
+                    .collect();
+                self.problems
+                    .push(format!("Recursive calls {}", cycle.join(" -> ")));
+                return (None, None);
+            }
+            None => (),
+        }
+
+        self.visits.insert(entry, Visit::InProgress);
+        self.path.push(entry);
+        let (local_max, call_sites) = match self.local.get(&entry) {
+            Some(local) => (local.max, local.call_sites.clone()),
+            None => (Some(0), Vec::new()),
+        };
+
+        let mut bytes = local_max;
+        let mut calls = Some(0);
+        for (depth, callee) in call_sites {
+            let (callee_bytes, callee_calls) = self.total_usage(callee);
+            bytes = match (bytes, callee_bytes) {
+                (Some(bytes), Some(callee_bytes)) => Some(bytes.max(depth + callee_bytes)),
+                _ => None,
+            };
+            calls = match (calls, callee_calls) {
+                (Some(calls), Some(callee_calls)) => Some(calls.max(callee_calls + 1)),
+                _ => None,
+            };
+        }
+
+        self.path.pop();
+        self.visits.insert(entry, Visit::Done(bytes, calls));
+        (bytes, calls)
+    }
+}
+
+impl StackReport {
+    pub fn worst_case(&self) -> Option<u32> {
+        self.routines
+            .iter()
+            .find(|routine| routine.entry == 0)
+            .and_then(|routine| routine.bytes)
+    }
+
+    pub fn check_budget(&self, budget: u16) -> Result<(), String> {
+        match self.worst_case() {
+            Some(bytes) if bytes <= budget as u32 => Ok(()),
+            Some(bytes) => Err(format!(
+                "Stack use of {} bytes is over the budget of {} bytes",
+                bytes, budget
+            )),
+            None => Err(format!(
+                "Stack use can't be bounded, {}",
+                self.problems.join(", ")
+            )),
+        }
+    }
+
+    // The stack grows down from the stack start towards the vars placed at the bottom of RAM
+    pub fn check_ram(&self, ram_end: u16) -> Result<(), String> {
+        match self.worst_case() {
+            Some(bytes) if ram_end as u32 + bytes > STACK_START as u32 => Err(format!(
+                "Stack use of {} bytes runs into the RAM variables that end at 0x{:04X}",
+                bytes,
+                ram_end.saturating_sub(1)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for StackReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unbounded = |value: Option<u32>| match value {
+            Some(value) => value.to_string(),
+            None => "unbounded".to_string(),
+        };
+        let width = self
+            .routines
+            .iter()
+            .map(|routine| routine.name.len())
+            .max()
+            .unwrap_or(0)
+            .max(7);
+
+        writeln!(
+            f,
+            "{:width$} {:>9} {:>9}",
+            "routine",
+            "bytes",
+            "calls",
+            width = width
+        )?;
+        for routine in self.routines.iter() {
+            writeln!(
+                f,
+                "{:width$} {:>9} {:>9}",
+                routine.name,
+                unbounded(routine.bytes),
+                unbounded(routine.calls),
+                width = width
+            )?;
+        }
+        for problem in self.problems.iter() {
+            writeln!(f, "{}", problem)?;
+        }
+        match self.worst_case() {
+            Some(bytes) => write!(
+                f,
+                "Worst case stack use is {} of {} bytes",
+                bytes, STACK_REGION
+            ),
+            None => write!(f, "Worst case stack use is unbounded"),
+        }
+    }
+}
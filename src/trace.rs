@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use crate::{
+    disassembler::Instruction,
+    register_file::{FlagRegister, RegisterFile},
+};
+
+const BINARY_MAGIC: &[u8; 4] = b"RBTR";
+const BINARY_VERSION: u8 = 1;
+
+pub enum TraceFormat {
+    Text,
+    // Fixed 20 byte records : pc, opcode byte, 8 registers and the flags, all little endian
+    Binary,
+}
+
+pub struct Trace {
+    writer: BufWriter<File>,
+    format: TraceFormat,
+    ranges: Vec<(u16, u16)>,
+    error: Option<String>,
+}
+
+impl Trace {
+    pub fn create(path: &str, format: TraceFormat) -> Result<Self, String> {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(error) => return Err(format!("Error creating trace file {} \n{}", path, error)),
+        };
+
+        let mut trace = Self {
+            writer: BufWriter::new(file),
+            format,
+            ranges: Vec::new(),
+            error: None,
+        };
+
+        if let TraceFormat::Binary = trace.format {
+            let mut header = BINARY_MAGIC.to_vec();
+            header.push(BINARY_VERSION);
+            trace.write(&header);
+        }
+
+        Ok(trace)
+    }
+
+    // Only instructions inside one of the added ranges are traced, with no ranges everything is
+    pub fn add_range(&mut self, start: u16, end: u16) {
+        self.ranges.push((start, end));
+    }
+
+    pub fn add_label(
+        &mut self,
+        labels: &HashMap<String, u16>,
+        label: &str,
+        program_end: u16,
+    ) -> Result<(), String> {
+        let start = match labels.get(label) {
+            Some(address) => *address,
+            None => return Err(format!("Uknown label {} in trace filter", label)),
+        };
+
+        // A label covers everything up to the next label
+        let end = labels
+            .values()
+            .filter(|address| **address > start)
+            .min()
+            .map(|address| address - 1)
+            .unwrap_or(program_end);
+
+        self.add_range(start, end);
+        Ok(())
+    }
+
+    pub fn wants(&self, address: u16) -> bool {
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|(start, end)| address >= *start && address <= *end)
+    }
+
+    pub fn record(
+        &mut self,
+        instruction: &Instruction,
+        opcode_byte: u8,
+        registers: &RegisterFile,
+        flags: &FlagRegister,
+    ) {
+        match self.format {
+            TraceFormat::Text => {
+                let mut line = format!("{:04X}  {:<20}", instruction.address, instruction);
+                for (index, word) in registers.words().iter().enumerate() {
+                    line.push_str(format!(" ${}={:04X}", index, word).as_str());
+                }
+                line.push_str(
+                    format!(
+                        " compare={} carry={}\n",
+                        flags.compare as u8, flags.carry as u8
+                    )
+                    .as_str(),
+                );
+                self.write(line.as_bytes());
+            }
+            TraceFormat::Binary => {
+                let mut record = Vec::with_capacity(20);
+                record.extend_from_slice(&instruction.address.to_le_bytes());
+                record.push(opcode_byte);
+                for word in registers.words().iter() {
+                    record.extend_from_slice(&word.to_le_bytes());
+                }
+                record.push(flags.compare as u8 | (flags.carry as u8) << 1);
+                self.write(&record);
+            }
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = self.writer.write_all(bytes) {
+            self.error = Some(format!("Error writing trace \n{}", error));
+        }
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        self.writer
+            .flush()
+            .map_err(|e| format!("Error writing trace \n{}", e))
+    }
+}
@@ -1,3 +1,8 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
 use sdl2::{
     event::Event,
     keyboard::Keycode,
@@ -7,17 +12,108 @@ use sdl2::{
     video::Window,
 };
 
-use crate::rusty_boi::RustyBoi;
+use crate::{
+    movie::{Movie, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_UP},
+    rusty_boi::{Halt, RustyBoi},
+};
+
+pub const UP_ADDRESS: u16 = 0x87FE;
+pub const DOWN_ADDRESS: u16 = 0x87FD;
+pub const LEFT_ADDRESS: u16 = 0x87FC;
+pub const RIGHT_ADDRESS: u16 = 0x87FB;
 
-const UP_ADDRESS: u16 = 0x87FE;
-const DOWN_ADDRESS: u16 = 0x87FD;
-const LEFT_ADDRESS: u16 = 0x87FC;
-const RIGHT_ADDRESS: u16 = 0x87FB;
+const BUTTON_ADDRESSES: [(u8, u16); 4] = [
+    (BUTTON_UP, UP_ADDRESS),
+    (BUTTON_DOWN, DOWN_ADDRESS),
+    (BUTTON_LEFT, LEFT_ADDRESS),
+    (BUTTON_RIGHT, RIGHT_ADDRESS),
+];
 
 const SCREEN_WIDTH: u32 = 600;
 const SCREEN_HEIGHT: u32 = 400;
 
-pub fn run_vm(mut rusty_boi: RustyBoi) -> Result<(), String> {
+pub const CLOCK_HZ: u64 = 15_000_000;
+pub const FRAME_RATE: u64 = 60;
+pub const CYCLES_PER_FRAME: u64 = CLOCK_HZ / FRAME_RATE;
+
+// Only buttons that changed since the last frame are written, like the key events of a live run
+fn write_input(rusty_boi: &mut RustyBoi, applied: u8, buttons: u8) {
+    for (button, address) in BUTTON_ADDRESSES {
+        if (applied ^ buttons) & button != 0 {
+            rusty_boi.write_memory_byte(address, (buttons & button != 0) as u8);
+        }
+    }
+}
+
+fn read_input(rusty_boi: &mut RustyBoi) -> u8 {
+    let mut buttons = 0;
+    for (button, address) in BUTTON_ADDRESSES {
+        if rusty_boi.read_memory_byte(address) != 0 {
+            buttons |= button;
+        }
+    }
+    buttons
+}
+
+// Prints why the debugger stopped the VM, returns true when it did
+fn report_stop(rusty_boi: &mut RustyBoi) -> bool {
+    let stop = match rusty_boi.take_stop() {
+        Some(stop) => stop,
+        None => return false,
+    };
+    if let Some(debugger) = rusty_boi.debugger() {
+        println!("{}", debugger.report(&stop, rusty_boi.prog_mem()));
+    }
+    true
+}
+
+fn finish_replay(rusty_boi: &RustyBoi, replay: &Movie) -> Result<(), String> {
+    replay.check(rusty_boi.state_hash())?;
+    println!(
+        "Replay of {} frames finished, final state matches",
+        replay.frames.len()
+    );
+    Ok(())
+}
+
+pub fn run_headless(
+    rusty_boi: &mut RustyBoi,
+    frames: u64,
+    replay: Option<&Movie>,
+) -> Result<Option<(Halt, usize)>, String> {
+    rusty_boi.set_cycle_budget(Some(CYCLES_PER_FRAME));
+    let mut applied = read_input(rusty_boi);
+    let mut halted = None;
+    for frame in 0..frames as usize {
+        if let Some(buttons) = replay.and_then(|movie| movie.frames.get(frame)) {
+            write_input(rusty_boi, applied, *buttons);
+            applied = *buttons;
+        }
+        rusty_boi.run();
+        if report_stop(rusty_boi) {
+            return Ok(None);
+        }
+        // A halted VM never changes again, so the rest of a replay can be skipped
+        if let Some(halt) = rusty_boi.halted() {
+            halted = Some((halt, frame));
+            break;
+        }
+    }
+
+    if let Some(replay) = replay {
+        if replay.frames.len() as u64 == frames {
+            finish_replay(rusty_boi, replay)?;
+        }
+    }
+
+    Ok(halted)
+}
+
+pub fn run_vm(
+    rusty_boi: &mut RustyBoi,
+    replay: Option<&Movie>,
+    mut record: Option<&mut Movie>,
+) -> Result<(), String> {
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video().map_err(|e| e.to_string())?;
 
@@ -30,17 +126,34 @@ pub fn run_vm(mut rusty_boi: RustyBoi) -> Result<(), String> {
     let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
 
     let mut event_pump = sdl_context.event_pump()?;
+    let frame_time = Duration::from_nanos(1_000_000_000 / FRAME_RATE);
+    rusty_boi.set_cycle_budget(Some(CYCLES_PER_FRAME));
+    let mut rewinding = false;
+    let mut paused = false;
+    let mut resuming = false;
+    let mut buttons: u8 = 0;
+    let mut applied = read_input(rusty_boi);
+    let mut frame: usize = 0;
+    let mut shown_halt = None;
+    // The final hash is kept at the last recorded frame, a frame the debugger cut short and the
+    // window was closed on isn't part of the movie
+    if let Some(movie) = record.as_mut() {
+        movie.final_hash = rusty_boi.state_hash();
+    }
     'main: loop {
+        let frame_start = Instant::now();
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'main,
                 Event::KeyDown { keycode, .. } => {
                     if let Some(key) = keycode {
                         match key.to_string().as_str() {
-                            "W" => rusty_boi.write_memory_byte(UP_ADDRESS, 1),
-                            "A" => rusty_boi.write_memory_byte(LEFT_ADDRESS, 1),
-                            "S" => rusty_boi.write_memory_byte(DOWN_ADDRESS, 1),
-                            "D" => rusty_boi.write_memory_byte(RIGHT_ADDRESS, 1),
+                            "W" => buttons |= BUTTON_UP,
+                            "A" => buttons |= BUTTON_LEFT,
+                            "S" => buttons |= BUTTON_DOWN,
+                            "D" => buttons |= BUTTON_RIGHT,
+                            "Backspace" => rewinding = true,
+                            "F5" => paused = false,
                             _ => (),
                         }
                     }
@@ -48,10 +161,11 @@ pub fn run_vm(mut rusty_boi: RustyBoi) -> Result<(), String> {
                 Event::KeyUp { keycode, .. } => {
                     if let Some(key) = keycode {
                         match key.to_string().as_str() {
-                            "W" => rusty_boi.write_memory_byte(UP_ADDRESS, 0),
-                            "A" => rusty_boi.write_memory_byte(LEFT_ADDRESS, 0),
-                            "S" => rusty_boi.write_memory_byte(DOWN_ADDRESS, 0),
-                            "D" => rusty_boi.write_memory_byte(RIGHT_ADDRESS, 0),
+                            "W" => buttons &= !BUTTON_UP,
+                            "A" => buttons &= !BUTTON_LEFT,
+                            "S" => buttons &= !BUTTON_DOWN,
+                            "D" => buttons &= !BUTTON_RIGHT,
+                            "Backspace" => rewinding = false,
                             _ => (),
                         }
                     }
@@ -60,11 +174,74 @@ pub fn run_vm(mut rusty_boi: RustyBoi) -> Result<(), String> {
             }
         }
 
-        rusty_boi.run();
+        if paused {
+            // Waiting for F5 after the debugger stopped the VM
+        } else if rewinding {
+            frame = frame.saturating_sub(rusty_boi.rewind_frames(1));
+            if let Some(movie) = record.as_mut() {
+                movie.frames.truncate(frame);
+                movie.final_hash = rusty_boi.state_hash();
+            }
+            applied = read_input(rusty_boi);
+        } else if rusty_boi.halted().is_some() {
+            // The last frame stays on screen, rewinding lets the program run again
+        } else {
+            // A replay drives the input until it runs out, then the keyboard takes over. The rest
+            // of a frame cut short by the debugger keeps the input it started with
+            let frame_buttons = match replay {
+                _ if resuming => applied,
+                Some(movie) if frame < movie.frames.len() => movie.frames[frame],
+                _ => buttons,
+            };
+            write_input(rusty_boi, applied, frame_buttons);
+            applied = frame_buttons;
+
+            rusty_boi.run();
+            resuming = report_stop(rusty_boi);
+            if resuming {
+                println!("Paused, press F5 to continue");
+                paused = true;
+            } else {
+                // A frame cut short by the debugger is only recorded once it finishes
+                if let Some(movie) = record.as_mut() {
+                    movie.frames.push(frame_buttons);
+                    movie.final_hash = rusty_boi.state_hash();
+                }
+                frame += 1;
+            }
+
+            if let Some(movie) = replay {
+                if frame == movie.frames.len() {
+                    if let Err(error) = finish_replay(rusty_boi, movie) {
+                        println!("{}", error);
+                    }
+                }
+            }
+        }
 
         println!("{:?}", rusty_boi.read_memory_word(0x8000));
 
-        render(&mut canvas, &rusty_boi);
+        if rusty_boi.halted() != shown_halt {
+            shown_halt = rusty_boi.halted();
+            let title = match shown_halt {
+                Some(halt) => {
+                    println!("{} on frame {}", halt, frame);
+                    format!("Test - {}", halt)
+                }
+                None => "Test".to_string(),
+            };
+            canvas
+                .window_mut()
+                .set_title(title.as_str())
+                .map_err(|e| e.to_string())?;
+        }
+
+        render(&mut canvas, rusty_boi);
+
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
+        }
     }
 
     Ok(())
@@ -0,0 +1,89 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+fn write_source(name: &str, source: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rusty_boi_asm_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.iga", name));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+// Assembles to a raw program next to the source, returns the result and the program path
+fn assemble_raw(name: &str, source: &str) -> (Output, String) {
+    let path = write_source(name, source);
+    let output = format!("{}.raw", path.to_str().unwrap());
+    let result = Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+        .args(["c", path.to_str().unwrap(), output.as_str(), "--raw"])
+        .output()
+        .unwrap();
+    (result, output)
+}
+
+fn assemble(name: &str, source: &str) -> Output {
+    assemble_raw(name, source).0
+}
+
+fn error(name: &str, source: &str) -> String {
+    let output = assemble(name, source);
+    assert!(!output.status.success(), "{} assembled", name);
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn rejects_extra_operands() {
+    assert!(
+        error("load", "    LOAD $0 #1 #2\n").contains("Unexpected operand #2 for LOAD on line 1")
+    );
+    assert!(error("jmp", "x:\n    JMP x extra junk\n")
+        .contains("Unexpected operand extra for JMP on line 2"));
+    assert!(error("const", "const A #1 #2\n").contains("Unexpected operand #2 for const on line 1"));
+    // if takes a whole expression, rept and fill an optional second operand
+    assert!(assemble("fill", "fill 0xFF 2\nrept 2 I\n    NOP\nendr\n")
+        .status
+        .success());
+    assert!(
+        assemble("if", "const A #1\nif A > #0 && A < #2\n    NOP\nendif\n")
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn character_literals_can_be_spaces() {
+    let (result, output) = assemble_raw("space", "    LOAD $0a ' '\n    LOAD $1a 'A' // ' '\n");
+    assert!(result.status.success(), "{:?}", result);
+    assert_eq!(fs::read(output).unwrap(), vec![0, 0, 32, 0, 2, 65]);
+}
+
+#[test]
+fn sources_without_code_still_build() {
+    let path = write_source("empty", "const A #1\n");
+    let output = format!("{}.bin", path.to_str().unwrap());
+    let result = Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+        .args(["c", path.to_str().unwrap(), output.as_str()])
+        .output()
+        .unwrap();
+    assert!(result.status.success(), "{:?}", result);
+
+    // There's nothing to run so it halts straight away
+    let result = Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+        .args(["h", output.as_str(), "1"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(result.stderr)
+        .unwrap()
+        .contains("ran past the end of the program at 0x0000"));
+}
+
+#[test]
+fn variables_leave_room_for_the_stack() {
+    assert!(error("big", "var big [34790]\n    NOP\n")
+        .contains("Variable big needs 34790 bytes and runs into the 256 bytes kept for the stack"));
+    assert!(assemble("fits", "var big [34554]\n    NOP\n")
+        .status
+        .success());
+}
@@ -0,0 +1,247 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+fn write_source(name: &str, source: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rusty_boi_boi_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.boi", name));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn rusty_boi(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Compiles and runs the program headless, the value main returns is the exit status
+fn exit_code(name: &str, source: &str) -> i32 {
+    let path = write_source(name, source);
+    let output = rusty_boi(&["h", path.to_str().unwrap(), "10"]);
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("Halted with exit code"),
+        "{} didn't halt: {:?}",
+        name,
+        output
+    );
+    output.status.code().unwrap()
+}
+
+fn compile_error(name: &str, source: &str) -> String {
+    let path = write_source(name, source);
+    let output = rusty_boi(&["boi", path.to_str().unwrap(), "/dev/null"]);
+    assert!(!output.status.success(), "{} compiled", name);
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn arithmetic() {
+    assert_eq!(
+        exit_code(
+            "precedence",
+            "u16 main() { return 2 + 3 * 4 - 10 / 3 % 2; }"
+        ),
+        13
+    );
+    assert_eq!(
+        exit_code("shifts", "u16 main() { return (1 << 5) + (200 >> 3); }"),
+        57
+    );
+    assert_eq!(exit_code("negate", "u16 main() { return -3 + 5; }"), 2);
+    assert_eq!(
+        exit_code(
+            "compound",
+            "u16 main() { u16 a = 5; a *= 4; a -= 2; a /= 3; a %= 4; a <<= 3; return a; }"
+        ),
+        16
+    );
+    assert_eq!(
+        exit_code("byte_wrap", "u16 main() { u8 a = 250; a += 10; return a; }"),
+        4
+    );
+}
+
+#[test]
+fn signed_values() {
+    let source = r#"
+u16 main() {
+    i16 a = -7;
+    i16 b = 2;
+    if (a / b == -3 && a % b == -1 && a < b) {
+        return 1;
+    }
+    return 0;
+}
+"#;
+    assert_eq!(exit_code("signed_div", source), 1);
+
+    // Bytes are sign extended when signed and zero extended otherwise
+    let source = r#"
+u16 main() {
+    i8 a = -1;
+    u8 c = 255;
+    i16 b = a;
+    i16 d = c;
+    if (b < 0 && d > 0) {
+        return d - 250;
+    }
+    return 0;
+}
+"#;
+    assert_eq!(exit_code("extend", source), 5);
+
+    // Unsigned compares treat the top bit as a large value
+    let source = r#"
+u16 main() {
+    u16 big = 40000;
+    i16 small = -1;
+    u16 result = 0;
+    if (big > 1) {
+        result += 1;
+    }
+    if (small < 1) {
+        result += 2;
+    }
+    return result;
+}
+"#;
+    assert_eq!(exit_code("compare", source), 3);
+}
+
+#[test]
+fn calls() {
+    let source = r#"
+u16 add(u16 a, u16 b) {
+    return a + b;
+}
+
+u16 twice(u16 a) {
+    return add(a, a);
+}
+
+u16 main() {
+    u16 keep = 3;
+    u16 sum = add(add(1, 2), twice(add(3, 4)));
+    return sum * 2 + keep;
+}
+"#;
+    assert_eq!(exit_code("calls", source), 37);
+}
+
+#[test]
+fn arrays_and_loops() {
+    let source = r#"
+u8 values[10];
+
+u16 main() {
+    u16 i = 0;
+    while (i < 10) {
+        values[i] = i * 3;
+        i += 1;
+    }
+    u16 sum = 0;
+    i = 0;
+    while (1) {
+        if (i == 10) {
+            break;
+        }
+        i += 1;
+        if (values[i - 1] % 2) {
+            continue;
+        }
+        sum += values[i - 1];
+    }
+    return sum;
+}
+"#;
+    assert_eq!(exit_code("arrays", source), 60);
+}
+
+#[test]
+fn short_circuit() {
+    let source = r#"
+u16 count;
+
+u16 bump() {
+    count += 1;
+    return 1;
+}
+
+u16 main() {
+    if (0 && bump()) {
+        count += 10;
+    }
+    if (1 || bump()) {
+        count += 100;
+    }
+    if (!(1 && bump())) {
+        count += 10;
+    }
+    return count;
+}
+"#;
+    assert_eq!(exit_code("short_circuit", source), 101);
+}
+
+#[test]
+fn block_scopes() {
+    let source = r#"
+u8 pick(u8 a) {
+    u8 r = 0;
+    if (a) {
+        u8 t = 1;
+        r = t;
+    } else {
+        u8 t = 2;
+        r = t;
+    }
+    while (a < 3) {
+        u16 r = 100;
+        a += 1;
+    }
+    return r;
+}
+
+u16 main() {
+    return pick(1) * 10 + pick(0);
+}
+"#;
+    assert_eq!(exit_code("scopes", source), 12);
+    assert!(compile_error(
+        "out_of_scope",
+        "void main() {\n if (1) {\n u8 q = 1;\n }\n q = 2;\n}\n"
+    )
+    .contains("Uknown variable q on line 5"));
+    assert!(
+        compile_error("redefined", "void main() {\n u8 a = 1;\n u8 a = 2;\n}\n")
+            .contains("a is already defined on line 3")
+    );
+}
+
+#[test]
+fn memory_and_exit() {
+    assert_eq!(
+        exit_code(
+            "peek_poke",
+            "u16 main() { poke(0x8100, 77); return peek(0x8100); }"
+        ),
+        77
+    );
+    assert_eq!(exit_code("void_main", "u16 x;\nvoid main() { x = 3; }"), 0);
+    // Exit statuses only keep a byte
+    assert_eq!(exit_code("large", "u16 main() { return 300; }"), 255);
+}
+
+#[test]
+fn rejects_recursion() {
+    let error = compile_error(
+        "recursion",
+        "u16 a() {\n return b();\n}\nu16 b() {\n return a();\n}\nu16 main() {\n return a();\n}\n",
+    );
+    assert!(error.contains("Recursive call"), "{}", error);
+}
@@ -0,0 +1,50 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+fn message(body: &str) -> Vec<u8> {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+}
+
+#[test]
+fn keeps_going_after_bad_messages() {
+    let mut input = message(r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#);
+    input.extend(message("this isn't json"));
+    // Too large to be read, the body is skipped
+    let large = 1 << 25;
+    input.extend(format!("Content-Length: {}\r\n\r\n", large).into_bytes());
+    input.extend(vec![b' '; large]);
+    input.extend(message(
+        r#"{"seq":2,"type":"request","command":"threads","arguments":{}}"#,
+    ));
+    input.extend(message(
+        r#"{"seq":3,"type":"request","command":"disconnect","arguments":{}}"#,
+    ));
+
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+        .args(["d", "tests/fixtures/gdb.iga"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = adapter.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(&input);
+    });
+    let output = adapter.wait_with_output().unwrap();
+    writer.join().unwrap();
+    assert!(output.status.success());
+
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert!(output.contains(r#""request_seq":1,"command":"initialize","success":true"#));
+    assert_eq!(
+        output
+            .matches(r#""request_seq":0,"command":"","success":false"#)
+            .count(),
+        2
+    );
+    assert!(output.contains("byte limit"));
+    assert!(output.contains(r#""request_seq":2,"command":"threads","success":true"#));
+    assert!(output.contains(r#""request_seq":3,"command":"disconnect","success":true"#));
+}
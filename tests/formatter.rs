@@ -0,0 +1,95 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+fn rusty_boi(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rusty_boi_fmt_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+fn assemble(path: &str, defines: &[&str]) -> Vec<u8> {
+    let output = format!("{}.raw", path);
+    let mut args = vec!["c", path, output.as_str(), "--raw"];
+    for define in defines {
+        args.push("-D");
+        args.push(define);
+    }
+    let result = rusty_boi(&args);
+    assert!(result.status.success(), "{:?}", result);
+    fs::read(output).unwrap()
+}
+
+// Formats a copy of the file and checks every build variant still assembles to the same bytes
+fn check_fixture(source: &str, variants: &[&[&str]]) {
+    let name = source.rsplit('/').next().unwrap();
+    let copy = scratch(name);
+    fs::copy(source, &copy).unwrap();
+    let copy = copy.to_str().unwrap();
+
+    let before: Vec<Vec<u8>> = variants
+        .iter()
+        .map(|defines| assemble(copy, defines))
+        .collect();
+    let result = rusty_boi(&["fmt", copy]);
+    assert!(result.status.success(), "{:?}", result);
+    let after: Vec<Vec<u8>> = variants
+        .iter()
+        .map(|defines| assemble(copy, defines))
+        .collect();
+    assert_eq!(before, after, "formatting {} changed the program", source);
+
+    // Formatting is stable
+    let formatted = fs::read_to_string(copy).unwrap();
+    assert!(rusty_boi(&["fmt", "--check", copy]).status.success());
+    assert_eq!(fs::read_to_string(copy).unwrap(), formatted);
+}
+
+#[test]
+fn keeps_the_examples_assembling_the_same() {
+    for example in ["color", "test", "top_down_movment"] {
+        check_fixture(format!("examples/{}.iga", example).as_str(), &[&[]]);
+    }
+}
+
+#[test]
+fn keeps_every_conditional_variant_the_same() {
+    check_fixture(
+        "tests/fixtures/format.iga",
+        &[&[], &["FAST"], &["SLOW"], &["FAST", "SLOW"]],
+    );
+    let formatted = fs::read_to_string(scratch("format.iga")).unwrap();
+    // Numbers outside conditionals are normalized, the ones inside are left alone
+    assert!(formatted.contains("const SPEED #4"));
+    assert!(formatted.contains("const STEP  #0002"));
+    assert!(formatted.contains("LOAD  $7  0x10"));
+    assert!(formatted.contains("LOAD  $1a 0x0A"));
+}
+
+#[test]
+fn check_carries_on_past_files_that_dont_assemble() {
+    let broken = scratch("broken.iga");
+    fs::write(&broken, "  LOAD $9 #1\n").unwrap();
+    let messy = scratch("messy.iga");
+    fs::write(&messy, "  LOAD $0 #1\n").unwrap();
+
+    let result = rusty_boi(&[
+        "fmt",
+        "--check",
+        broken.to_str().unwrap(),
+        messy.to_str().unwrap(),
+    ]);
+    assert!(!result.status.success());
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    assert!(stdout.contains("broken.iga doesn't assemble"));
+    assert!(stdout.contains("messy.iga needs formatting"));
+}
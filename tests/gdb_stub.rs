@@ -0,0 +1,110 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+// Each test gets its own port since tests run in parallel
+const PORT: u16 = 47133;
+
+struct Client {
+    stream: TcpStream,
+    stub: Child,
+}
+
+impl Client {
+    fn start(port: u16) -> Self {
+        let mut stub = Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+            .args(["g", "tests/fixtures/gdb.iga", port.to_string().as_str()])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+                return Self { stream, stub };
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _ = stub.kill();
+        let _ = stub.wait();
+        panic!("The stub never started listening");
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> String {
+        let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.stream.write_all(&packet).unwrap();
+
+        assert_eq!(self.read_byte(), b'+');
+        assert_eq!(self.read_byte(), b'$');
+        let mut reply = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        self.read_byte();
+        self.read_byte();
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    fn send(&mut self, data: &str) -> String {
+        self.send_raw(data.as_bytes())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.stub.kill();
+        let _ = self.stub.wait();
+    }
+}
+
+#[test]
+fn drives_the_vm_over_loopback() {
+    let mut client = Client::start(PORT);
+    assert_eq!(client.send("?"), "S05");
+    // Registers are little endian words, then pc, sp and the flags byte
+    let registers = format!("{}0000fe8700", "0000".repeat(8));
+    assert_eq!(client.send("g"), registers);
+
+    assert_eq!(client.send("s"), "S05");
+    assert!(client.send("g").starts_with("00000300"));
+    let registers = format!("3412{}0400fe8700", "0000".repeat(7));
+    assert_eq!(client.send(format!("G{}", registers).as_str()), "OK");
+    assert_eq!(client.send("g"), registers);
+
+    assert_eq!(client.send("M10,2:abcd"), "OK");
+    assert_eq!(client.send("m10,2"), "abcd");
+    // Program memory starts at 0x10000, LOAD $1 #3 is 01 02 03 00
+    assert_eq!(client.send("m10000,4"), "01020300");
+
+    assert_eq!(client.send("Z0,8,1"), "OK");
+    assert_eq!(client.send("c"), "T05swbreak:;");
+    assert_eq!(client.send("p8"), "0800");
+    assert_eq!(client.send("z0,8,1"), "OK");
+    assert_eq!(client.send("s"), "S05");
+    assert_eq!(client.send("p8"), "0c00");
+}
+
+#[test]
+fn survives_malformed_packets() {
+    let mut client = Client::start(PORT + 1);
+    assert_eq!(client.send_raw("é".as_bytes()), "");
+    assert_eq!(client.send("Z2,ffffffff,2"), "E01");
+    assert_eq!(client.send("M10,2:aéb"), "E01");
+    assert_eq!(client.send("G12"), "E01");
+    assert_eq!(client.send("?"), "S05");
+}
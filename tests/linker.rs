@@ -0,0 +1,61 @@
+use std::{fs, process::Command};
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF29CE484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001B3)
+    })
+}
+
+#[test]
+fn rejects_exports_outside_the_code() {
+    let dir = std::env::temp_dir().join(format!("rusty_boi_link_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("lib.iga");
+    let object = dir.join("lib.o");
+    fs::write(
+        &source,
+        "export start\nexport finish\nstart:\n    NOP\nfinish:\n",
+    )
+    .unwrap();
+    let rusty_boi = env!("CARGO_BIN_EXE_rust_boy");
+    let result = Command::new(rusty_boi)
+        .args([
+            "c",
+            source.to_str().unwrap(),
+            object.to_str().unwrap(),
+            "--object",
+        ])
+        .output()
+        .unwrap();
+    assert!(result.status.success(), "{:?}", result);
+
+    // A label right after the last instruction is fine
+    let image = dir.join("lib.img");
+    let link = |object: &str| {
+        Command::new(rusty_boi)
+            .args(["link", image.to_str().unwrap(), object, "--map"])
+            .output()
+            .unwrap()
+    };
+    let result = link(object.to_str().unwrap());
+    assert!(result.status.success(), "{:?}", result);
+
+    // Point the export past the code and fix the checksum so only the offset is wrong
+    let mut bytes = fs::read(&object).unwrap();
+    let name = bytes
+        .windows(6)
+        .position(|window| window == b"finish")
+        .unwrap();
+    bytes[name + 6..name + 8].copy_from_slice(&0xFFFFu16.to_le_bytes());
+    let body = bytes.len() - 8;
+    let checksum = fnv1a(&bytes[..body]);
+    bytes[body..].copy_from_slice(&checksum.to_le_bytes());
+    let broken = dir.join("broken.o");
+    fs::write(&broken, bytes).unwrap();
+
+    let result = link(broken.to_str().unwrap());
+    assert!(!result.status.success());
+    assert!(String::from_utf8(result.stderr)
+        .unwrap()
+        .contains("Export finish at 0xFFFF is outside the code of the object"));
+}
@@ -0,0 +1,63 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+fn message(body: &str) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+fn hover(id: usize, line: usize, character: usize) -> String {
+    message(
+        format!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"textDocument/hover","params":{{"textDocument":{{"uri":"file:///test.iga"}},"position":{{"line":{},"character":{}}}}}}}"#,
+            id, line, character
+        )
+        .as_str(),
+    )
+}
+
+#[test]
+fn hovers_only_real_registers() {
+    let source = r#"MOV $ $1\nMOV $1a $9\nMOV $é $1\n"#;
+    let mut input = message(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#);
+    // A message that isn't JSON doesn't stop the server
+    input.push_str(&message("nope"));
+    input.push_str(&message(
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///test.iga","languageId":"iga","version":1,"text":"{}"}}}}}}"#,
+            source
+        )
+        .as_str(),
+    ));
+    for (id, (line, character)) in [(0, 4), (0, 7), (1, 5), (1, 9), (2, 4)].iter().enumerate() {
+        input.push_str(&hover(id + 2, *line, *character));
+    }
+    input.push_str(&message(r#"{"jsonrpc":"2.0","id":9,"method":"shutdown"}"#));
+    input.push_str(&message(r#"{"jsonrpc":"2.0","method":"exit"}"#));
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rust_boy"))
+        .arg("l")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    server
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = server.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert!(output.contains(r#""id":null,"error":{"code":-32700"#));
+    assert!(output.contains(r#""id":2,"result":null"#));
+    assert!(output.contains(
+        r#""id":3,"result":{"contents":{"kind":"markdown","value":"16bit register $1"}}"#
+    ));
+    assert!(output.contains(r#""value":"first 8bit half of register $1""#));
+    assert!(output.contains(r#""id":5,"result":null"#));
+    assert!(output.contains(r#""id":6,"result":null"#));
+}